@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_vector_chunks_document_id;
+DROP TABLE IF EXISTS vector_chunks;
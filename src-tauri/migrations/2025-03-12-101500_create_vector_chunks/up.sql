@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS vector_chunks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    document_id INTEGER NOT NULL,
+    chunk_index INTEGER NOT NULL,
+    start_offset INTEGER NOT NULL,
+    end_offset INTEGER NOT NULL,
+    chunk_text TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_vector_chunks_document_id ON vector_chunks (document_id);
+
+-- The previous index stored one vector per document and can't be mapped onto chunks,
+-- so documents get vectorized again on their next edit
+UPDATE projects_activities SET is_vectorized = 0;
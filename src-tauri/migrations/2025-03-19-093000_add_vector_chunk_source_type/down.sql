@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_vector_chunks_source;
+ALTER TABLE vector_chunks DROP COLUMN source_type;
+ALTER TABLE vector_chunks RENAME COLUMN source_id TO document_id;
+CREATE INDEX IF NOT EXISTS idx_vector_chunks_document_id ON vector_chunks (document_id);
@@ -0,0 +1,11 @@
+-- Activities and project documents have overlapping ids, so every chunk records which table it came from
+ALTER TABLE vector_chunks RENAME COLUMN document_id TO source_id;
+ALTER TABLE vector_chunks ADD COLUMN source_type TEXT NOT NULL DEFAULT 'project_document';
+
+DROP INDEX IF EXISTS idx_vector_chunks_document_id;
+CREATE INDEX IF NOT EXISTS idx_vector_chunks_source ON vector_chunks (source_type, source_id);
+
+-- Existing chunks can't be attributed to a source. AUTOINCREMENT keeps their ids from being
+-- reused, so their vectors simply stop resolving
+DELETE FROM vector_chunks;
+UPDATE projects_activities SET is_vectorized = 0;
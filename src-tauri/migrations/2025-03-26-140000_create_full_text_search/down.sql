@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER IF EXISTS activity_full_text_fts_update;
+DROP TRIGGER IF EXISTS activity_full_text_fts_delete;
+DROP TRIGGER IF EXISTS activity_full_text_fts_insert;
+DROP TRIGGER IF EXISTS projects_activities_fts_update;
+DROP TRIGGER IF EXISTS projects_activities_fts_delete;
+DROP TRIGGER IF EXISTS projects_activities_fts_insert;
+DROP TABLE IF EXISTS activity_full_text_fts;
+DROP TABLE IF EXISTS projects_activities_fts;
@@ -0,0 +1,55 @@
+-- Keyword search over documents and captured activities. Both are external content tables,
+-- so the text isn't stored twice and the triggers below keep the indexes in sync.
+CREATE VIRTUAL TABLE IF NOT EXISTS projects_activities_fts USING fts5(
+    document_name,
+    full_document_text,
+    content = 'projects_activities',
+    content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS activity_full_text_fts USING fts5(
+    window_title,
+    edited_full_text,
+    content = 'activity_full_text',
+    content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER IF NOT EXISTS projects_activities_fts_insert AFTER INSERT ON projects_activities BEGIN
+    INSERT INTO projects_activities_fts (rowid, document_name, full_document_text)
+    VALUES (new.id, new.document_name, new.full_document_text);
+END;
+
+CREATE TRIGGER IF NOT EXISTS projects_activities_fts_delete AFTER DELETE ON projects_activities BEGIN
+    INSERT INTO projects_activities_fts (projects_activities_fts, rowid, document_name, full_document_text)
+    VALUES ('delete', old.id, old.document_name, old.full_document_text);
+END;
+
+CREATE TRIGGER IF NOT EXISTS projects_activities_fts_update AFTER UPDATE OF document_name, full_document_text ON projects_activities BEGIN
+    INSERT INTO projects_activities_fts (projects_activities_fts, rowid, document_name, full_document_text)
+    VALUES ('delete', old.id, old.document_name, old.full_document_text);
+    INSERT INTO projects_activities_fts (rowid, document_name, full_document_text)
+    VALUES (new.id, new.document_name, new.full_document_text);
+END;
+
+CREATE TRIGGER IF NOT EXISTS activity_full_text_fts_insert AFTER INSERT ON activity_full_text BEGIN
+    INSERT INTO activity_full_text_fts (rowid, window_title, edited_full_text)
+    VALUES (new.id, new.window_title, new.edited_full_text);
+END;
+
+CREATE TRIGGER IF NOT EXISTS activity_full_text_fts_delete AFTER DELETE ON activity_full_text BEGIN
+    INSERT INTO activity_full_text_fts (activity_full_text_fts, rowid, window_title, edited_full_text)
+    VALUES ('delete', old.id, old.window_title, old.edited_full_text);
+END;
+
+CREATE TRIGGER IF NOT EXISTS activity_full_text_fts_update AFTER UPDATE OF window_title, edited_full_text ON activity_full_text BEGIN
+    INSERT INTO activity_full_text_fts (activity_full_text_fts, rowid, window_title, edited_full_text)
+    VALUES ('delete', old.id, old.window_title, old.edited_full_text);
+    INSERT INTO activity_full_text_fts (rowid, window_title, edited_full_text)
+    VALUES (new.id, new.window_title, new.edited_full_text);
+END;
+
+-- Index everything that was captured before this migration.
+INSERT INTO projects_activities_fts (projects_activities_fts) VALUES ('rebuild');
+INSERT INTO activity_full_text_fts (activity_full_text_fts) VALUES ('rebuild');
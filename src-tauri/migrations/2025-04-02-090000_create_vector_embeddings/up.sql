@@ -0,0 +1,14 @@
+-- One row per vector in the HNSW index, so the graph can be rebuilt without embedding again.
+-- `embedding` holds `dimension` little-endian f32 values, `content_hash` is the SHA-256 of the
+-- embedded text.
+CREATE TABLE IF NOT EXISTS vector_embeddings (
+    chunk_id INTEGER PRIMARY KEY,
+    model TEXT NOT NULL,
+    dimension INTEGER NOT NULL,
+    content_hash TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (chunk_id) REFERENCES vector_chunks (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_vector_embeddings_model ON vector_embeddings (model);
@@ -0,0 +1,19 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE vector_embeddings_old (
+    chunk_id INTEGER PRIMARY KEY,
+    model TEXT NOT NULL,
+    dimension INTEGER NOT NULL,
+    content_hash TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (chunk_id) REFERENCES vector_chunks (id) ON DELETE CASCADE
+);
+
+INSERT OR REPLACE INTO vector_embeddings_old (chunk_id, model, dimension, content_hash, embedding, created_at)
+SELECT chunk_id, model, dimension, content_hash, embedding, created_at FROM vector_embeddings;
+
+DROP INDEX IF EXISTS idx_vector_embeddings_model;
+DROP TABLE vector_embeddings;
+ALTER TABLE vector_embeddings_old RENAME TO vector_embeddings;
+
+CREATE INDEX IF NOT EXISTS idx_vector_embeddings_model ON vector_embeddings (model);
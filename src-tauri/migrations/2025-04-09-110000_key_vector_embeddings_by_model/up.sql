@@ -0,0 +1,20 @@
+-- While the index is re-embedded with another model, a chunk has a vector for both models.
+CREATE TABLE vector_embeddings_new (
+    chunk_id INTEGER NOT NULL,
+    model TEXT NOT NULL,
+    dimension INTEGER NOT NULL,
+    content_hash TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chunk_id, model),
+    FOREIGN KEY (chunk_id) REFERENCES vector_chunks (id) ON DELETE CASCADE
+);
+
+INSERT INTO vector_embeddings_new (chunk_id, model, dimension, content_hash, embedding, created_at)
+SELECT chunk_id, model, dimension, content_hash, embedding, created_at FROM vector_embeddings;
+
+DROP INDEX IF EXISTS idx_vector_embeddings_model;
+DROP TABLE vector_embeddings;
+ALTER TABLE vector_embeddings_new RENAME TO vector_embeddings;
+
+CREATE INDEX IF NOT EXISTS idx_vector_embeddings_model ON vector_embeddings (model);
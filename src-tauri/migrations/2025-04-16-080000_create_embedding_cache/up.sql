@@ -0,0 +1,12 @@
+-- Vectors by the hash of the exact text that was embedded. Unlike `vector_embeddings` the rows
+-- outlive the chunks, so re-saving a document only embeds the chunks that actually changed.
+CREATE TABLE IF NOT EXISTS embedding_cache (
+    model TEXT NOT NULL,
+    content_hash TEXT NOT NULL,
+    dimension INTEGER NOT NULL,
+    embedding BLOB NOT NULL,
+    last_used_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (model, content_hash)
+);
+
+CREATE INDEX IF NOT EXISTS idx_embedding_cache_last_used_at ON embedding_cache (last_used_at);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_content_fingerprints_duplicate_of;
+DROP TABLE IF EXISTS content_fingerprints;
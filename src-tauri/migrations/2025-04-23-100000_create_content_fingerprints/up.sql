@@ -0,0 +1,12 @@
+-- SimHash of the text of every fingerprinted source. Near-duplicates point at the first source
+-- of their cluster through `duplicate_of`, which is NULL for the first source itself.
+CREATE TABLE IF NOT EXISTS content_fingerprints (
+    source_type TEXT NOT NULL,
+    source_id INTEGER NOT NULL,
+    simhash INTEGER NOT NULL,
+    duplicate_of INTEGER,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (source_type, source_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_content_fingerprints_duplicate_of ON content_fingerprints (source_type, duplicate_of);
@@ -0,0 +1,2 @@
+-- Retrieved documents an assistant answer cites, a JSON array of MessageSource
+ALTER TABLE messages ADD COLUMN sources TEXT;
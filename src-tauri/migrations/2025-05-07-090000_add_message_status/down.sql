@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE messages DROP COLUMN output_tokens;
+ALTER TABLE messages DROP COLUMN input_tokens;
+ALTER TABLE messages DROP COLUMN status;
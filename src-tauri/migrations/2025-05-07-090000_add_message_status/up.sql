@@ -0,0 +1,5 @@
+-- Assistant answers are written while they stream in. `status` tells whether an answer is still
+-- streaming, completed, was cancelled, failed or was interrupted by the app closing
+ALTER TABLE messages ADD COLUMN status TEXT NOT NULL DEFAULT 'complete';
+ALTER TABLE messages ADD COLUMN input_tokens INTEGER;
+ALTER TABLE messages ADD COLUMN output_tokens INTEGER;
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_usage_events_project_id;
+DROP INDEX IF EXISTS idx_usage_events_chat_id;
+DROP INDEX IF EXISTS idx_usage_events_created_at;
+DROP TABLE IF EXISTS usage_events;
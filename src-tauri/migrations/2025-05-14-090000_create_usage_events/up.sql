@@ -0,0 +1,18 @@
+-- One row per call to an LLM or embedding model. Rows outlive their chat, the money was spent
+-- either way.
+CREATE TABLE IF NOT EXISTS usage_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    model TEXT NOT NULL,
+    purpose TEXT NOT NULL,
+    input_tokens INTEGER NOT NULL DEFAULT 0,
+    output_tokens INTEGER NOT NULL DEFAULT 0,
+    cost REAL NOT NULL DEFAULT 0,
+    chat_id INTEGER,
+    project_id INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS idx_usage_events_created_at ON usage_events (created_at);
+CREATE INDEX IF NOT EXISTS idx_usage_events_chat_id ON usage_events (chat_id);
+CREATE INDEX IF NOT EXISTS idx_usage_events_project_id ON usage_events (project_id);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_content_fingerprints_band3;
+DROP INDEX IF EXISTS idx_content_fingerprints_band2;
+DROP INDEX IF EXISTS idx_content_fingerprints_band1;
+DROP INDEX IF EXISTS idx_content_fingerprints_band0;
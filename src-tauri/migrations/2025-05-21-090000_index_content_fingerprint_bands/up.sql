@@ -0,0 +1,7 @@
+-- Near-duplicates share at least one 16-bit band of their SimHash, so a new fingerprint is only
+-- compared with the fingerprints found through these indexes. The expressions have to match the
+-- ones of the lookup exactly.
+CREATE INDEX IF NOT EXISTS idx_content_fingerprints_band0 ON content_fingerprints (simhash & 65535);
+CREATE INDEX IF NOT EXISTS idx_content_fingerprints_band1 ON content_fingerprints ((simhash >> 16) & 65535);
+CREATE INDEX IF NOT EXISTS idx_content_fingerprints_band2 ON content_fingerprints ((simhash >> 32) & 65535);
+CREATE INDEX IF NOT EXISTS idx_content_fingerprints_band3 ON content_fingerprints ((simhash >> 48) & 65535);
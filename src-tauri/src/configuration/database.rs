@@ -1,18 +1,21 @@
 use std::fs;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
 
 use diesel::sqlite::SqliteConnection;
 use diesel::Connection;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
-use log::info;
+use log::{error, info};
 use tauri::AppHandle;
-use tokio::sync::Mutex;
+use tokio::sync::OwnedRwLockReadGuard;
 
-use crate::engine::similarity_search_engine::{SimilaritySearch, SyncSimilaritySearch};
+use crate::configuration::state::ServiceAccess;
+use crate::engine::embedding_engine::EmbeddingSettings;
+use crate::engine::similarity_search_engine::{IndexParams, SimilaritySearch, VectorLoader};
+use crate::entity::setting::Setting;
+use crate::repository::settings_repository::{get_setting, insert_or_update_setting};
+use crate::repository::vector_db_repository::get_vector_embeddings_by_model;
 use crate::HNSW;
 
-pub type SyncVectorDatabase = Arc<Mutex<Option<SimilaritySearch>>>;
-
 const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 pub fn initialize_database(
@@ -37,36 +40,143 @@ pub fn initialize_database(
     Ok(db)
 }
 
-pub async fn drop_database_handle() {
-    let mut db = HNSW.lock().await;
-    *db = None;
+/// Writes the vector index to disk and stops its worker. Has to run before the process exits,
+/// `std::process::exit` skips the destructor that would otherwise schedule the dump.
+pub async fn close_vector_database() {
+    let index = HNSW.write().await.take();
+    if let Some(index) = index {
+        if let Err(e) = index.close().await {
+            error!("Failed to close vector index: {}", e);
+        }
+    }
 }
 
+/// The active vector index, held for reading while a change is written to SQLite and the index.
+/// Swapping the collection waits for these guards, so no change lands in a retired collection.
+pub type VectorDbGuard = OwnedRwLockReadGuard<Option<SimilaritySearch>, SimilaritySearch>;
+
+/// Returns a handle to the active vector index, opening it on first use. The handle takes no
+/// lock, hold on to it only for as long as one request.
 pub async fn get_vector_db(
     app_handle: &AppHandle,
-) -> Result<SyncSimilaritySearch, Box<dyn std::error::Error>> {
+) -> Result<SimilaritySearch, Box<dyn std::error::Error>> {
+    Ok(lock_vector_db(app_handle).await?.clone())
+}
+
+/// Holds the active vector index, opening it on first use. Compute embeddings before calling
+/// this, a collection swap waits for the guard to be dropped.
+pub async fn lock_vector_db(
+    app_handle: &AppHandle,
+) -> Result<VectorDbGuard, Box<dyn std::error::Error>> {
     info!("Getting HNSW instance");
-    let mut db = HNSW.lock().await;
-    if let Some(_) = db.as_ref() {
-        info!("Retrieving existing instance of HNSW");
-        Ok(HNSW.clone())
-    } else {
-        info!("Initializing new instance of HNSW");
-        let hnsw = initialize_vector_database(app_handle)?;
-        *db = Some(hnsw);
-        Ok(HNSW.clone())
+    if HNSW.read().await.is_none() {
+        let mut db = HNSW.write().await;
+        if db.is_none() {
+            info!("Initializing new instance of HNSW");
+            *db = Some(initialize_vector_database(app_handle)?);
+        }
     }
+    OwnedRwLockReadGuard::try_map(HNSW.clone().read_owned().await, Option::as_ref)
+        .map_err(|_| "Vector index is closed".into())
 }
 
-fn initialize_vector_database<'a>(
-    app_handle: &AppHandle,
-) -> Result<SimilaritySearch, Box<dyn std::error::Error>> {
-    let app_dir = app_handle
+/// Name of the collection `get_vector_db` opens, and the model its vectors were computed with.
+pub const VECTOR_COLLECTION_SETTING: &str = "vector_collection";
+pub const VECTOR_COLLECTION_MODEL_SETTING: &str = "vector_collection_model";
+const DEFAULT_VECTOR_COLLECTION: &str = "chunk_vectors";
+
+/// The embedding model of the active collection, `None` for indexes built before it was recorded.
+pub fn get_vector_collection_model(db: &rusqlite::Connection) -> Option<String> {
+    get_setting(db, VECTOR_COLLECTION_MODEL_SETTING)
+        .ok()
+        .map(|setting| setting.setting_value)
+        .filter(|value| !value.is_empty())
+}
+
+pub fn get_vector_db_path(app_handle: &AppHandle) -> PathBuf {
+    app_handle
         .path_resolver()
         .app_data_dir()
-        .expect("The app data directory should exist.");
-    let hnsw_db_path = app_dir.join("hnsw");
-    let collection_name = "activity_vectors";
-    let hnsw = SimilaritySearch::open(hnsw_db_path.to_str().unwrap(), collection_name)?;
+        .expect("The app data directory should exist.")
+        .join("hnsw")
+}
+
+/// Opens a collection whose graph is rebuilt from the stored vectors of `model` if needed.
+pub fn open_vector_collection(
+    app_handle: &AppHandle,
+    collection_name: &str,
+    model: String,
+) -> Result<SimilaritySearch, Box<dyn std::error::Error>> {
+    let hnsw_db_path = get_vector_db_path(app_handle);
+    let params = app_handle.db(|db| IndexParams::load(db));
+
+    // Only vectors of one model can share a graph
+    let loader_handle = app_handle.clone();
+    let load_vectors: VectorLoader = Box::new(move || {
+        let vectors = loader_handle.db(|db| get_vector_embeddings_by_model(db, &model))?;
+        Ok(vectors
+            .into_iter()
+            .map(|(chunk_id, vector)| (chunk_id as usize, vector))
+            .collect())
+    });
+    let hnsw = SimilaritySearch::open(
+        hnsw_db_path.to_str().unwrap(),
+        collection_name,
+        params,
+        load_vectors,
+    )?;
     Ok(hnsw)
 }
+
+/// Deletes the files of collections that were swapped out by a re-embedding job.
+fn remove_stale_collections(hnsw_db_path: &Path, collection_name: &str) {
+    let Ok(entries) = fs::read_dir(hnsw_db_path) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some((stem, _)) = file_name.split_once(".hnsw.") else {
+            continue;
+        };
+        if stem.trim_end_matches("_new") != collection_name {
+            info!("Removing stale vector index file {}", file_name);
+            if let Err(e) = fs::remove_file(entry.path()) {
+                error!("Failed to remove {}: {}", file_name, e);
+            }
+        }
+    }
+}
+
+fn initialize_vector_database(
+    app_handle: &AppHandle,
+) -> Result<SimilaritySearch, Box<dyn std::error::Error>> {
+    let collection_name = app_handle
+        .db(|db| get_setting(db, VECTOR_COLLECTION_SETTING))?
+        .setting_value;
+    let collection_name = if collection_name.is_empty() {
+        DEFAULT_VECTOR_COLLECTION.to_string()
+    } else {
+        collection_name
+    };
+
+    // Indexes from before the model was recorded were built with the configured model
+    let model = match app_handle.db(|db| get_vector_collection_model(db)) {
+        Some(model) => model,
+        None => {
+            let model = app_handle.db(|db| EmbeddingSettings::load(db)).model_name();
+            app_handle.db(|db| {
+                insert_or_update_setting(
+                    db,
+                    Setting {
+                        setting_key: VECTOR_COLLECTION_MODEL_SETTING.to_string(),
+                        setting_value: model.clone(),
+                    },
+                )
+            })?;
+            model
+        }
+    };
+
+    remove_stale_collections(&get_vector_db_path(app_handle), &collection_name);
+    open_vector_collection(app_handle, &collection_name, model)
+}
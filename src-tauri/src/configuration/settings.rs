@@ -10,4 +10,36 @@ pub struct Settings {
     pub api_key_claude: String,
     pub api_key_open_ai: String,
     pub vectorization_enabled: bool,
+    #[serde(default)]
+    pub embedding_provider: Option<String>,
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    #[serde(default)]
+    pub embedding_base_url: Option<String>,
+    #[serde(default)]
+    pub embedding_api_key: Option<String>,
+    #[serde(default)]
+    pub embedding_model_path: Option<String>,
+    #[serde(default)]
+    pub hnsw_m: Option<String>,
+    #[serde(default)]
+    pub hnsw_ef_construction: Option<String>,
+    #[serde(default)]
+    pub hnsw_ef_search: Option<String>,
+    #[serde(default)]
+    pub hnsw_max_elements: Option<String>,
+    #[serde(default)]
+    pub retrieval_top_k: Option<String>,
+    #[serde(default)]
+    pub custom_llm_base_url: Option<String>,
+    #[serde(default)]
+    pub custom_llm_model: Option<String>,
+    #[serde(default)]
+    pub custom_llm_api_key: Option<String>,
+    #[serde(default)]
+    pub custom_llm_headers: Option<String>,
+    #[serde(default)]
+    pub llm_models: Option<String>,
+    #[serde(default)]
+    pub monthly_budget: Option<String>,
 }
\ No newline at end of file
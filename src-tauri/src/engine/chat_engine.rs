@@ -1,163 +1,69 @@
+use futures::stream::{self, BoxStream};
 use futures::StreamExt;
+use lazy_static::lazy_static;
 use log::{debug, error, info};
-use reqwest::{Client, Response};
-use serde::{Deserialize, Serialize};
-use serde_json;
-use std::collections::HashSet;
-use std::time::Duration;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tauri::{AppHandle, Manager};
+use tokio::sync::watch;
 
 use crate::configuration::state::ServiceAccess;
-use crate::database;
-use crate::engine::similarity_search_engine::TOPK;
-use crate::repository::activity_log_repository::get_activity_full_text_by_id;
-use crate::repository::project_repository::get_activity_text_from_project;
-use crate::repository::activity_log_repository::get_additional_ids_from_sql_db;
-use crate::repository::settings_repository::get_setting;
+use crate::engine::context_budget_engine::{fit_documents, ContextBudget, ContextReport};
+use crate::engine::llm_engine::{
+    get_llm_provider, is_connection_error, Completion, CompletionRequest, LlmMessage, LlmProvider,
+    StreamEvent, TokenUsage,
+};
+use crate::engine::retrieval_engine::{retrieve_chunks, RetrievedChunk};
+use crate::engine::similarity_search_engine::IndexParams;
+use crate::engine::usage_engine::{check_llm_budget, record_llm_usage};
+use crate::entity::chat_item::{MessageSource, MessageStatus};
+use crate::entity::llm_model::LlmModel;
+use crate::entity::search_filter::SearchFilter;
+use crate::entity::usage_event::{UsagePurpose, UsageScope};
+use crate::repository::chat_db_repository::{
+    create_message, finish_message, mark_interrupted_messages, start_assistant_message,
+    update_message_content,
+};
 
-#[derive(Serialize)]
-struct ClaudeRequest {
-    model: String,
-    max_tokens: usize,
-    messages: Vec<Message>,
-    system: String,
-    stream: bool,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct Message {
-    role: String,
-    content: String,
-}
-
-#[derive(Deserialize)]
-struct ClaudeResponse {
-    content: Vec<Content>,
-    usage: Usage,
-}
-
-#[derive(Deserialize)]
-struct Usage {
-    input_tokens: u32,
-    output_tokens: u32,
-}
+/// Documents shown to the relevance filter are cut off after this many characters.
+const RELEVANCE_PREVIEW_CHARS: usize = 1000;
+const RELEVANCE_MAX_TOKENS: usize = 100;
+const ANSWER_MAX_TOKENS: usize = 2500;
+const NAME_MAX_TOKENS: usize = 20;
+/// Tokens a message takes up besides its text, for the role and separators.
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+const MAX_RETRIES: u32 = 3;
+/// Sources keep this many characters of the passage the model was given.
+const SNIPPET_CHARS: usize = 300;
+/// A streaming answer is written to the database at most this often.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
 
-#[derive(Deserialize)]
-struct Content {
+/// The retrieved documents the answer may draw on.
+#[derive(Default)]
+struct AnswerContext {
     text: String,
+    window_titles: Vec<String>,
+    /// The documents in `text`, the one cited as `[n]` at index `n - 1`.
+    sources: Vec<MessageSource>,
 }
 
-const ANTHROPIC_URL: &str = "https://api.anthropic.com/v1/messages";
-const ANTRHOPIC_MODEL: &str = "claude-3-haiku-20240307";
-const ANTRHOPIC_MAIN_MODEL: &str = "claude-3-7-sonnet-20250219";
-const ANTRHOPIC_MODEL_CHEAP: &str = "claude-3-5-haiku-20241022";
-
-#[tauri::command]
-pub async fn send_prompt_to_llm(
-    app_handle: tauri::AppHandle,
-    conversation_history: Vec<Message>,
-    is_first_message: bool,
-    combined_activity_text: String,
-    model_id: Option<String>, // Add this parameter
-) -> Result<(), String> {
-    let setting =
-        app_handle.db(|db| get_setting(db, "api_key_claude").expect("Failed on api_key_claude"));
-    let setting_openai =
-        app_handle.db(|db| get_setting(db, "api_key_open_ai").expect("Failed on api_key_open_ai"));
-
-    // Configure client with keep-alive and proper timeouts
-    let client = Client::builder()
-        .timeout(Duration::from_secs(180))  // Increased timeout
-        .tcp_keepalive(Duration::from_secs(60))  // Keep connection alive for 60 seconds
-        .pool_idle_timeout(Duration::from_secs(90))  // Allow connections to stay in pool
-        .pool_max_idle_per_host(2)  // Keep up to 2 idle connections per host
-        .connect_timeout(Duration::from_secs(30))
-        .build()
-        .map_err(|e| format!("Failed to create client: {}", e))?;
-
-    let model_to_use = match model_id.as_deref() {
-        Some("claude-3-haiku-20240307") => "claude-3-haiku-20240307",
-        Some("claude-3-5-haiku-20241022") => "claude-3-5-haiku-20241022",
-        _ => "claude-3-7-sonnet-20250219", // Default to Claude 3.7 Sonnet
-    };
-    let mut filtered_context = String::new();
-    let mut window_titles = Vec::new();
-    debug!("Combined activity text: {}", combined_activity_text);
-
-    if is_first_message {
-        let user_prompt = conversation_history
-            .last()
-            .map(|msg| msg.content.clone())
-            .unwrap_or_default();
-        info!("User Prompt: {}", user_prompt);
-        
-
-        info!("Getting database instance");
-        let hnsw_bind = database::get_vector_db(&app_handle)
-            .await
-            .expect("Database initialization failed!");
-        let top_k = TOPK;
-        let hnsw_guard = hnsw_bind.lock().await;
-        info!("Setting up database lock");
-        let db = hnsw_guard.as_ref().expect("HNSW database not initialized!");
-        info!("Initiating similarity search...");
-
-        let similar_ids_with_distances = db
-            .top_k(&user_prompt, top_k, &setting_openai.setting_value)
-            .await
-            .map_err(|e| format!("Similarity search failed: {}", e))?;
-
-        let similar_ids_vec: Vec<(i64, f32)> = similar_ids_with_distances
-            .into_iter()
-            .map(|(id, distance)| (id as i64, distance))
-            .collect();
-
-        let similar_ids: Vec<i64> = similar_ids_vec.iter().map(|(id, _)| *id).collect();
-
-
-        let mut all_ids_set = HashSet::new();
-        all_ids_set.extend(similar_ids);
-
-        let mut context = String::new();
-
-        for (index, document_id) in all_ids_set.iter().enumerate() {
-            let result: Option<(String, String)> = app_handle
-                .db(|db| get_activity_text_from_project(db, *document_id))
-                .map_err(|e| {
-                    format!(
-                        "Failed to retrieve document text for ID {}: {}",
-                        document_id, e
-                    )
-                })
-                .unwrap_or_else(|err| {
-                    error!("{}", err);
-                    None
-                });
-
-            if let Some((document_name, text)) = result {
-                debug!("Document {}: ID: {}", index + 1, document_id);
-                // Limit text to 500 characters for filtering stage
-                let filtered_text = if text.len() > 1000 {
-                    text.chars().take(1000).collect::<String>() + "..."
-                } else {
-                    text.clone()
-                };
-                context.push_str(&format!(
-                    "Document ID: {}\nContent:\n{}\n\n",
-                    document_id, filtered_text
-                ));
-            }
-        }
-
-        if context.is_empty() {
-            context.push_str("No relevant documents found.\n\n");
-        }
-
+fn snippet(text: &str) -> String {
+    if text.chars().count() > SNIPPET_CHARS {
+        text.chars().take(SNIPPET_CHARS).collect::<String>() + "..."
+    } else {
+        text.to_string()
+    }
+}
 
-        let relevance_system_prompt = format!( "The user's prompt is: {}\n\n. You are an intelligent and logical personal assistant. Your task is to carefully review the content of provided documents and output solely a maximum of four numerical IDs of the documents that are directly related to the user prompt and are highly likely to help in answering the user's prompt (corresponding to the Document ID at the beginning of each document). If an individual document is not extremely relevant to the user prompt and the user prompt can be successfully answered without that document, do not include it in the list of returned documents.
+fn relevance_system_prompt(user_prompt: &str) -> String {
+    format!(
+        "The user's prompt is: {}\n\n. You are an intelligent and logical personal assistant. Your task is to carefully review the content of provided documents and output solely a maximum of four numerical IDs of the documents that are directly related to the user prompt and are highly likely to help in answering the user's prompt (corresponding to the Document ID at the beginning of each document). If an individual document is not extremely relevant to the user prompt and the user prompt can be successfully answered without that document, do not include it in the list of returned documents.
 
         Examples of relevant and irrelevant documents in different business scenarios:
-        If a document is virtually identical to another one, just include one of them in the list of returned documents.
         
         Example 1: The user prompt is to outline effective marketing strategies for social media.
         - Relevant document:
@@ -223,364 +129,900 @@ pub async fn send_prompt_to_llm(
     
         The user's prompt is: {}\n\nOutput the relevant document IDs as a comma-separated list of numbers only or an empty list, with absolutely no other additional text or explanations. For example: 123,456,789 or an empty list.",
         user_prompt, user_prompt
-    );
+    )
+}
 
-        let relevance_request_body = ClaudeRequest {
-            model: ANTRHOPIC_MODEL_CHEAP.to_string(),
-            max_tokens: 100,
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: context,
-            }],
-            system: relevance_system_prompt,
-            stream: false,
+/// Numbers the chunks as the relevance filter sees them, long ones are cut off.
+fn relevance_documents(chunks: &[RetrievedChunk]) -> String {
+    let mut context = String::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        debug!(
+            "Document {}: {} {} (chunk {:?})",
+            index + 1,
+            chunk.source_type.as_str(),
+            chunk.source_id,
+            chunk.chunk_id
+        );
+        let filtered_text = if chunk.text.chars().count() > RELEVANCE_PREVIEW_CHARS {
+            chunk
+                .text
+                .chars()
+                .take(RELEVANCE_PREVIEW_CHARS)
+                .collect::<String>()
+                + "..."
+        } else {
+            chunk.text.clone()
         };
+        context.push_str(&format!(
+            "Document ID: {}\nContent:\n{}\n\n",
+            index + 1,
+            filtered_text
+        ));
+    }
+    if context.is_empty() {
+        context.push_str("No relevant documents found.\n\n");
+    }
+    context
+}
 
-        let relevance_response = client
-            .post(ANTHROPIC_URL)
-            .header("Content-Type", "application/json")
-            .header("x-api-key", &setting.setting_value)
-            .header("anthropic-version", "2023-06-01")
-            .header("Connection", "keep-alive")  // Added keep-alive header
-            .json(&relevance_request_body)
-            .send()
-            .await
-            .map_err(|e| format!("Relevance filtering request failed: {}", e))?;
-
-        debug!("Relevance filtering response: {:?}", relevance_response);
-
-        if relevance_response.status().is_success() {
-            let relevance_result: ClaudeResponse = relevance_response
-                .json()
-                .await
-                .map_err(|e| format!("Failed to parse relevance filtering response: {}", e))?;
-
-            info!(
-                "Relevance filtering token usage - Input: {}, Output: {}",
-                relevance_result.usage.input_tokens, relevance_result.usage.output_tokens
-            );
-
-            let relevant_document_ids: Vec<i64> = relevance_result
-                .content
-                .first()
-                .map(|content| {
-                    let text = &content.text;
-                    let numbers: Vec<i64> = text
-                        .split(|c: char| !c.is_numeric())
-                        .filter_map(|s| s.parse().ok())
-                        .collect();
-                    numbers
-                })
-                .unwrap_or_default();
+/// Retrieves documents for the prompt and keeps the ones `model` considers relevant, in the
+/// order they were retrieved in.
+async fn retrieve_relevant_chunks(
+    app_handle: &AppHandle,
+    provider: &dyn LlmProvider,
+    model: &LlmModel,
+    user_prompt: &str,
+    scope: UsageScope,
+) -> Result<Vec<RetrievedChunk>, String> {
+    let top_k = app_handle.db(|db| IndexParams::load(db)).top_k;
+    let retrieved_chunks = retrieve_chunks(
+        app_handle,
+        user_prompt,
+        top_k,
+        &SearchFilter::for_project(scope.project_id),
+    )
+    .await?;
+    // Nothing to pick from, so the relevance call would only cost tokens
+    if retrieved_chunks.is_empty() {
+        return Ok(Vec::new());
+    }
 
-            debug!("Relevant document IDs: {:?}", relevant_document_ids);
+    let relevance_request = CompletionRequest {
+        model: model.id.clone(),
+        system: relevance_system_prompt(user_prompt),
+        messages: vec![LlmMessage::user(relevance_documents(&retrieved_chunks))],
+        max_tokens: RELEVANCE_MAX_TOKENS.min(model.max_output_tokens),
+    };
+    let relevance_result = provider
+        .complete(&relevance_request)
+        .await
+        .map_err(|e| format!("Relevance filtering request failed: {}", e))?;
+    let usage = completion_usage(provider, &relevance_request, &relevance_result);
+    info!(
+        "Relevance filtering token usage - Input: {}, Output: {}",
+        usage.input_tokens, usage.output_tokens
+    );
+    record_llm_usage(
+        app_handle,
+        provider.id(),
+        model,
+        UsagePurpose::RelevanceFilter,
+        usage,
+        scope,
+    );
 
-            for document_id in relevant_document_ids {
-                let result: Option<(String, String)> = app_handle
-                    .db(|db| get_activity_text_from_project(db, document_id))
-                    .map_err(|e| format!("Failed to retrieve document text: {}", e))?;
+    let relevant_document_ids: Vec<i64> = relevance_result
+        .text
+        .split(|c: char| !c.is_numeric())
+        .filter_map(|s| s.parse().ok())
+        .collect();
+    debug!("Relevant document IDs: {:?}", relevant_document_ids);
 
-                if let Some((document_name, text)) = result {
-                    filtered_context.push_str(&format!(
-                        "Document ID: {}\nContent:\n{}\n\n",
-                        document_id, text
-                    ));
-                    window_titles.push(document_name);
-                }
+    // Documents are numbered from 1 in the order they were retrieved.
+    let mut positions: Vec<usize> = relevant_document_ids
+        .into_iter()
+        .filter_map(|document_id| usize::try_from(document_id).ok()?.checked_sub(1))
+        .filter(|position| *position < retrieved_chunks.len())
+        .collect();
+    positions.sort_unstable();
+    positions.dedup();
+    Ok(positions
+        .into_iter()
+        .map(|position| retrieved_chunks[position].clone())
+        .collect())
+}
+
+/// A retrieved document as the answer prompt shows it, the answer cites it by `citation`.
+fn document_entry(citation: usize, chunk: &RetrievedChunk) -> String {
+    format!("[{}] {}\n{}\n\n", citation, chunk.document_name, chunk.text)
+}
+
+/// The context made of the first `entries.len()` of `chunks`, `entries` being what fit of them.
+fn answer_context(chunks: &[RetrievedChunk], entries: Vec<String>) -> AnswerContext {
+    let mut context = AnswerContext::default();
+    for (chunk, entry) in chunks.iter().zip(entries) {
+        context.text.push_str(&entry);
+        context.sources.push(MessageSource {
+            citation: context.sources.len() + 1,
+            source_type: chunk.source_type,
+            source_id: chunk.source_id,
+            chunk_id: chunk.chunk_id,
+            title: chunk.document_name.clone(),
+            snippet: snippet(&chunk.text),
+            score: chunk.score,
+        });
+        if !context.window_titles.contains(&chunk.document_name) {
+            context.window_titles.push(chunk.document_name.clone());
+        }
+    }
+    debug!("Filtered context for final response generation: {}", context.text);
+    context
+}
+
+fn answer_system_prompt(provider_name: &str, context: &str) -> String {
+    let mut prompt = format!(
+        "You are Heelix chat app that is powered by {} LLM. Heelix chat is developed by Heelix Technologies. Only identify yourself as such. Provide answer in markdown format.",
+        provider_name
+    );
+    if !context.is_empty() {
+        prompt.push_str(&format!(
+            "\n\nThe documents between the <documents> tags were retrieved from the user's device and may help in answering the prompt. Review them carefully to decide if they are relevant. If they are, use them to answer the query. If they are not relevant to the query, ignore them completely when responding and respond as if they were not there without mentioning having received them at all. Every document starts with its number in square brackets. When you use a document, cite it right after the statement it supports with its number in square brackets, e.g. [1] or [1][3].\n\n<documents>\n{}</documents>",
+            context
+        ));
+    }
+    prompt
+}
+
+/// Numbers of the documents `answer` cites as `[n]` or `[n, m]`, in the order they're first
+/// cited. Numbers outside `1..=document_count` are ignored.
+fn parse_citations(answer: &str, document_count: usize) -> Vec<usize> {
+    let mut citations = Vec::new();
+    let mut rest = answer;
+    while let Some(start) = rest.find('[') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find(['[', ']']) else {
+            break;
+        };
+        if !rest[end..].starts_with(']') {
+            continue;
+        }
+        let numbers: Option<Vec<usize>> = rest[..end]
+            .split(',')
+            .map(|number| number.trim().parse().ok())
+            .collect();
+        for number in numbers.unwrap_or_default() {
+            if (1..=document_count).contains(&number) && !citations.contains(&number) {
+                citations.push(number);
             }
+        }
+        rest = &rest[end + 1..];
+    }
+    citations
+}
 
-            debug!(
-                "Filtered context for final response generation: {}",
-                filtered_context
-            );
+/// Turns the conversation into messages the providers accept: turns alternate between user and
+/// assistant, starting with the user. Consecutive turns of the same role are joined.
+fn conversation_messages(conversation_history: Vec<LlmMessage>) -> Vec<LlmMessage> {
+    let mut messages: Vec<LlmMessage> = Vec::new();
+    for message in conversation_history {
+        let role = if message.role == "user" {
+            "user"
         } else {
-            let error_message = relevance_response
-                .text()
-                .await
-                .map_err(|e| format!("Failed to read error message: {}", e))?;
-            info!(
-                "Error from Claude API during relevance filtering: {}",
-                error_message
-            );
-            return Err(format!(
-                "Error from Claude API during relevance filtering: {}",
-                error_message
-            ));
+            "assistant"
+        };
+        if message.content.trim().is_empty() || (messages.is_empty() && role == "assistant") {
+            continue;
+        }
+        match messages.last_mut() {
+            Some(last) if last.role == role => {
+                last.content.push_str("\n\n");
+                last.content.push_str(&message.content);
+            }
+            _ => messages.push(LlmMessage {
+                role: role.to_string(),
+                content: message.content,
+            }),
         }
     }
+    messages
+}
 
-    let conversation_history_content = conversation_history
+/// Keeps the most recent messages that fit in `budget` tokens. The last message is always kept,
+/// and the kept ones still start with a user turn.
+fn trim_history(
+    mut messages: Vec<LlmMessage>,
+    budget: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Vec<LlmMessage> {
+    let mut used = 0;
+    let mut keep = 0;
+    for message in messages.iter().rev() {
+        let tokens = count_tokens(&message.content) + MESSAGE_OVERHEAD_TOKENS;
+        if keep > 0 && used + tokens > budget {
+            break;
+        }
+        used += tokens;
+        keep += 1;
+    }
+    let mut start = messages.len() - keep;
+    while start + 1 < messages.len() && messages[start].role != "user" {
+        start += 1;
+    }
+    if start > 0 {
+        info!("Dropped {} old messages to fit the context window", start);
+    }
+    messages.split_off(start)
+}
+
+/// Approximate number of tokens `request` takes up.
+fn request_tokens(provider: &dyn LlmProvider, request: &CompletionRequest) -> u32 {
+    let message_tokens: usize = request
+        .messages
         .iter()
-        .rev()
-        .skip(1)
-        .rev()
-        .map(|message| {
-            let role = if message.role == "user" {
-                "User"
-            } else {
-                "Assistant"
-            };
-            format!("{}: {}", role, message.content)
-        })
-        .collect::<Vec<String>>()
-        .join("\n");
-
-    let system_prompt = format!("You are Heelix chat app that is powered by Anthropic LLM. Heelix chat is developed by Heelix Technologies. Only identify yourself as such. Provide answer in markdown format. The following documents were retrieved from the user's device and may help in answering the prompt. Review them carefully to decide if they are relevant, if they are - using them to answer the query, but if they are not relevant to query, ignore them completely when responding, respond as if they were not there without mentioning having received them at all.{}\n\n
-Attached is the conversation history for context only. When answering, only give a single assistant response, do not also continue the conversation with a user answer.):
-{}\n\n", filtered_context, conversation_history_content);
-
-    debug!("Sending final response generation request to Claude API...");
-    let mut user_message = conversation_history
-        .last()
-        .map(|msg| msg.content.clone())
-        .unwrap_or_default();
-
-    if !combined_activity_text.is_empty() {
-        user_message = format!(
-            "{}The following is additional context from selected activities:\n{}",
-            user_message, combined_activity_text
-        );
+        .map(|message| provider.count_tokens(&message.content) + MESSAGE_OVERHEAD_TOKENS)
+        .sum();
+    (provider.count_tokens(&request.system) + message_tokens) as u32
+}
+
+/// Token usage of a completion, estimated when the provider doesn't report it.
+fn completion_usage(
+    provider: &dyn LlmProvider,
+    request: &CompletionRequest,
+    completion: &Completion,
+) -> TokenUsage {
+    if provider.capabilities().reports_usage {
+        return completion.usage;
+    }
+    TokenUsage {
+        input_tokens: request_tokens(provider, request),
+        output_tokens: provider.count_tokens(&completion.text) as u32,
+    }
+}
+
+type AnswerStream = BoxStream<'static, anyhow::Result<StreamEvent>>;
+
+/// Streams the answer when `model` can, otherwise it arrives as a single delta.
+async fn request_answer(
+    provider: &dyn LlmProvider,
+    model: &LlmModel,
+    request: &CompletionRequest,
+) -> anyhow::Result<AnswerStream> {
+    if model.capabilities.streaming {
+        return provider.stream(request).await;
+    }
+    let completion = provider.complete(request).await?;
+    let events = vec![
+        Ok(StreamEvent::Delta(completion.text)),
+        Ok(StreamEvent::Usage(completion.usage)),
+    ];
+    Ok(stream::iter(events).boxed())
+}
+
+/// What `send_prompt` was asked to answer.
+struct Prompt {
+    conversation_history: Vec<LlmMessage>,
+    is_first_message: bool,
+    combined_activity_text: String,
+    model_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model finished its answer.
+    Stop,
+    Cancelled,
+    Error,
+}
+
+/// What a generation streams to the frontend, tagged with `type`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChatStreamEvent {
+    /// How the context window was split for the request and what was left out to fit it.
+    Context(ContextReport),
+    /// The next piece of the answer, along with the whole answer so far.
+    Delta { delta: String, full_text: String },
+    /// The retrieved documents the answer cites.
+    Sources {
+        sources: Vec<MessageSource>,
+        window_titles: Vec<String>,
+    },
+    /// Always the last event of a generation, `full_text` is what was received until then.
+    Finish {
+        finish_reason: FinishReason,
+        full_text: String,
+        usage: TokenUsage,
+        error: Option<String>,
+    },
+}
+
+impl FinishReason {
+    fn message_status(&self) -> MessageStatus {
+        match self {
+            FinishReason::Stop => MessageStatus::Complete,
+            FinishReason::Cancelled => MessageStatus::Cancelled,
+            FinishReason::Error => MessageStatus::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ChatStreamPayload {
+    chat_id: Option<i64>,
+    generation_id: u64,
+    /// The assistant message the answer is stored in.
+    message_id: Option<i64>,
+    #[serde(flatten)]
+    event: ChatStreamEvent,
+}
+
+/// Sends the events of one generation as `chat_stream` events, so the frontend can tell apart
+/// generations running at the same time, and stores the answer in its chat.
+struct ChatStream {
+    app_handle: AppHandle,
+    chat_id: Option<i64>,
+    project_id: Option<i64>,
+    generation_id: u64,
+    message_id: Option<i64>,
+}
+
+impl ChatStream {
+    fn usage_scope(&self) -> UsageScope {
+        UsageScope {
+            chat_id: self.chat_id,
+            project_id: self.project_id,
+        }
     }
 
-    let request_body = ClaudeRequest {
-        model: ANTRHOPIC_MAIN_MODEL.to_string(),
-        max_tokens: 2500,
-        messages: vec![Message {
-            role: "user".to_string(),
-            content: user_message,
-        }],
-        system: system_prompt,
-        stream: true,
+    fn send(&self, event: ChatStreamEvent) -> Result<(), String> {
+        let payload = ChatStreamPayload {
+            chat_id: self.chat_id,
+            generation_id: self.generation_id,
+            message_id: self.message_id,
+            event,
+        };
+        self.app_handle
+            .get_window("main")
+            .ok_or("Failed to get main window".to_string())?
+            .emit("chat_stream", payload)
+            .map_err(|e| format!("Failed to emit chat_stream: {}", e))
+    }
+
+    /// Writes the answer received so far, so it survives the app closing.
+    fn save(&self, answer: &PartialAnswer) {
+        let Some(message_id) = self.message_id else {
+            return;
+        };
+        if let Err(e) = self
+            .app_handle
+            .db(|db| update_message_content(db, message_id, &answer.text))
+        {
+            error!("Failed to save message {}: {}", message_id, e);
+        }
+    }
+
+    /// Saves the answer with its final status. An answer that failed before any text arrived
+    /// keeps the error instead, so the chat doesn't show an empty message.
+    fn finish(&self, answer: &PartialAnswer, finish_reason: FinishReason, error: Option<&str>) {
+        let Some(message_id) = self.message_id else {
+            return;
+        };
+        let content = match error {
+            Some(error) if answer.text.is_empty() => error,
+            _ => answer.text.as_str(),
+        };
+        let result = self.app_handle.db(|db| {
+            finish_message(
+                db,
+                message_id,
+                content,
+                finish_reason.message_status(),
+                &answer.sources,
+                answer.usage.input_tokens,
+                answer.usage.output_tokens,
+            )
+        });
+        if let Err(e) = result {
+            error!("Failed to save message {}: {}", message_id, e);
+        }
+    }
+}
+
+/// The answer as far as it was received.
+#[derive(Default)]
+struct PartialAnswer {
+    text: String,
+    usage: TokenUsage,
+    sources: Vec<MessageSource>,
+}
+
+lazy_static! {
+    /// Cancellation flags of the running generations, by generation id.
+    static ref GENERATIONS: Mutex<HashMap<u64, watch::Sender<bool>>> = Mutex::new(HashMap::new());
+}
+static NEXT_GENERATION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Runs `future` unless the generation is cancelled first, `None` when it was.
+async fn until_cancelled<T>(
+    cancelled: &mut watch::Receiver<bool>,
+    future: impl Future<Output = T>,
+) -> Option<T> {
+    tokio::select! {
+        biased;
+        Ok(_) = cancelled.wait_for(|cancelled| *cancelled) => None,
+        value = future => Some(value),
+    }
+}
+
+/// An answer that started streaming in.
+struct StartedAnswer {
+    provider: Arc<dyn LlmProvider>,
+    model: LlmModel,
+    context: AnswerContext,
+    /// Size of the request, for providers that don't report usage.
+    request_tokens: u32,
+    events: AnswerStream,
+}
+
+/// Picks the model, gathers the context and sends the request, up to the point where the answer
+/// starts streaming in.
+async fn start_answer(stream: &ChatStream, prompt: Prompt) -> Result<StartedAnswer, String> {
+    let app_handle = &stream.app_handle;
+    let provider = get_llm_provider(app_handle, prompt.model_id.as_deref())
+        .map_err(|e| format!("LLM provider unavailable: {}", e))?;
+    let model = provider
+        .resolve_model(prompt.model_id.as_deref())
+        .map_err(|e| e.to_string())?;
+    check_llm_budget(app_handle, &model).map_err(|e| e.to_string())?;
+    info!("Answering with {} {}", provider.name(), model.id);
+    debug!("Combined activity text: {}", prompt.combined_activity_text);
+
+    let chunks = if prompt.is_first_message {
+        let user_prompt = prompt
+            .conversation_history
+            .last()
+            .map(|msg| msg.content.clone())
+            .unwrap_or_default();
+        info!("User Prompt: {}", user_prompt);
+        retrieve_relevant_chunks(
+            app_handle,
+            provider.as_ref(),
+            &model,
+            &user_prompt,
+            stream.usage_scope(),
+        )
+        .await?
+    } else {
+        Vec::new()
+    };
+
+    let mut messages = conversation_messages(prompt.conversation_history);
+    match messages.last_mut() {
+        Some(last) if last.role == "user" => {
+            if !prompt.combined_activity_text.is_empty() {
+                last.content = format!(
+                    "{}The following is additional context from selected activities:\n{}",
+                    last.content, prompt.combined_activity_text
+                );
+            }
+        }
+        _ => return Err("The conversation doesn't end with a user message".to_string()),
+    }
+
+    // Older messages and lower ranked documents give way first when the window is too small
+    let count_tokens = |text: &str| provider.count_tokens(text);
+    let message_tokens =
+        |message: &LlmMessage| count_tokens(&message.content) + MESSAGE_OVERHEAD_TOKENS;
+    let documents: Vec<String> = chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| document_entry(index + 1, chunk))
+        .collect();
+    // With documents the system prompt also explains how to use them
+    let instructions = answer_system_prompt(
+        provider.name(),
+        if documents.is_empty() { "" } else { "\n" },
+    );
+    let budget = ContextBudget::allocate(
+        model.context_window,
+        ANSWER_MAX_TOKENS.min(model.max_output_tokens),
+        count_tokens(&instructions),
+        messages.last().map_or(0, message_tokens),
+        messages.iter().rev().skip(1).map(message_tokens).sum(),
+        documents
+            .iter()
+            .map(|document| count_tokens(document))
+            .sum(),
+    )
+    .map_err(|e| e.to_string())?;
+    let fitted = fit_documents(&documents, budget.documents, count_tokens);
+    let message_count = messages.len();
+    let messages = trim_history(messages, budget.prompt + budget.history, count_tokens);
+
+    let kept_documents = fitted.texts.len();
+    let report = ContextReport {
+        budget,
+        dropped_messages: message_count - messages.len(),
+        dropped_documents: chunks[kept_documents..]
+            .iter()
+            .map(|chunk| chunk.document_name.clone())
+            .collect(),
+        truncated_document: fitted
+            .last_truncated
+            .then(|| chunks[kept_documents - 1].document_name.clone()),
+    };
+    if !report.is_complete() {
+        info!("Shortened the context to fit {}: {:?}", model.id, report);
+    }
+    stream.send(ChatStreamEvent::Context(report))?;
+
+    let context = answer_context(&chunks, fitted.texts);
+    let request = CompletionRequest {
+        model: model.id.clone(),
+        system: answer_system_prompt(provider.name(), &context.text),
+        messages,
+        max_tokens: budget.output,
     };
 
     let mut attempt = 0;
-    let max_retries = 3;
     let mut delay = Duration::from_secs(1);
+    let events = loop {
+        match request_answer(provider.as_ref(), &model, &request).await {
+            Ok(events) => break events,
+            Err(e) if is_connection_error(&e) && attempt < MAX_RETRIES => {
+                attempt += 1;
+                error!(
+                    "Request to {} failed: {}. Retrying... (Attempt {}/{})",
+                    provider.name(),
+                    e,
+                    attempt,
+                    MAX_RETRIES
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2; // Exponential backoff
+            }
+            Err(e) if is_connection_error(&e) => {
+                let error_message = format!(
+                    "Apologies, {} appears to be down right now - please try again later or switch to another model for the time being",
+                    provider.name()
+                );
+                error!("Request failed after {} attempts: {}", MAX_RETRIES, e);
+                stream.send(ChatStreamEvent::Delta {
+                    delta: error_message.clone(),
+                    full_text: error_message.clone(),
+                })?;
+                return Err(error_message);
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    };
+    Ok(StartedAnswer {
+        request_tokens: request_tokens(provider.as_ref(), &request),
+        provider,
+        model,
+        context,
+        events,
+    })
+}
+
+/// Streams the answer to `prompt` into `answer` until it's complete or the generation is
+/// cancelled. A cancelled or failed answer keeps what was received so far.
+async fn generate(
+    stream: &ChatStream,
+    prompt: Prompt,
+    mut cancelled: watch::Receiver<bool>,
+    answer: &mut PartialAnswer,
+) -> Result<FinishReason, String> {
+    let Some(started) = until_cancelled(&mut cancelled, start_answer(stream, prompt)).await else {
+        return Ok(FinishReason::Cancelled);
+    };
+    let StartedAnswer {
+        provider,
+        model,
+        context,
+        request_tokens,
+        mut events,
+    } = started?;
 
-    loop {
-        let response = client
-            .post(ANTHROPIC_URL)
-            .header("Content-Type", "application/json")
-            .header("x-api-key", &setting.setting_value)
-            .header("anthropic-version", "2023-06-01")
-            .header("Connection", "keep-alive")
-            .json(&request_body)
-            .send()
-            .await;
-
-        match response {
-            Ok(resp) => {
-                return handle_success_response(resp, app_handle, window_titles.clone()).await;
+    let mut result = Ok(FinishReason::Stop);
+    let mut last_save = Instant::now();
+    while let Some(event) = until_cancelled(&mut cancelled, events.next()).await {
+        let Some(event) = event else {
+            break;
+        };
+        match event {
+            Ok(StreamEvent::Delta(delta)) => {
+                answer.text.push_str(&delta);
+                stream.send(ChatStreamEvent::Delta {
+                    delta,
+                    full_text: answer.text.clone(),
+                })?;
+                if last_save.elapsed() >= SAVE_INTERVAL {
+                    stream.save(answer);
+                    last_save = Instant::now();
+                }
             }
+            Ok(StreamEvent::Usage(usage)) => answer.usage = usage,
             Err(e) => {
-                if attempt < max_retries {
-                    attempt += 1;
-                    error!(
-                        "Request to Claude API failed: {}. Retrying... (Attempt {}/{})",
-                        e, attempt, max_retries
-                    );
-                    tokio::time::sleep(delay).await;
-                    delay *= 2;  // Exponential backoff
-                } else {
-                    let error_message =
-                        "Apologies, Claude API appears to be down right now - please try again later or switch to OpenAI for the time being";
-                    error!("Request failed after {} attempts: {}", max_retries, e);
-                    app_handle
-                        .get_window("main")
-                        .expect("Failed to get main window")
-                        .emit("llm_response", error_message.to_string())
-                        .map_err(|emit_err| {
-                            format!("Failed to emit error message: {}", emit_err)
-                        })?;
-                    return Err(error_message.to_string());
-                }
+                result = Err(e.to_string());
+                break;
             }
         }
     }
-}
+    if *cancelled.borrow() {
+        info!("Generation cancelled after {} characters", answer.text.len());
+        result = Ok(FinishReason::Cancelled);
+    }
+    // Dropping the stream closes the connection to the provider
+    drop(events);
+    if !provider.capabilities().reports_usage || answer.usage.output_tokens == 0 {
+        answer.usage.output_tokens = provider.count_tokens(&answer.text) as u32;
+    }
+    if answer.usage.input_tokens == 0 {
+        answer.usage.input_tokens = request_tokens;
+    }
+    record_llm_usage(
+        &stream.app_handle,
+        provider.id(),
+        &model,
+        UsagePurpose::Answer,
+        answer.usage,
+        stream.usage_scope(),
+    );
 
-async fn handle_success_response(
-    response: Response,
-    app_handle: AppHandle,
-    window_titles: Vec<String>,
-) -> Result<(), String> {
-    if response.status().is_success() {
-        let mut stream = response.bytes_stream();
-        let mut completion = String::new();
-        let mut input_tokens = 0;
-        let mut output_tokens = 0;
-        let mut buffer: Vec<u8> = Vec::new();  // Explicitly specify Vec<u8> for byte storage
-
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.map_err(|e| format!("Failed to read chunk: {}", e))?;
-            let text = String::from_utf8_lossy(&chunk);
-
-            for line in text.lines() {
-                if !line.starts_with("data: ") {
-                    continue;
-                }
-                
-                let data = line[6..].trim();
-                
-                // Skip empty data lines
-                if data.is_empty() {
-                    continue;
-                }
+    answer.sources = parse_citations(&answer.text, context.sources.len())
+        .into_iter()
+        .map(|citation| context.sources[citation - 1].clone())
+        .collect();
+    stream.send(ChatStreamEvent::Sources {
+        sources: answer.sources.clone(),
+        window_titles: context.window_titles,
+    })?;
 
-                // Handle ping events - these keep the connection alive
-                if data == "{\"type\": \"ping\"}" {
-                    debug!("Received ping event");
-                    continue;
-                }
+    info!(
+        "Final response token usage - Input: {}, Output: {}",
+        answer.usage.input_tokens, answer.usage.output_tokens
+    );
+    info!("Result from {}: {}", provider.name(), answer.text);
+    result
+}
 
-                // Parse the event data
-                let json_data: serde_json::Value = match serde_json::from_str(data) {
-                    Ok(data) => data,
-                    Err(e) => {
-                        error!("Failed to parse event data: {}", e);
-                        continue;
-                    }
-                };
-
-                // Handle error events
-                if let Some("error") = json_data["type"].as_str() {
-                    if let Some(error) = json_data["error"].as_object() {
-                        let error_type = error["type"].as_str().unwrap_or("unknown");
-                        let error_message = error["message"].as_str().unwrap_or("Unknown error");
-                        
-                        error!("Received error event: {} - {}", error_type, error_message);
-                        
-                        match error_type {
-                            "overloaded_error" => {
-                                return Err("Service is currently overloaded. Please try again later.".to_string());
-                            }
-                            _ => {
-                                return Err(format!("Stream error: {}", error_message));
-                            }
-                        }
-                    }
-                }
+/// Starts answering the last message of `conversation_history` with the model `model_id`, or the
+/// default model of the provider picked in the settings, and returns the id of the generation.
+/// Generations run concurrently, each one is cancelled on its own.
+///
+/// On the first message of a chat, documents are retrieved from the index and filtered for
+/// relevance before they're handed to the model. The answer is emitted as `chat_stream` events
+/// tagged with `chat_id` and the generation id: a `context` event once the prompt is assembled,
+/// `delta` events while the answer streams in, then `sources`, then a `finish` event with the
+/// finish reason and token usage.
+///
+/// With a `chat_id`, the user message and the answer are stored in the chat, the answer while it
+/// streams in.
+#[tauri::command]
+pub async fn send_prompt(
+    app_handle: AppHandle,
+    conversation_history: Vec<LlmMessage>,
+    is_first_message: bool,
+    combined_activity_text: String,
+    model_id: Option<String>,
+    project_id: Option<i64>,
+    chat_id: Option<i64>,
+) -> Result<u64, String> {
+    let prompt = Prompt {
+        conversation_history,
+        is_first_message,
+        combined_activity_text,
+        model_id,
+    };
+    let message_id = match chat_id {
+        Some(chat_id) => {
+            let user_input = prompt
+                .conversation_history
+                .last()
+                .map(|message| message.content.as_str())
+                .unwrap_or_default();
+            let message_id = app_handle
+                .db(|db| {
+                    create_message(db, chat_id, "user", user_input, &[])?;
+                    start_assistant_message(db, chat_id)
+                })
+                .map_err(|e| format!("Failed to store the message: {}", e))?;
+            Some(message_id)
+        }
+        None => None,
+    };
+    let generation_id = NEXT_GENERATION_ID.fetch_add(1, Ordering::Relaxed);
+    let (cancel, cancelled) = watch::channel(false);
+    GENERATIONS.lock().unwrap().insert(generation_id, cancel);
+    let stream = ChatStream {
+        app_handle,
+        chat_id,
+        project_id,
+        generation_id,
+        message_id,
+    };
 
-                // Handle different event types
-                match json_data["type"].as_str() {
-                    Some("message_start") => {
-                        if let Some(usage) = json_data["message"]["usage"].as_object() {
-                            input_tokens = usage["input_tokens"].as_u64().unwrap_or(0) as u32;
-                            output_tokens = usage["output_tokens"].as_u64().unwrap_or(0) as u32;
-                        }
-                    }
-                    Some("content_block_delta") => {
-                        if let Some(delta) = json_data["delta"]["text"].as_str() {
-                            completion.push_str(delta);
-                            
-                            // Emit updates to frontend more frequently
-                            app_handle
-                                .get_window("main")
-                                .expect("Failed to get main window")
-                                .emit("llm_response", completion.clone())
-                                .map_err(|e| format!("Failed to emit response: {}", e))?;
-                        }
-                    }
-                    Some("message_delta") => {
-                        if let Some(usage) = json_data["usage"].as_object() {
-                            output_tokens = usage["output_tokens"].as_u64().unwrap_or(0) as u32;
-                        }
-                    }
-                    Some("message_stop") => {
-                        // Final emission of window titles and completion
-                        app_handle
-                            .get_window("main")
-                            .expect("Failed to get main window")
-                            .emit(
-                                "window_titles",
-                                serde_json::to_string(&window_titles).unwrap(),
-                            )
-                            .map_err(|e| format!("Failed to emit window titles: {}", e))?;
-
-                        app_handle
-                            .get_window("main")
-                            .expect("Failed to get main window")
-                            .emit("output_tokens", output_tokens)
-                            .map_err(|e| format!("Failed to emit output tokens: {}", e))?;
-                    }
-                    _ => {} // Ignore unknown event types
-                }
+    tauri::async_runtime::spawn(async move {
+        let mut answer = PartialAnswer::default();
+        let (finish_reason, error) = match generate(&stream, prompt, cancelled, &mut answer).await {
+            Ok(finish_reason) => (finish_reason, None),
+            Err(e) => {
+                error!("Generation {} failed: {}", generation_id, e);
+                (FinishReason::Error, Some(e))
             }
+        };
+        GENERATIONS.lock().unwrap().remove(&generation_id);
+        stream.finish(&answer, finish_reason, error.as_deref());
+        let finish = ChatStreamEvent::Finish {
+            finish_reason,
+            full_text: answer.text,
+            usage: answer.usage,
+            error,
+        };
+        if let Err(e) = stream.send(finish) {
+            error!("{}", e);
         }
+    });
+    Ok(generation_id)
+}
 
-        info!(
-            "Final response token usage - Input: {}, Output: {}",
-            input_tokens, output_tokens
-        );
-        info!("Result from Claude: {}", completion);
-        Ok(())
-    } else {
-        let error_message = response
-            .text()
-            .await
-            .map_err(|e| format!("Failed to read error message: {}", e))?;
-        info!("Error from Claude API: {}", error_message);
-        Err(format!("Error from Claude API: {}", error_message))
+/// Answers that were streaming when the app closed keep what arrived until then and are marked
+/// as interrupted.
+pub fn recover_interrupted_generations(app_handle: &AppHandle) {
+    match app_handle.db(mark_interrupted_messages) {
+        Ok(0) => {}
+        Ok(count) => info!("Marked {} interrupted answers", count),
+        Err(e) => error!("Failed to recover interrupted answers: {}", e),
     }
 }
 
+/// Stops a running generation. Returns false when it already ended.
 #[tauri::command]
-pub async fn name_conversation(
-    app_handle: tauri::AppHandle,
-    user_input: String,
+pub fn cancel_generation(generation_id: u64) -> Result<bool, String> {
+    match GENERATIONS.lock().unwrap().get(&generation_id) {
+        Some(cancel) => {
+            info!("Cancelling generation {}", generation_id);
+            cancel.send_replace(true);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Asks `model_id`, or the default model of `provider`, for a short name of a conversation
+/// starting with `user_input`.
+async fn generate_name(
+    app_handle: &AppHandle,
+    provider: &dyn LlmProvider,
+    model_id: Option<&str>,
+    user_input: &str,
+    chat_id: Option<i64>,
 ) -> Result<String, String> {
-    let setting =
-        app_handle.db(|db| get_setting(db, "api_key_claude").expect("Failed on api_key_claude"));
-
-    // Use the same client configuration for consistency
-    let client = Client::builder()
-        .timeout(Duration::from_secs(180))
-        .tcp_keepalive(Duration::from_secs(60))
-        .pool_idle_timeout(Duration::from_secs(90))
-        .pool_max_idle_per_host(2)
-        .build()
-        .map_err(|e| format!("Failed to create client: {}", e))?;
-
-    let system_prompt = format!(
-        "Name the conversation based on the user input. Use a total of 18 characters or less, without quotation marks. Use proper English, don't skip spaces between words. You only need to answer with the name. The following is the user input: \n\n{}\n\n.:",
-        user_input
-    );
-    let request_body = ClaudeRequest {
-        model: ANTRHOPIC_MODEL_CHEAP.to_string(),
-        max_tokens: 20,
-        messages: vec![Message {
-            role: "user".to_string(),
-            content: "Please generate a concise name for the conversation based on the user input."
-                .to_string(),
-        }],
-        system: system_prompt,
-        stream: false,
+    let model = provider
+        .resolve_model(model_id)
+        .map_err(|e| e.to_string())?;
+    check_llm_budget(app_handle, &model).map_err(|e| e.to_string())?;
+    let request = CompletionRequest {
+        model: model.id.clone(),
+        system: format!(
+            "Name the conversation based on the user input. Use a total of 18 characters or less, without quotation marks. Use proper English, don't skip spaces between words. You only need to answer with the name. The following is the user input: \n\n{}\n\n.:",
+            user_input
+        ),
+        messages: vec![LlmMessage::user(
+            "Please generate a concise name for the conversation based on the user input.",
+        )],
+        max_tokens: NAME_MAX_TOKENS.min(model.max_output_tokens),
     };
-
-    let response = client
-        .post(ANTHROPIC_URL)
-        .header("Content-Type", "application/json")
-        .header("x-api-key", &setting.setting_value)
-        .header("anthropic-version", "2023-06-01")
-        .header("Connection", "keep-alive")
-        .json(&request_body)
-        .send()
+    let completion = provider
+        .complete(&request)
         .await
-        .map_err(|e| format!("Request failed: {}", e))?;
-
-    if response.status().is_success() {
-        let response_body: ClaudeResponse = response
-            .json()
-            .await
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
-        let generated_name = response_body.content[0].text.trim().to_string();
-        Ok(generated_name)
-    } else {
-        let error_message = response
-            .text()
-            .await
-            .map_err(|e| format!("Failed to read error message: {}", e))?;
-        Err(format!("Error from Claude API: {}", error_message))
+        .map_err(|e| format!("Failed to name the conversation: {}", e))?;
+    record_llm_usage(
+        app_handle,
+        provider.id(),
+        &model,
+        UsagePurpose::ConversationName,
+        completion_usage(provider, &request, &completion),
+        UsageScope {
+            chat_id,
+            project_id: None,
+        },
+    );
+    let name = completion.text.trim();
+    if name.is_empty() {
+        return Ok("Unnamed Conversation".to_string());
     }
+    Ok(name.to_string())
+}
+
+/// Names the conversation with the provider of `model_id`, or the one picked in the settings
+/// when the model is unknown.
+#[tauri::command]
+pub async fn generate_conversation_name(
+    app_handle: AppHandle,
+    user_input: &str,
+    model_id: Option<String>,
+    chat_id: Option<i64>,
+) -> Result<String, String> {
+    let provider = get_llm_provider(&app_handle, model_id.as_deref())
+        .map_err(|e| format!("LLM provider unavailable: {}", e))?;
+    generate_name(
+        &app_handle,
+        provider.as_ref(),
+        model_id.as_deref(),
+        user_input,
+        chat_id,
+    )
+    .await
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{conversation_messages, parse_citations, trim_history, MESSAGE_OVERHEAD_TOKENS};
+    use crate::engine::llm_engine::LlmMessage;
+
+    fn message(role: &str, content: &str) -> LlmMessage {
+        LlmMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn turns_alternate_starting_with_the_user() {
+        let messages = conversation_messages(vec![
+            message("assistant", "How can I help?"),
+            message("user", "Hi"),
+            message("user", "What did I work on?"),
+            message("assistant", ""),
+            message("assistant", "The report"),
+            message("user", "Thanks"),
+        ]);
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| (message.role.as_str(), message.content.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("user", "Hi\n\nWhat did I work on?"),
+                ("assistant", "The report"),
+                ("user", "Thanks"),
+            ]
+        );
+    }
+
+    #[test]
+    fn old_messages_are_dropped_to_fit_the_budget() {
+        let messages = vec![
+            message("user", "aaaa"),
+            message("assistant", "bb"),
+            message("user", "c"),
+            message("assistant", "dd"),
+            message("user", "e"),
+        ];
+        // One token per character, the last four messages fit but would start with the assistant
+        let budget = 1 + 2 + 1 + 2 + 4 * MESSAGE_OVERHEAD_TOKENS;
+        let trimmed = trim_history(messages.clone(), budget, str::len);
+        assert_eq!(
+            trimmed
+                .iter()
+                .map(|m| m.content.as_str())
+                .collect::<Vec<_>>(),
+            vec!["c", "dd", "e"]
+        );
+
+        let trimmed = trim_history(messages, 0, str::len);
+        assert_eq!(trimmed.len(), 1);
+        assert_eq!(trimmed[0].content, "e");
+    }
+
+    #[test]
+    fn citations_are_read_in_order_of_appearance() {
+        let answer = "The launch moved to May [2]. Budget was approved [1, 3][2] and see \
+                      [the notes](https://example.com) [7] [[4]].";
+        assert_eq!(parse_citations(answer, 4), vec![2, 1, 3, 4]);
+        assert!(parse_citations("No sources here [a]", 4).is_empty());
+    }
+}
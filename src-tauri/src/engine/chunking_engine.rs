@@ -0,0 +1,205 @@
+use rusqlite::Connection;
+
+use crate::repository::settings_repository::get_setting;
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1500;
+pub const DEFAULT_CHUNK_OVERLAP: usize = 200;
+
+/// Sizes are measured in characters, not bytes.
+#[derive(Debug, Clone)]
+pub struct ChunkingOptions {
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+}
+
+impl Default for ChunkingOptions {
+    fn default() -> Self {
+        ChunkingOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            chunk_overlap: DEFAULT_CHUNK_OVERLAP,
+        }
+    }
+}
+
+impl ChunkingOptions {
+    pub fn load(db: &Connection) -> Self {
+        let parse = |setting_key: &str| {
+            get_setting(db, setting_key)
+                .ok()
+                .and_then(|setting| setting.setting_value.parse::<usize>().ok())
+        };
+        ChunkingOptions {
+            chunk_size: parse("chunk_size")
+                .filter(|value| *value > 0)
+                .unwrap_or(DEFAULT_CHUNK_SIZE),
+            // An overlap of 0 turns it off
+            chunk_overlap: parse("chunk_overlap").unwrap_or(DEFAULT_CHUNK_OVERLAP),
+        }
+    }
+}
+
+/// A slice of a document. Offsets are byte offsets into the original text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextChunk {
+    pub text: String,
+    pub start_offset: usize,
+    pub end_offset: usize,
+}
+
+/// Splits `text` into overlapping chunks of at most `chunk_size` characters.
+///
+/// Chunks preferably end right before a markdown heading, then at a paragraph break, a line
+/// break, the end of a sentence and finally any whitespace. Only when none of these can be
+/// found in the second half of the window is a word cut in two.
+pub fn chunk_text(text: &str, options: &ChunkingOptions) -> Vec<TextChunk> {
+    let chars: Vec<char> = text.chars().collect();
+    let byte_offsets: Vec<usize> = text
+        .char_indices()
+        .map(|(byte_index, _)| byte_index)
+        .chain(std::iter::once(text.len()))
+        .collect();
+
+    let chunk_size = options.chunk_size.max(1);
+    let overlap = options.chunk_overlap.min(chunk_size / 2);
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let window_end = (start + chunk_size).min(chars.len());
+        let end = if window_end == chars.len() {
+            window_end
+        } else {
+            find_break(&chars, start + chunk_size / 2, window_end).unwrap_or(window_end)
+        };
+
+        let raw = &text[byte_offsets[start]..byte_offsets[end]];
+        let trimmed_start = raw.len() - raw.trim_start().len();
+        let trimmed = raw.trim();
+        if !trimmed.is_empty() {
+            let start_offset = byte_offsets[start] + trimmed_start;
+            chunks.push(TextChunk {
+                text: trimmed.to_string(),
+                start_offset,
+                end_offset: start_offset + trimmed.len(),
+            });
+        }
+
+        if end == chars.len() {
+            break;
+        }
+
+        let mut next_start = end.saturating_sub(overlap).max(start + 1);
+        // Let the overlap begin on a word instead of in the middle of one.
+        while overlap > 0 && next_start < end && !chars[next_start - 1].is_whitespace() {
+            next_start += 1;
+        }
+        start = next_start;
+    }
+
+    chunks
+}
+
+/// Returns the best char index in `(min, max]` to end a chunk at.
+fn find_break(chars: &[char], min: usize, max: usize) -> Option<usize> {
+    let mut best: Option<(u8, usize)> = None;
+    for index in (min.max(1) + 1..=max).rev() {
+        let rank = break_rank(chars, index);
+        if rank == 0 {
+            continue;
+        }
+        if best.map_or(true, |(best_rank, _)| rank > best_rank) {
+            best = Some((rank, index));
+        }
+        if rank == HEADING_BREAK {
+            break;
+        }
+    }
+    best.map(|(_, index)| index)
+}
+
+const HEADING_BREAK: u8 = 5;
+
+fn break_rank(chars: &[char], index: usize) -> u8 {
+    let previous = chars[index - 1];
+    let before_previous = if index >= 2 { Some(chars[index - 2]) } else { None };
+    let current = chars.get(index).copied();
+
+    if previous == '\n' && current == Some('#') {
+        HEADING_BREAK
+    } else if previous == '\n' && before_previous == Some('\n') {
+        4
+    } else if previous == '\n' {
+        3
+    } else if previous.is_whitespace() && matches!(before_previous, Some('.' | '!' | '?')) {
+        2
+    } else if previous.is_whitespace() {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{chunk_text, ChunkingOptions, DEFAULT_CHUNK_SIZE};
+
+    fn options(chunk_size: usize, chunk_overlap: usize) -> ChunkingOptions {
+        ChunkingOptions {
+            chunk_size,
+            chunk_overlap,
+        }
+    }
+
+    #[test]
+    fn short_text_is_a_single_chunk() {
+        let chunks = chunk_text("  Hello world  ", &options(100, 10));
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].text, "Hello world");
+        assert_eq!((chunks[0].start_offset, chunks[0].end_offset), (2, 13));
+    }
+
+    #[test]
+    fn prefers_paragraph_and_heading_breaks() {
+        let text = "# Intro\nSome text here.\n\nSecond paragraph goes on.\n# Next\nMore words follow";
+        let chunks = chunk_text(text, &options(60, 0));
+        assert_eq!(chunks[0].text, "# Intro\nSome text here.\n\nSecond paragraph goes on.");
+        assert!(chunks[1].text.starts_with("# Next"));
+    }
+
+    #[test]
+    fn offsets_are_utf8_safe_and_point_at_the_chunk() {
+        let text = "Zażółć gęślą jaźń. ".repeat(40) + "终于到了结尾，这里是中文。";
+        let chunks = chunk_text(&text, &options(64, 16));
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert_eq!(&text[chunk.start_offset..chunk.end_offset], chunk.text);
+            assert!(chunk.text.chars().count() <= 64);
+        }
+        assert!(chunks.last().unwrap().text.ends_with("这里是中文。"));
+    }
+
+    #[test]
+    fn consecutive_chunks_overlap() {
+        let text = (0..200).map(|i| format!("word{}", i)).collect::<Vec<_>>().join(" ");
+        let chunks = chunk_text(&text, &options(100, 30));
+        for pair in chunks.windows(2) {
+            assert!(pair[1].start_offset < pair[0].end_offset);
+            assert!(pair[1].start_offset > pair[0].start_offset);
+        }
+    }
+
+    #[test]
+    fn overlap_can_be_turned_off_but_chunk_size_cannot() {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE settings (setting_key TEXT PRIMARY KEY, setting_value TEXT NOT NULL);
+             INSERT INTO settings VALUES ('chunk_size', '0'), ('chunk_overlap', '0');",
+        )
+        .unwrap();
+        let loaded = ChunkingOptions::load(&db);
+        assert_eq!(loaded.chunk_size, DEFAULT_CHUNK_SIZE);
+        assert_eq!(loaded.chunk_overlap, 0);
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+/// Kept free of the context window, in parts of it, as token counts of models without a
+/// published tokenizer are approximate.
+const MARGIN_DIVISOR: usize = 20;
+/// Share in percent of the room left for history and documents the documents get at least when
+/// both don't fit.
+const DOCUMENT_SHARE_PERCENT: usize = 60;
+/// A document is only cut off when at least this many tokens of it fit, otherwise it's dropped.
+const MIN_TRUNCATED_TOKENS: usize = 200;
+/// Tokens are hardly ever longer, so prefixes are only searched this far for each token.
+const MAX_CHARS_PER_TOKEN: usize = 32;
+const TRUNCATION_MARK: &str = "[...]\n\n";
+
+/// How the context window of a model is split for one answer, in tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ContextBudget {
+    pub context_window: usize,
+    pub output: usize,
+    pub system: usize,
+    /// The message being answered.
+    pub prompt: usize,
+    pub history: usize,
+    pub documents: usize,
+}
+
+impl ContextBudget {
+    /// Reserves `output`, `system` and `prompt` and splits the rest between the earlier messages
+    /// and the retrieved documents. When both don't fit, the documents get at least their share
+    /// and whatever the history doesn't need. Fails when the prompt alone doesn't fit.
+    pub fn allocate(
+        context_window: usize,
+        output: usize,
+        system: usize,
+        prompt: usize,
+        history_needed: usize,
+        documents_needed: usize,
+    ) -> Result<Self> {
+        let reserved = output + system + prompt + context_window / MARGIN_DIVISOR;
+        let Some(available) = context_window.checked_sub(reserved) else {
+            bail!(
+                "The message takes up {} tokens, too many for the context window of {} tokens",
+                prompt,
+                context_window
+            );
+        };
+        let document_share = available * DOCUMENT_SHARE_PERCENT / 100;
+        let documents =
+            documents_needed.min(document_share.max(available.saturating_sub(history_needed)));
+        Ok(ContextBudget {
+            context_window,
+            output,
+            system,
+            prompt,
+            history: history_needed.min(available - documents),
+            documents,
+        })
+    }
+}
+
+/// The retrieved documents that fit into their budget.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FittedDocuments {
+    pub texts: Vec<String>,
+    /// The last kept document was cut off.
+    pub last_truncated: bool,
+}
+
+/// What was left out to fit the context into the window of the model.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ContextReport {
+    pub budget: ContextBudget,
+    /// Oldest messages of the conversation left out.
+    pub dropped_messages: usize,
+    /// Titles of the retrieved documents left out, best ranked first.
+    pub dropped_documents: Vec<String>,
+    pub truncated_document: Option<String>,
+}
+
+impl ContextReport {
+    pub fn is_complete(&self) -> bool {
+        self.dropped_messages == 0
+            && self.dropped_documents.is_empty()
+            && self.truncated_document.is_none()
+    }
+}
+
+/// Longest prefix of `text` that takes up at most `max_tokens`.
+fn truncate_to_tokens<'a>(
+    text: &'a str,
+    max_tokens: usize,
+    count_tokens: &impl Fn(&str) -> usize,
+) -> &'a str {
+    let boundaries: Vec<usize> = text
+        .char_indices()
+        .map(|(index, _)| index)
+        .chain(std::iter::once(text.len()))
+        .take(max_tokens.saturating_mul(MAX_CHARS_PER_TOKEN) + 1)
+        .collect();
+    // Binary search for the longest prefix that fits, the empty one always does
+    let (mut low, mut high) = (0, boundaries.len() - 1);
+    while low < high {
+        let middle = (low + high).div_ceil(2);
+        if count_tokens(&text[..boundaries[middle]]) <= max_tokens {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    &text[..boundaries[low]]
+}
+
+/// Keeps the `documents`, ranked best first, that fit into `budget` tokens. Documents are kept
+/// whole until one doesn't fit, that one is cut off if enough of it fits and the lower ranked
+/// ones are dropped.
+pub fn fit_documents(
+    documents: &[String],
+    budget: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> FittedDocuments {
+    let mut fitted = FittedDocuments {
+        texts: Vec::new(),
+        last_truncated: false,
+    };
+    let mut remaining = budget;
+    for document in documents {
+        let tokens = count_tokens(document);
+        if tokens <= remaining {
+            remaining -= tokens;
+            fitted.texts.push(document.clone());
+            continue;
+        }
+        let room = remaining.saturating_sub(count_tokens(TRUNCATION_MARK));
+        if room >= MIN_TRUNCATED_TOKENS {
+            let kept = truncate_to_tokens(document, room, &count_tokens);
+            fitted.texts.push(format!("{}{}", kept, TRUNCATION_MARK));
+            fitted.last_truncated = true;
+        }
+        break;
+    }
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fit_documents, ContextBudget, MIN_TRUNCATED_TOKENS, TRUNCATION_MARK};
+
+    #[test]
+    fn documents_and_history_share_what_the_prompt_leaves() {
+        // 1000 tokens are left after the output, system prompt, message and margin
+        let budget = ContextBudget::allocate(2000, 500, 300, 100, 800, 800).unwrap();
+        assert_eq!((budget.documents, budget.history), (600, 400));
+
+        let budget = ContextBudget::allocate(2000, 500, 300, 100, 100, 2000).unwrap();
+        assert_eq!((budget.documents, budget.history), (900, 100));
+
+        let budget = ContextBudget::allocate(2000, 500, 300, 100, 2000, 50).unwrap();
+        assert_eq!((budget.documents, budget.history), (50, 950));
+
+        assert!(ContextBudget::allocate(2000, 500, 300, 1200, 0, 0).is_err());
+    }
+
+    #[test]
+    fn lowest_ranked_documents_are_cut_first() {
+        let count_tokens = |text: &str| text.chars().count();
+        let documents = vec!["a".repeat(300), "b".repeat(400), "c".repeat(100)];
+
+        let fitted = fit_documents(&documents, 800, count_tokens);
+        assert_eq!(fitted.texts.len(), 3);
+        assert!(!fitted.last_truncated);
+
+        let fitted = fit_documents(&documents, 600, count_tokens);
+        assert_eq!(fitted.texts.len(), 2);
+        assert!(fitted.last_truncated);
+        assert_eq!(fitted.texts[0], documents[0]);
+        assert_eq!(fitted.texts[1].len(), 300);
+        assert!(fitted.texts[1].ends_with(TRUNCATION_MARK));
+
+        // Too little room for a useful piece of the second document
+        let fitted = fit_documents(&documents, 300 + MIN_TRUNCATED_TOKENS, count_tokens);
+        assert_eq!(fitted.texts, vec![documents[0].clone()]);
+        assert!(!fitted.last_truncated);
+    }
+}
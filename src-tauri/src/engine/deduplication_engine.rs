@@ -0,0 +1,306 @@
+use log::{error, info};
+use rusqlite::Connection;
+use serde::Serialize;
+use tauri::AppHandle;
+
+use crate::configuration::state::ServiceAccess;
+use crate::engine::retrieval_engine::get_source_document;
+use crate::entity::vector_chunk::SourceType;
+use crate::repository::content_fingerprint_repository::{
+    delete_content_fingerprint, get_canonical_fingerprint_candidates, get_duplicate_links,
+    get_first_duplicate, move_duplicates, save_content_fingerprint, set_duplicate_of,
+};
+use crate::repository::search_filter_repository::get_source_details;
+
+/// Fingerprints at most this many bits apart are near-duplicates. Three of 64 bits tolerates
+/// counters, timestamps and a few changed words in an otherwise identical text. It has to stay
+/// below the four bands candidates are looked up by.
+pub const MAX_SIMHASH_DISTANCE: u32 = 3;
+/// Shorter texts are not fingerprinted, a few words don't make a stable fingerprint.
+pub const MIN_FINGERPRINT_WORDS: usize = 20;
+const SHINGLE_SIZE: usize = 3;
+
+/// 64-bit FNV-1a, stable across builds unlike `DefaultHasher`, so stored fingerprints stay valid.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    // FNV mixes the high bits poorly for short inputs, SimHash needs every bit to be random.
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Lowercase words of the text. Numbers are left out, they are mostly unread counters, clocks
+/// and dates that change between captures of the same window.
+fn fingerprint_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && !word.chars().all(|c| c.is_numeric()))
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// SimHash of the word shingles of `text`, `None` when the text is too short to fingerprint.
+pub fn simhash(text: &str) -> Option<u64> {
+    let words = fingerprint_words(text);
+    if words.len() < MIN_FINGERPRINT_WORDS {
+        return None;
+    }
+
+    let mut weights = [0i64; 64];
+    for shingle in words.windows(SHINGLE_SIZE) {
+        let hash = fnv1a(shingle.join(" ").as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if (hash >> bit) & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    Some(
+        weights
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0)
+            .fold(0u64, |fingerprint, (bit, _)| fingerprint | (1 << bit)),
+    )
+}
+
+pub fn is_near_duplicate(a: u64, b: u64) -> bool {
+    (a ^ b).count_ones() <= MAX_SIMHASH_DISTANCE
+}
+
+/// Fingerprints the text of a source and links it to the closest earlier source of the same
+/// type it nearly duplicates. Returns the id of that source, `None` if the text is unique.
+///
+/// Sources that were linked to this one move along with it, so clusters stay one level deep.
+pub fn register_fingerprint(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+    text: &str,
+) -> Result<Option<i64>, rusqlite::Error> {
+    let Some(fingerprint) = simhash(text) else {
+        unregister_fingerprint(db, source_type, source_id)?;
+        return Ok(None);
+    };
+
+    let duplicate_of = get_canonical_fingerprint_candidates(db, source_type, fingerprint)?
+        .into_iter()
+        .filter(|(id, other)| *id != source_id && is_near_duplicate(fingerprint, *other))
+        .min_by_key(|(id, other)| ((fingerprint ^ other).count_ones(), *id))
+        .map(|(id, _)| id);
+
+    if let Some(canonical_id) = duplicate_of {
+        move_duplicates(db, source_type, source_id, Some(canonical_id))?;
+    }
+    save_content_fingerprint(db, source_type, source_id, fingerprint, duplicate_of)?;
+    Ok(duplicate_of)
+}
+
+/// Drops the fingerprint of a deleted source. Its oldest duplicate takes its place and the
+/// others are linked to that one. Returns the id of the promoted duplicate, which may never have
+/// been embedded.
+pub fn unregister_fingerprint(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<Option<i64>, rusqlite::Error> {
+    let promoted_id = get_first_duplicate(db, source_type, source_id)?;
+    if let Some(promoted_id) = promoted_id {
+        set_duplicate_of(db, source_type, promoted_id, None)?;
+        move_duplicates(db, source_type, source_id, Some(promoted_id))?;
+    }
+    delete_content_fingerprint(db, source_type, source_id)?;
+    Ok(promoted_id)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateMember {
+    pub source_id: i64,
+    pub title: String,
+    pub date: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateCluster {
+    pub source_type: SourceType,
+    /// The first captured source, the others were linked to it.
+    pub canonical: DuplicateMember,
+    pub duplicates: Vec<DuplicateMember>,
+}
+
+fn get_duplicate_member(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<Option<DuplicateMember>, rusqlite::Error> {
+    let Some((title, _)) = get_source_document(db, source_type, source_id)? else {
+        return Ok(None);
+    };
+    let date = get_source_details(db, source_type, source_id)?.and_then(|details| details.date);
+    Ok(Some(DuplicateMember {
+        source_id,
+        title,
+        date,
+    }))
+}
+
+/// Lists the activities and project documents that were detected as near-duplicates, grouped
+/// by the source they duplicate.
+#[tauri::command]
+pub fn get_duplicate_clusters(app_handle: AppHandle) -> Result<Vec<DuplicateCluster>, String> {
+    app_handle
+        .db(|db| -> Result<Vec<DuplicateCluster>, rusqlite::Error> {
+            let mut clusters: Vec<DuplicateCluster> = Vec::new();
+            for (source_type, canonical_id, duplicate_id) in get_duplicate_links(db)? {
+                let Some(duplicate) = get_duplicate_member(db, source_type, duplicate_id)? else {
+                    continue;
+                };
+                match clusters.last_mut() {
+                    Some(cluster)
+                        if cluster.source_type == source_type
+                            && cluster.canonical.source_id == canonical_id =>
+                    {
+                        cluster.duplicates.push(duplicate);
+                    }
+                    _ => {
+                        let Some(canonical) = get_duplicate_member(db, source_type, canonical_id)?
+                        else {
+                            error!(
+                                "Near-duplicate {} points at missing {}",
+                                duplicate_id, canonical_id
+                            );
+                            continue;
+                        };
+                        clusters.push(DuplicateCluster {
+                            source_type,
+                            canonical,
+                            duplicates: vec![duplicate],
+                        });
+                    }
+                }
+            }
+            info!("Found {} near-duplicate clusters", clusters.len());
+            Ok(clusters)
+        })
+        .map_err(|e| format!("Failed to list duplicate clusters: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{is_near_duplicate, register_fingerprint, simhash, unregister_fingerprint};
+    use crate::entity::vector_chunk::SourceType;
+    use crate::repository::content_fingerprint_repository::{
+        get_canonical_fingerprint_candidates, get_duplicate_of,
+    };
+
+    const PLANNING_NOTES: &str = "Quarterly planning notes: hire two engineers for the mobile \
+        team, migrate the reporting pipeline to the new warehouse, and finish the accessibility \
+        audit of the checkout flow before the holiday season starts in November";
+
+    const SLACK_CAPTURE: &str =
+        "general channel Alice: the deploy of the billing service is done, \
+        please check the dashboards before lunch. Bob: thanks, I will look at the error rates and \
+        the latency graphs and report back in the thread once everything looks fine";
+
+    #[test]
+    fn captures_differing_in_counters_are_near_duplicates() {
+        let first = simhash(&format!("(2) Slack 10:41 {}", SLACK_CAPTURE)).unwrap();
+        let second = simhash(&format!("(3) Slack 10:42 {}", SLACK_CAPTURE)).unwrap();
+        assert!(is_near_duplicate(first, second));
+    }
+
+    #[test]
+    fn different_texts_are_not_near_duplicates() {
+        let first = simhash(SLACK_CAPTURE).unwrap();
+        let second = simhash(PLANNING_NOTES).unwrap();
+        assert!(!is_near_duplicate(first, second));
+    }
+
+    #[test]
+    fn short_texts_have_no_fingerprint() {
+        assert_eq!(simhash("(2) Slack"), None);
+    }
+
+    fn fingerprint_db() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(include_str!(
+            "../../migrations/2025-04-23-100000_create_content_fingerprints/up.sql"
+        ))
+        .unwrap();
+        db.execute_batch(include_str!(
+            "../../migrations/2025-05-21-090000_index_content_fingerprint_bands/up.sql"
+        ))
+        .unwrap();
+        db
+    }
+
+    /// Another capture of the same window, only its counter differs.
+    fn capture(text: &str, counter: u32) -> String {
+        format!("({}) {}", counter, text)
+    }
+
+    fn register(db: &Connection, source_id: i64, text: &str) -> Option<i64> {
+        register_fingerprint(db, SourceType::Activity, source_id, text).unwrap()
+    }
+
+    fn unregister(db: &Connection, source_id: i64) -> Option<i64> {
+        unregister_fingerprint(db, SourceType::Activity, source_id).unwrap()
+    }
+
+    fn duplicate_of(db: &Connection, source_id: i64) -> Option<i64> {
+        get_duplicate_of(db, SourceType::Activity, source_id).unwrap()
+    }
+
+    #[test]
+    fn duplicates_move_with_their_canonical_source() {
+        let db = fingerprint_db();
+        assert_eq!(register(&db, 1, SLACK_CAPTURE), None);
+        assert_eq!(register(&db, 2, PLANNING_NOTES), None);
+        assert_eq!(register(&db, 3, &capture(PLANNING_NOTES, 2)), Some(2));
+
+        // The text of 2 changed to a capture of 1, so 3 now duplicates 1 as well
+        assert_eq!(register(&db, 2, &capture(SLACK_CAPTURE, 2)), Some(1));
+        assert_eq!(duplicate_of(&db, 3), Some(1));
+    }
+
+    #[test]
+    fn the_oldest_duplicate_replaces_a_deleted_source() {
+        let db = fingerprint_db();
+        register(&db, 1, SLACK_CAPTURE);
+        register(&db, 2, &capture(SLACK_CAPTURE, 2));
+        register(&db, 3, &capture(SLACK_CAPTURE, 3));
+
+        assert_eq!(unregister(&db, 1), Some(2));
+        assert_eq!(duplicate_of(&db, 2), None);
+        assert_eq!(duplicate_of(&db, 3), Some(2));
+        assert_eq!(register(&db, 4, &capture(SLACK_CAPTURE, 4)), Some(2));
+
+        assert_eq!(unregister(&db, 3), None);
+    }
+
+    #[test]
+    fn candidates_share_a_band_of_the_fingerprint() {
+        let db = fingerprint_db();
+        let fingerprint = simhash(SLACK_CAPTURE).unwrap();
+        register(&db, 1, SLACK_CAPTURE);
+        register(&db, 2, PLANNING_NOTES);
+
+        // Three flipped bits, one in each of three bands, leave the fourth band intact
+        let near = fingerprint ^ (1 << 3) ^ (1 << 20) ^ (1 << 40);
+        let candidates = get_canonical_fingerprint_candidates(&db, SourceType::Activity, near);
+        assert_eq!(candidates.unwrap(), vec![(1, fingerprint)]);
+
+        let far = fingerprint ^ (1 << 3) ^ (1 << 20) ^ (1 << 40) ^ (1 << 60);
+        let candidates = get_canonical_fingerprint_candidates(&db, SourceType::Activity, far);
+        assert!(candidates.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,775 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use async_openai::{config::OpenAIConfig, types::CreateEmbeddingRequestArgs, Client};
+use async_trait::async_trait;
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use rusqlite::Connection;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tauri::AppHandle;
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+use crate::configuration::state::ServiceAccess;
+use crate::engine::usage_engine::MeteredEmbeddingProvider;
+use crate::entity::usage_event::UsageScope;
+use crate::repository::embedding_cache_repository::{
+    get_cached_embeddings, prune_embedding_cache, save_cached_embeddings, touch_cached_embeddings,
+};
+use crate::repository::settings_repository::get_setting;
+
+pub const PROVIDER_OPENAI: &str = "openai";
+pub const PROVIDER_OPENAI_COMPATIBLE: &str = "openai_compatible";
+pub const PROVIDER_LOCAL: &str = "local";
+
+const OPENAI_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+const OPENAI_COMPATIBLE_DEFAULT_MODEL: &str = "nomic-embed-text";
+const LOCAL_MAX_SEQUENCE_LENGTH: usize = 512;
+const LOCAL_BATCH_SIZE: usize = 16;
+
+/// Texts longer than this many characters are cut off before embedding.
+pub const MAX_CHARS: usize = 7900;
+/// Upper bounds of a single embeddings request.
+const MAX_BATCH_INPUTS: usize = 64;
+const MAX_BATCH_CHARS: usize = 200_000;
+const MAX_CONCURRENT_REQUESTS: usize = 4;
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+const MAX_CACHE_ENTRIES: usize = 200_000;
+
+/// Returned by providers when the server asked to slow down.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.retry_after {
+            Some(retry_after) => write!(f, "Rate limited, retry after {:?}", retry_after),
+            None => write!(f, "Rate limited"),
+        }
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Turns text into vectors for the similarity search index.
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    /// Identifier of the model producing the vectors, e.g. `text-embedding-3-small`.
+    fn model_name(&self) -> &str;
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>>;
+
+    /// Embeds several texts at once, the vectors are returned in the order of `texts`.
+    ///
+    /// Providers that can't batch fall back to one request per text.
+    async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let mut vectors = Vec::with_capacity(texts.len());
+        for text in &texts {
+            vectors.push(self.embed(text).await?);
+        }
+        Ok(vectors)
+    }
+}
+
+/// Cuts `text` off after `MAX_CHARS` characters.
+pub fn truncate_for_embedding(text: &str) -> &str {
+    match text.char_indices().nth(MAX_CHARS) {
+        Some((byte_index, _)) => &text[..byte_index],
+        None => text,
+    }
+}
+
+pub struct OpenAiEmbeddingProvider {
+    client: Client<OpenAIConfig>,
+    model: String,
+}
+
+impl OpenAiEmbeddingProvider {
+    pub fn new(api_key: &str, model: &str) -> Self {
+        let config = OpenAIConfig::new().with_api_key(api_key);
+        OpenAiEmbeddingProvider {
+            client: Client::with_config(config),
+            model: model.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAiEmbeddingProvider {
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.embed_batch(vec![text.to_string()])
+            .await?
+            .pop()
+            .ok_or(anyhow!("OpenAI returned no embedding"))
+    }
+
+    // async-openai already backs off and retries when OpenAI answers with 429.
+    async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(&self.model)
+            .input(texts)
+            .build()?;
+        let response = self.client.embeddings().create(request).await?;
+        let mut data = response.data;
+        data.sort_by_key(|embedding| embedding.index);
+        Ok(data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+}
+
+/// Talks to any server exposing an OpenAI style `/v1/embeddings` endpoint (Ollama, llama.cpp, ...).
+pub struct OpenAiCompatibleEmbeddingProvider {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct CompatibleEmbeddingResponse {
+    data: Vec<CompatibleEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct CompatibleEmbedding {
+    #[serde(default)]
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl OpenAiCompatibleEmbeddingProvider {
+    pub fn new(base_url: &str, api_key: Option<String>, model: &str) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(120))
+            .build()?;
+        Ok(OpenAiCompatibleEmbeddingProvider {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model: model.to_string(),
+        })
+    }
+
+    fn embeddings_url(&self) -> String {
+        if self.base_url.ends_with("/v1") {
+            format!("{}/embeddings", self.base_url)
+        } else {
+            format!("{}/v1/embeddings", self.base_url)
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAiCompatibleEmbeddingProvider {
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.embed_batch(vec![text.to_string()])
+            .await?
+            .pop()
+            .ok_or(anyhow!("Embedding server returned no embedding"))
+    }
+
+    async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let mut request = self
+            .client
+            .post(self.embeddings_url())
+            .json(&json!({ "model": self.model, "input": texts }));
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request.send().await?;
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+                .map(Duration::from_secs);
+            return Err(RateLimited { retry_after }.into());
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_message = response.text().await.unwrap_or_default();
+            bail!("Embedding server returned {}: {}", status, error_message);
+        }
+
+        let mut body: CompatibleEmbeddingResponse = response.json().await?;
+        body.data.sort_by_key(|embedding| embedding.index);
+        Ok(body
+            .data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+}
+
+/// Sentence-transformer (BERT family) model evaluated on the CPU.
+///
+/// The model directory must contain `config.json`, `tokenizer.json` and `model.safetensors`,
+/// as published for e.g. `sentence-transformers/all-MiniLM-L6-v2`.
+pub struct LocalEmbeddingModel {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl LocalEmbeddingModel {
+    pub fn load(model_dir: &Path) -> Result<Self> {
+        info!("Loading local embedding model from {}", model_dir.display());
+        let device = Device::Cpu;
+        let config: Config =
+            serde_json::from_str(&std::fs::read_to_string(model_dir.join("config.json"))?)?;
+
+        let mut tokenizer =
+            Tokenizer::from_file(model_dir.join("tokenizer.json")).map_err(|e| anyhow!("{}", e))?;
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: LOCAL_MAX_SEQUENCE_LENGTH.min(config.max_position_embeddings),
+                ..Default::default()
+            }))
+            .map_err(|e| anyhow!("{}", e))?;
+
+        let weights = model_dir.join("model.safetensors");
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights], DTYPE, &device)? };
+        let model = BertModel::load(vb, &config)?;
+
+        Ok(LocalEmbeddingModel {
+            model,
+            tokenizer,
+            device,
+        })
+    }
+
+    /// Mean pools the last hidden state over the attention mask and L2 normalizes the result.
+    pub fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts, true)
+            .map_err(|e| anyhow!("{}", e))?;
+
+        let token_ids = encodings
+            .iter()
+            .map(|encoding| Tensor::new(encoding.get_ids(), &self.device))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let attention_masks = encodings
+            .iter()
+            .map(|encoding| Tensor::new(encoding.get_attention_mask(), &self.device))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+
+        let token_ids = Tensor::stack(&token_ids, 0)?;
+        let attention_mask = Tensor::stack(&attention_masks, 0)?;
+        let token_type_ids = token_ids.zeros_like()?;
+
+        let hidden_states =
+            self.model
+                .forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
+
+        let mask = attention_mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+        let summed = hidden_states.broadcast_mul(&mask)?.sum(1)?;
+        let pooled = summed.broadcast_div(&mask.sum(1)?)?;
+        let norm = pooled.sqr()?.sum_keepdim(1)?.sqrt()?;
+        let normalized = pooled.broadcast_div(&norm)?;
+
+        Ok(normalized.to_vec2::<f32>()?)
+    }
+}
+
+lazy_static! {
+    // Loading the weights takes a while, so the model is kept around for as long as the path
+    // doesn't change.
+    static ref LOCAL_MODEL: std::sync::Mutex<Option<(PathBuf, Arc<LocalEmbeddingModel>)>> =
+        std::sync::Mutex::new(None);
+}
+
+fn get_local_model(model_dir: &Path) -> Result<Arc<LocalEmbeddingModel>> {
+    let mut cached = LOCAL_MODEL.lock().unwrap();
+    if let Some((path, model)) = cached.as_ref() {
+        if path == model_dir {
+            return Ok(model.clone());
+        }
+    }
+    let model = Arc::new(LocalEmbeddingModel::load(model_dir)?);
+    *cached = Some((model_dir.to_path_buf(), model.clone()));
+    Ok(model)
+}
+
+/// Name of the model in `model_dir`, it keys the cached and stored vectors. Directories with
+/// the same name can hold different weights, so a hash of the directory's canonical path, its
+/// config and the size and modification time of the weights is appended.
+fn local_model_name(model_dir: &Path) -> String {
+    let name = model_dir
+        .file_name()
+        .map(|name| format!("local/{}", name.to_string_lossy()))
+        .unwrap_or_else(|| "local".to_string());
+    match local_model_fingerprint(model_dir) {
+        Ok(fingerprint) => format!("{}@{}", name, fingerprint),
+        Err(e) => {
+            warn!("Failed to fingerprint {}: {}", model_dir.display(), e);
+            name
+        }
+    }
+}
+
+fn local_model_fingerprint(model_dir: &Path) -> std::io::Result<String> {
+    let weights = std::fs::metadata(model_dir.join("model.safetensors"))?;
+    let modified = weights
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(model_dir.canonicalize()?.to_string_lossy().as_bytes());
+    hasher.update(std::fs::read(model_dir.join("config.json"))?);
+    hasher.update(weights.len().to_le_bytes());
+    hasher.update(modified.as_nanos().to_le_bytes());
+    let hash = format!("{:x}", hasher.finalize());
+    Ok(hash[..12].to_string())
+}
+
+pub struct LocalEmbeddingProvider {
+    model: Arc<LocalEmbeddingModel>,
+    model_name: String,
+}
+
+impl LocalEmbeddingProvider {
+    pub fn new(model_dir: &Path) -> Result<Self> {
+        Ok(LocalEmbeddingProvider {
+            model: get_local_model(model_dir)?,
+            model_name: local_model_name(model_dir),
+        })
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for LocalEmbeddingProvider {
+    fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.embed_batch(vec![text.to_string()])
+            .await?
+            .pop()
+            .ok_or(anyhow!("Local model returned no embedding"))
+    }
+
+    async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let model = self.model.clone();
+        tokio::task::spawn_blocking(move || {
+            // Padding makes every sequence of a batch as long as the longest one, smaller
+            // batches keep the memory use in check.
+            let mut vectors = Vec::with_capacity(texts.len());
+            for batch in texts.chunks(LOCAL_BATCH_SIZE) {
+                vectors.extend(model.embed(batch.to_vec())?);
+            }
+            Ok(vectors)
+        })
+        .await?
+    }
+}
+
+pub fn non_empty_setting(db: &Connection, setting_key: &str) -> Option<String> {
+    get_setting(db, setting_key)
+        .ok()
+        .map(|setting| setting.setting_value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+#[derive(Debug, Clone)]
+pub struct EmbeddingSettings {
+    pub provider: String,
+    pub model: Option<String>,
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    pub api_key_open_ai: Option<String>,
+    pub model_path: Option<String>,
+}
+
+impl EmbeddingSettings {
+    pub fn load(db: &Connection) -> Self {
+        EmbeddingSettings {
+            provider: non_empty_setting(db, "embedding_provider")
+                .unwrap_or(PROVIDER_OPENAI.to_string()),
+            model: non_empty_setting(db, "embedding_model"),
+            base_url: non_empty_setting(db, "embedding_base_url"),
+            api_key: non_empty_setting(db, "embedding_api_key"),
+            api_key_open_ai: non_empty_setting(db, "api_key_open_ai"),
+            model_path: non_empty_setting(db, "embedding_model_path"),
+        }
+    }
+
+    /// Name of the model the configured provider would use, without loading or contacting it.
+    pub fn model_name(&self) -> String {
+        match self.provider.as_str() {
+            PROVIDER_OPENAI => self
+                .model
+                .clone()
+                .unwrap_or(OPENAI_EMBEDDING_MODEL.to_string()),
+            PROVIDER_OPENAI_COMPATIBLE => self
+                .model
+                .clone()
+                .unwrap_or(OPENAI_COMPATIBLE_DEFAULT_MODEL.to_string()),
+            PROVIDER_LOCAL => self
+                .model_path
+                .as_deref()
+                .map(|model_path| local_model_name(Path::new(model_path)))
+                .unwrap_or("local".to_string()),
+            other => other.to_string(),
+        }
+    }
+}
+
+/// Splits the inputs into groups that fit into one embeddings request each.
+fn split_into_batches<T>(inputs: Vec<(T, &str)>) -> Vec<Vec<(T, &str)>> {
+    let mut batches: Vec<Vec<(T, &str)>> = Vec::new();
+    let mut batch_chars = 0;
+    for (key, text) in inputs {
+        let text_chars = text.chars().count();
+        let is_full = batches.last().map_or(true, |batch| {
+            batch.len() >= MAX_BATCH_INPUTS || batch_chars + text_chars > MAX_BATCH_CHARS
+        });
+        if is_full {
+            batches.push(Vec::new());
+            batch_chars = 0;
+        }
+        batch_chars += text_chars;
+        batches.last_mut().unwrap().push((key, text));
+    }
+    batches
+}
+
+async fn embed_batch_with_retry(
+    provider: &dyn EmbeddingProvider,
+    texts: Vec<String>,
+) -> Result<Vec<Vec<f32>>> {
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        match provider.embed_batch(texts.clone()).await {
+            Err(e) if attempt < MAX_RATE_LIMIT_RETRIES => {
+                let Some(rate_limited) = e.downcast_ref::<RateLimited>() else {
+                    return Err(e);
+                };
+                let wait = rate_limited.retry_after.unwrap_or(delay);
+                attempt += 1;
+                warn!(
+                    "Embedding request rate limited, retrying in {:?} (attempt {}/{})",
+                    wait, attempt, MAX_RATE_LIMIT_RETRIES
+                );
+                tokio::time::sleep(wait).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Embeds `texts` through the cache, returning the vectors in the order of `texts`.
+///
+/// Texts whose vector is cached, or that occur more than once, are only embedded once. The
+/// rest is sent in batches, a few requests at a time, and retried when rate limited. Vectors
+/// that were computed are cached even when another batch fails.
+pub async fn embed_texts(
+    app_handle: &AppHandle,
+    provider: &dyn EmbeddingProvider,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    let model = provider.model_name().to_string();
+    let inputs: Vec<&str> = texts
+        .iter()
+        .map(|text| truncate_for_embedding(text))
+        .collect();
+    let hashes: Vec<String> = inputs.iter().map(|input| content_hash(input)).collect();
+
+    let mut vectors = app_handle.db(|db| get_cached_embeddings(db, &model, &hashes))?;
+    let cached_hashes: Vec<String> = vectors.keys().cloned().collect();
+    app_handle.db(|db| touch_cached_embeddings(db, &model, &cached_hashes))?;
+
+    let mut seen = HashSet::new();
+    let missing: Vec<(String, &str)> = hashes
+        .iter()
+        .zip(inputs.iter())
+        .filter(|(hash, _)| !vectors.contains_key(*hash) && seen.insert(hash.as_str()))
+        .map(|(hash, input)| (hash.clone(), *input))
+        .collect();
+    info!(
+        "Embedding {} texts with {}: {} cached, {} to compute",
+        texts.len(),
+        model,
+        cached_hashes.len(),
+        missing.len()
+    );
+
+    let results: Vec<Result<Vec<(String, Vec<f32>)>>> = futures::stream::iter(
+        split_into_batches(missing)
+            .into_iter()
+            .map(|batch| async move {
+                let batch_texts = batch.iter().map(|(_, input)| input.to_string()).collect();
+                let batch_vectors = embed_batch_with_retry(provider, batch_texts).await?;
+                if batch_vectors.len() != batch.len() {
+                    bail!(
+                        "Expected {} embeddings, got {}",
+                        batch.len(),
+                        batch_vectors.len()
+                    );
+                }
+                Ok(batch
+                    .into_iter()
+                    .map(|(hash, _)| hash)
+                    .zip(batch_vectors)
+                    .collect())
+            }),
+    )
+    .buffer_unordered(MAX_CONCURRENT_REQUESTS)
+    .collect()
+    .await;
+
+    let mut computed = Vec::new();
+    let mut first_error = None;
+    for result in results {
+        match result {
+            Ok(batch) => computed.extend(batch),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if !computed.is_empty() {
+        app_handle.db(|db| {
+            save_cached_embeddings(db, &model, &computed)?;
+            prune_embedding_cache(db, MAX_CACHE_ENTRIES)
+        })?;
+    }
+    if let Some(e) = first_error {
+        return Err(e);
+    }
+
+    vectors.extend(computed);
+    hashes
+        .iter()
+        .map(|hash| {
+            vectors
+                .get(hash)
+                .cloned()
+                .ok_or(anyhow!("Missing embedding for {}", hash))
+        })
+        .collect()
+}
+
+/// Hex encoded SHA-256 of the text an embedding was computed from.
+pub fn content_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+/// Builds the embedding provider selected by the `embedding_provider` setting.
+///
+/// Falls back to OpenAI when nothing is configured, which is what older installs used.
+pub fn create_embedding_provider(
+    settings: &EmbeddingSettings,
+) -> Result<Arc<dyn EmbeddingProvider>> {
+    match settings.provider.as_str() {
+        PROVIDER_OPENAI => {
+            let api_key = settings
+                .api_key_open_ai
+                .as_deref()
+                .ok_or(anyhow!("OpenAI API key is not set"))?;
+            Ok(Arc::new(OpenAiEmbeddingProvider::new(
+                api_key,
+                settings.model.as_deref().unwrap_or(OPENAI_EMBEDDING_MODEL),
+            )))
+        }
+        PROVIDER_OPENAI_COMPATIBLE => {
+            let base_url = settings
+                .base_url
+                .as_deref()
+                .ok_or(anyhow!("Embedding base URL is not set"))?;
+            Ok(Arc::new(OpenAiCompatibleEmbeddingProvider::new(
+                base_url,
+                settings.api_key.clone(),
+                settings
+                    .model
+                    .as_deref()
+                    .unwrap_or(OPENAI_COMPATIBLE_DEFAULT_MODEL),
+            )?))
+        }
+        PROVIDER_LOCAL => {
+            let model_path = settings
+                .model_path
+                .as_deref()
+                .ok_or(anyhow!("Local embedding model path is not set"))?;
+            Ok(Arc::new(LocalEmbeddingProvider::new(Path::new(
+                model_path,
+            ))?))
+        }
+        other => bail!("Unknown embedding provider: {}", other),
+    }
+}
+
+/// The provider from the settings, its requests are checked against the budget and recorded in
+/// the usage ledger.
+pub fn get_embedding_provider(app_handle: &AppHandle) -> Result<Arc<dyn EmbeddingProvider>> {
+    get_scoped_embedding_provider(app_handle, UsageScope::default())
+}
+
+/// Like [`get_embedding_provider`], with the usage recorded for the chat or project in `scope`.
+pub fn get_scoped_embedding_provider(
+    app_handle: &AppHandle,
+    scope: UsageScope,
+) -> Result<Arc<dyn EmbeddingProvider>> {
+    let settings = app_handle.db(|db| EmbeddingSettings::load(db));
+    let provider = create_embedding_provider(&settings)?;
+    Ok(Arc::new(MeteredEmbeddingProvider::new(
+        app_handle.clone(),
+        &settings.provider,
+        provider,
+        scope,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{
+        create_embedding_provider, local_model_name, split_into_batches, EmbeddingSettings,
+        MAX_BATCH_CHARS, MAX_BATCH_INPUTS, OPENAI_COMPATIBLE_DEFAULT_MODEL, OPENAI_EMBEDDING_MODEL,
+        PROVIDER_LOCAL, PROVIDER_OPENAI, PROVIDER_OPENAI_COMPATIBLE,
+    };
+
+    fn settings(provider: &str) -> EmbeddingSettings {
+        EmbeddingSettings {
+            provider: provider.to_string(),
+            model: None,
+            base_url: None,
+            api_key: None,
+            api_key_open_ai: None,
+            model_path: None,
+        }
+    }
+
+    fn provider_error(settings: &EmbeddingSettings) -> String {
+        match create_embedding_provider(settings) {
+            Ok(_) => panic!("expected {} to be rejected", settings.provider),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn settings_default_to_openai_and_skip_blank_values() {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE settings (setting_key TEXT PRIMARY KEY, setting_value TEXT NOT NULL);
+             INSERT INTO settings VALUES
+                 ('embedding_model', '  '), ('api_key_open_ai', 'sk-test');",
+        )
+        .unwrap();
+        let loaded = EmbeddingSettings::load(&db);
+        assert_eq!(loaded.provider, PROVIDER_OPENAI);
+        assert_eq!(loaded.model, None);
+        assert_eq!(loaded.api_key_open_ai.as_deref(), Some("sk-test"));
+        assert_eq!(loaded.model_name(), OPENAI_EMBEDDING_MODEL);
+    }
+
+    #[test]
+    fn providers_use_their_default_models() {
+        let mut openai = settings(PROVIDER_OPENAI);
+        openai.api_key_open_ai = Some("sk-test".to_string());
+        let provider = create_embedding_provider(&openai).unwrap();
+        assert_eq!(provider.model_name(), OPENAI_EMBEDDING_MODEL);
+
+        let mut compatible = settings(PROVIDER_OPENAI_COMPATIBLE);
+        compatible.base_url = Some("http://localhost:11434".to_string());
+        let provider = create_embedding_provider(&compatible).unwrap();
+        assert_eq!(provider.model_name(), OPENAI_COMPATIBLE_DEFAULT_MODEL);
+
+        compatible.model = Some("mxbai-embed-large".to_string());
+        assert_eq!(compatible.model_name(), "mxbai-embed-large");
+
+        let mut local = settings(PROVIDER_LOCAL);
+        assert_eq!(local.model_name(), "local");
+        local.model_path = Some("/models/all-MiniLM-L6-v2".to_string());
+        assert_eq!(local.model_name(), "local/all-MiniLM-L6-v2");
+    }
+
+    #[test]
+    fn local_models_in_directories_of_the_same_name_are_told_apart() -> std::io::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut names = Vec::new();
+        for version in ["v1", "v2"] {
+            let model_dir = temp_dir.path().join(version).join("model");
+            std::fs::create_dir_all(&model_dir)?;
+            std::fs::write(
+                model_dir.join("config.json"),
+                format!("{{\"v\": \"{}\"}}", version),
+            )?;
+            std::fs::write(model_dir.join("model.safetensors"), version)?;
+            names.push(local_model_name(&model_dir));
+        }
+        assert!(names[0].starts_with("local/model@"));
+        assert_ne!(names[0], names[1]);
+        assert_eq!(
+            local_model_name(&temp_dir.path().join("v1/model")),
+            names[0]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn missing_settings_are_reported() {
+        assert_eq!(
+            provider_error(&settings(PROVIDER_OPENAI)),
+            "OpenAI API key is not set"
+        );
+        assert_eq!(
+            provider_error(&settings(PROVIDER_OPENAI_COMPATIBLE)),
+            "Embedding base URL is not set"
+        );
+        assert_eq!(
+            provider_error(&settings(PROVIDER_LOCAL)),
+            "Local embedding model path is not set"
+        );
+        assert_eq!(
+            provider_error(&settings("cohere")),
+            "Unknown embedding provider: cohere"
+        );
+    }
+
+    #[test]
+    fn batches_respect_input_and_size_limits() {
+        let short = "x".repeat(10);
+        let inputs: Vec<(usize, &str)> = (0..MAX_BATCH_INPUTS + 1)
+            .map(|i| (i, short.as_str()))
+            .collect();
+        let batches = split_into_batches(inputs);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].len(), MAX_BATCH_INPUTS);
+        assert_eq!(batches[1][0].0, MAX_BATCH_INPUTS);
+
+        let long = "y".repeat(MAX_BATCH_CHARS / 2 + 1);
+        let batches = split_into_batches(vec![(0, long.as_str()), (1, long.as_str()), (2, "z")]);
+        let sizes: Vec<usize> = batches.iter().map(|batch| batch.len()).collect();
+        assert_eq!(sizes, vec![1, 2]);
+    }
+}
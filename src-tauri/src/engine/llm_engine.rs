@@ -0,0 +1,821 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use async_openai::{
+    config::OpenAIConfig,
+    error::OpenAIError,
+    types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+        ChatCompletionStreamOptions, CreateChatCompletionRequest,
+        CreateChatCompletionRequestArgs,
+    },
+    Client as OpenAIClient,
+};
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use log::{debug, error};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tauri::AppHandle;
+use tiktoken_rs::{cl100k_base, o200k_base, CoreBPE};
+
+use crate::configuration::state::ServiceAccess;
+use crate::engine::embedding_engine::non_empty_setting;
+use crate::engine::model_registry_engine::ModelRegistry;
+use crate::entity::llm_model::LlmModel;
+use crate::repository::settings_repository::get_setting;
+
+pub const PROVIDER_ANTHROPIC: &str = "anthropic";
+pub const PROVIDER_OPENAI: &str = "openai";
+pub const PROVIDER_OPENAI_COMPATIBLE: &str = "openai_compatible";
+
+const ANTHROPIC_URL: &str = "https://api.anthropic.com/v1/messages";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlmMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl LlmMessage {
+    pub fn user(content: impl Into<String>) -> Self {
+        LlmMessage {
+            role: "user".to_string(),
+            content: content.into(),
+        }
+    }
+}
+
+/// A request to a model, the same for every provider.
+#[derive(Debug, Clone)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub system: String,
+    pub messages: Vec<LlmMessage>,
+    pub max_tokens: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct TokenUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub text: String,
+    pub usage: TokenUsage,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// The next piece of the answer.
+    Delta(String),
+    /// Token counts so far, a later report replaces an earlier one.
+    Usage(TokenUsage),
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LlmCapabilities {
+    /// Answers arrive piece by piece rather than all at once.
+    pub streaming: bool,
+    /// Token usage comes from the provider, otherwise it's estimated with `count_tokens`.
+    pub reports_usage: bool,
+}
+
+/// A service answering prompts, the chat pipeline only talks to models through this.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// Identifier of the provider, e.g. `anthropic`.
+    fn id(&self) -> &str;
+
+    /// Name the provider goes by towards the user.
+    fn name(&self) -> &str;
+
+    fn capabilities(&self) -> LlmCapabilities;
+
+    /// Models of the registry the provider serves, the first one is used when none is picked.
+    fn models(&self) -> &[LlmModel];
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion>;
+
+    /// Streams the answer. Providers that can't stream send it as a single delta.
+    async fn stream(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<BoxStream<'static, Result<StreamEvent>>> {
+        let completion = self.complete(request).await?;
+        let events = vec![
+            Ok(StreamEvent::Delta(completion.text)),
+            Ok(StreamEvent::Usage(completion.usage)),
+        ];
+        Ok(stream::iter(events).boxed())
+    }
+
+    /// Number of tokens `text` takes up with this provider's models. Counted with
+    /// `cl100k_base` unless the provider knows better, it comes close for tokenizers that
+    /// aren't published.
+    fn count_tokens(&self, text: &str) -> usize {
+        count_bpe_tokens(&CL100K, text)
+    }
+
+    /// `model` if the provider serves it, its default model otherwise.
+    fn resolve_model(&self, model: Option<&str>) -> Result<LlmModel> {
+        let models = self.models();
+        model
+            .and_then(|model| models.iter().find(|known| known.id == model))
+            .or_else(|| models.first())
+            .cloned()
+            .ok_or_else(|| anyhow!("{} has no models configured", self.name()))
+    }
+}
+
+/// About four characters per token, which holds for English text with the common tokenizers.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+lazy_static! {
+    // Loading the ranks takes a moment, so the tokenizers are shared by all providers.
+    static ref CL100K: Option<CoreBPE> = load_tokenizer("cl100k_base", cl100k_base);
+    static ref O200K: Option<CoreBPE> = load_tokenizer("o200k_base", o200k_base);
+}
+
+fn load_tokenizer(name: &str, load: fn() -> Result<CoreBPE>) -> Option<CoreBPE> {
+    match load() {
+        Ok(tokenizer) => Some(tokenizer),
+        Err(e) => {
+            error!("Failed to load the {} tokenizer, estimating tokens: {}", name, e);
+            None
+        }
+    }
+}
+
+fn count_bpe_tokens(tokenizer: &Option<CoreBPE>, text: &str) -> usize {
+    match tokenizer {
+        Some(tokenizer) => tokenizer.encode_ordinary(text).len(),
+        None => estimate_tokens(text),
+    }
+}
+
+/// Whether the request failed before the provider answered, such requests are worth repeating.
+pub fn is_connection_error(error: &anyhow::Error) -> bool {
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        return error.is_connect() || error.is_timeout() || error.is_request();
+    }
+    matches!(error.downcast_ref::<OpenAIError>(), Some(OpenAIError::Reqwest(_)))
+}
+
+/// Appends `chunk` to `buffer` and returns the payloads of the `data:` lines it completes. A line
+/// split across chunks stays in the buffer until the rest of it arrives.
+fn take_sse_data(buffer: &mut Vec<u8>, chunk: &[u8]) -> Vec<String> {
+    buffer.extend_from_slice(chunk);
+    let mut payloads = Vec::new();
+    while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+        let line: Vec<u8> = buffer.drain(..=end).collect();
+        let line = String::from_utf8_lossy(&line);
+        if let Some(payload) = line.trim_end().strip_prefix("data:") {
+            let payload = payload.trim();
+            if !payload.is_empty() {
+                payloads.push(payload.to_string());
+            }
+        }
+    }
+    payloads
+}
+
+pub struct AnthropicProvider {
+    client: reqwest::Client,
+    api_key: String,
+    models: Vec<LlmModel>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicResponse {
+    content: Vec<AnthropicContent>,
+    usage: AnthropicUsage,
+}
+
+#[derive(Deserialize)]
+struct AnthropicContent {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct AnthropicUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+impl AnthropicProvider {
+    pub fn new(api_key: &str, models: Vec<LlmModel>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(180))
+            .tcp_keepalive(Duration::from_secs(60))
+            .pool_idle_timeout(Duration::from_secs(90))
+            .pool_max_idle_per_host(2)
+            .connect_timeout(Duration::from_secs(30))
+            .build()?;
+        Ok(AnthropicProvider {
+            client,
+            api_key: api_key.to_string(),
+            models,
+        })
+    }
+
+    async fn send(&self, request: &CompletionRequest, stream: bool) -> Result<reqwest::Response> {
+        let response = self
+            .client
+            .post(ANTHROPIC_URL)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .header("Connection", "keep-alive")
+            .json(&json!({
+                "model": request.model,
+                "max_tokens": request.max_tokens,
+                "system": request.system,
+                "messages": request.messages,
+                "stream": stream,
+            }))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let error_message = response.text().await?;
+            bail!("Error from Claude API: {}", error_message);
+        }
+        Ok(response)
+    }
+}
+
+/// Reads one event of the Messages stream, keeping the token counts in `usage`.
+fn parse_anthropic_event(data: &str, usage: &mut TokenUsage) -> Result<Option<StreamEvent>> {
+    let event: serde_json::Value = match serde_json::from_str(data) {
+        Ok(event) => event,
+        Err(e) => {
+            error!("Failed to parse event data: {}", e);
+            return Ok(None);
+        }
+    };
+    match event["type"].as_str() {
+        Some("error") => {
+            let error_type = event["error"]["type"].as_str().unwrap_or("unknown");
+            let error_message = event["error"]["message"].as_str().unwrap_or("Unknown error");
+            error!("Received error event: {} - {}", error_type, error_message);
+            if error_type == "overloaded_error" {
+                bail!("Service is currently overloaded. Please try again later.");
+            }
+            bail!("Stream error: {}", error_message)
+        }
+        Some("message_start") => {
+            let message_usage = &event["message"]["usage"];
+            usage.input_tokens = message_usage["input_tokens"].as_u64().unwrap_or(0) as u32;
+            usage.output_tokens = message_usage["output_tokens"].as_u64().unwrap_or(0) as u32;
+            Ok(Some(StreamEvent::Usage(*usage)))
+        }
+        Some("content_block_delta") => Ok(event["delta"]["text"]
+            .as_str()
+            .map(|text| StreamEvent::Delta(text.to_string()))),
+        Some("message_delta") => {
+            if let Some(output_tokens) = event["usage"]["output_tokens"].as_u64() {
+                usage.output_tokens = output_tokens as u32;
+            }
+            Ok(Some(StreamEvent::Usage(*usage)))
+        }
+        // Pings keep the connection alive, block and message boundaries carry nothing we use
+        _ => Ok(None),
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    fn id(&self) -> &str {
+        PROVIDER_ANTHROPIC
+    }
+
+    fn name(&self) -> &str {
+        "Anthropic"
+    }
+
+    fn capabilities(&self) -> LlmCapabilities {
+        LlmCapabilities {
+            streaming: true,
+            reports_usage: true,
+        }
+    }
+
+    fn models(&self) -> &[LlmModel] {
+        &self.models
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion> {
+        let response: AnthropicResponse = self.send(request, false).await?.json().await?;
+        let text = response
+            .content
+            .into_iter()
+            .map(|content| content.text)
+            .collect::<String>();
+        Ok(Completion {
+            text,
+            usage: TokenUsage {
+                input_tokens: response.usage.input_tokens,
+                output_tokens: response.usage.output_tokens,
+            },
+        })
+    }
+
+    async fn stream(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<BoxStream<'static, Result<StreamEvent>>> {
+        let response = self.send(request, true).await?;
+        let mut buffer = Vec::new();
+        let mut usage = TokenUsage::default();
+        let events = response.bytes_stream().flat_map(move |chunk| {
+            let events: Vec<Result<StreamEvent>> = match chunk {
+                Ok(chunk) => take_sse_data(&mut buffer, &chunk)
+                    .iter()
+                    .filter_map(|data| parse_anthropic_event(data, &mut usage).transpose())
+                    .collect(),
+                Err(e) => vec![Err(anyhow!("Failed to read chunk: {}", e))],
+            };
+            stream::iter(events)
+        });
+        Ok(events.boxed())
+    }
+}
+
+pub struct OpenAiProvider {
+    client: OpenAIClient<OpenAIConfig>,
+    models: Vec<LlmModel>,
+}
+
+impl OpenAiProvider {
+    pub fn new(api_key: &str, models: Vec<LlmModel>) -> Self {
+        OpenAiProvider {
+            client: OpenAIClient::with_config(OpenAIConfig::new().with_api_key(api_key)),
+            models,
+        }
+    }
+
+    fn openai_request(&self, request: &CompletionRequest) -> Result<CreateChatCompletionRequest> {
+        // Reasoning models reject `max_tokens` and pick their own output length
+        let reasoning = self
+            .models
+            .iter()
+            .any(|model| model.id == request.model && model.capabilities.reasoning);
+        openai_request(request, reasoning)
+    }
+}
+
+fn openai_request(
+    request: &CompletionRequest,
+    reasoning: bool,
+) -> Result<CreateChatCompletionRequest> {
+    let mut messages: Vec<ChatCompletionRequestMessage> = vec![
+        ChatCompletionRequestSystemMessageArgs::default()
+            .content(request.system.as_str())
+            .build()?
+            .into(),
+    ];
+    for message in &request.messages {
+        let message = if message.role == "assistant" {
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .content(message.content.as_str())
+                .build()?
+                .into()
+        } else {
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(message.content.as_str())
+                .build()?
+                .into()
+        };
+        messages.push(message);
+    }
+
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.model(&request.model).messages(messages);
+    if !reasoning {
+        args.max_tokens(request.max_tokens as u32);
+    }
+    Ok(args.build()?)
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    fn id(&self) -> &str {
+        PROVIDER_OPENAI
+    }
+
+    fn name(&self) -> &str {
+        "OpenAI"
+    }
+
+    fn capabilities(&self) -> LlmCapabilities {
+        LlmCapabilities {
+            streaming: true,
+            reports_usage: true,
+        }
+    }
+
+    // GPT-4o and the o-series share this tokenizer
+    fn count_tokens(&self, text: &str) -> usize {
+        count_bpe_tokens(&O200K, text)
+    }
+
+    fn models(&self) -> &[LlmModel] {
+        &self.models
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion> {
+        let response = self
+            .client
+            .chat()
+            .create(self.openai_request(request)?)
+            .await?;
+        debug!("OpenAI response: {:?}", response);
+        let text = response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.clone())
+            .unwrap_or_default();
+        let usage = response
+            .usage
+            .map(|usage| TokenUsage {
+                input_tokens: usage.prompt_tokens,
+                output_tokens: usage.completion_tokens,
+            })
+            .unwrap_or_default();
+        Ok(Completion { text, usage })
+    }
+
+    async fn stream(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<BoxStream<'static, Result<StreamEvent>>> {
+        let mut openai_request = self.openai_request(request)?;
+        openai_request.stream_options = Some(ChatCompletionStreamOptions {
+            include_usage: true,
+        });
+        let responses = self.client.chat().create_stream(openai_request).await?;
+        let events = responses.flat_map(|response| {
+            let events: Vec<Result<StreamEvent>> = match response {
+                Ok(response) => {
+                    let delta = response
+                        .choices
+                        .first()
+                        .and_then(|choice| choice.delta.content.clone())
+                        .map(|content| Ok(StreamEvent::Delta(content)));
+                    // Usage comes in a last response without choices
+                    let usage = response.usage.map(|usage| {
+                        Ok(StreamEvent::Usage(TokenUsage {
+                            input_tokens: usage.prompt_tokens,
+                            output_tokens: usage.completion_tokens,
+                        }))
+                    });
+                    delta.into_iter().chain(usage).collect()
+                }
+                Err(e) => vec![Err(anyhow!("Error while streaming response: {}", e))],
+            };
+            stream::iter(events)
+        });
+        Ok(events.boxed())
+    }
+}
+
+/// Talks to any server exposing an OpenAI style `/v1/chat/completions` endpoint (Ollama,
+/// llama.cpp, vLLM, ...).
+pub struct OpenAiCompatibleProvider {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    headers: Vec<(String, String)>,
+    models: Vec<LlmModel>,
+}
+
+/// Settings of the custom endpoint, `custom_llm_*` in the settings table. Its model comes from
+/// the model registry.
+#[derive(Debug, Clone)]
+pub struct CustomLlmSettings {
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    /// JSON object of extra header names and values, e.g. `{"X-Org": "research"}`.
+    pub headers: Option<String>,
+}
+
+impl CustomLlmSettings {
+    pub fn load(db: &Connection) -> Self {
+        CustomLlmSettings {
+            base_url: non_empty_setting(db, "custom_llm_base_url"),
+            api_key: non_empty_setting(db, "custom_llm_api_key"),
+            headers: non_empty_setting(db, "custom_llm_headers"),
+        }
+    }
+}
+
+/// Reads the extra headers of the custom endpoint, sorted by name.
+fn parse_custom_headers(headers: Option<&str>) -> Result<Vec<(String, String)>> {
+    let Some(headers) = headers else {
+        return Ok(Vec::new());
+    };
+    let headers: HashMap<String, String> = serde_json::from_str(headers)
+        .map_err(|e| anyhow!("custom_llm_headers must be a JSON object of strings: {}", e))?;
+    let mut headers: Vec<(String, String)> = headers.into_iter().collect();
+    headers.sort();
+    Ok(headers)
+}
+
+/// Reads one chunk of an OpenAI style stream, `[DONE]` marks its end.
+fn parse_openai_chunk(data: &str) -> Result<Vec<StreamEvent>> {
+    if data == "[DONE]" {
+        return Ok(Vec::new());
+    }
+    let chunk: serde_json::Value = match serde_json::from_str(data) {
+        Ok(chunk) => chunk,
+        Err(e) => {
+            error!("Failed to parse event data: {}", e);
+            return Ok(Vec::new());
+        }
+    };
+    if let Some(message) = chunk["error"]["message"].as_str() {
+        bail!("Stream error: {}", message);
+    }
+    let mut events = Vec::new();
+    if let Some(content) = chunk["choices"][0]["delta"]["content"].as_str() {
+        if !content.is_empty() {
+            events.push(StreamEvent::Delta(content.to_string()));
+        }
+    }
+    if let Some(usage) = chunk["usage"].as_object() {
+        events.push(StreamEvent::Usage(TokenUsage {
+            input_tokens: usage["prompt_tokens"].as_u64().unwrap_or(0) as u32,
+            output_tokens: usage["completion_tokens"].as_u64().unwrap_or(0) as u32,
+        }));
+    }
+    Ok(events)
+}
+
+impl OpenAiCompatibleProvider {
+    pub fn new(settings: &CustomLlmSettings, models: Vec<LlmModel>) -> Result<Self> {
+        let base_url = settings
+            .base_url
+            .as_deref()
+            .ok_or(anyhow!("custom_llm_base_url is not set"))?;
+        // Local servers may take a while to load the model on the first request
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(600))
+            .connect_timeout(Duration::from_secs(30))
+            .build()?;
+        Ok(OpenAiCompatibleProvider {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: settings.api_key.clone(),
+            headers: parse_custom_headers(settings.headers.as_deref())?,
+            models,
+        })
+    }
+
+    fn chat_completions_url(&self) -> String {
+        if self.base_url.ends_with("/v1") {
+            format!("{}/chat/completions", self.base_url)
+        } else {
+            format!("{}/v1/chat/completions", self.base_url)
+        }
+    }
+
+    async fn send(&self, request: &CompletionRequest, stream: bool) -> Result<reqwest::Response> {
+        let mut messages = vec![json!({ "role": "system", "content": request.system })];
+        messages.extend(
+            request
+                .messages
+                .iter()
+                .map(|message| json!({ "role": message.role, "content": message.content })),
+        );
+        let mut body = json!({
+            "model": request.model,
+            "messages": messages,
+            "max_tokens": request.max_tokens,
+            "stream": stream,
+        });
+        if stream {
+            body["stream_options"] = json!({ "include_usage": true });
+        }
+
+        let mut http_request = self.client.post(self.chat_completions_url()).json(&body);
+        if let Some(api_key) = &self.api_key {
+            http_request = http_request.bearer_auth(api_key);
+        }
+        for (name, value) in &self.headers {
+            http_request = http_request.header(name, value);
+        }
+        let response = http_request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_message = response.text().await?;
+            bail!(
+                "Error from {} ({}): {}",
+                self.base_url,
+                status,
+                error_message
+            );
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiCompatibleProvider {
+    fn id(&self) -> &str {
+        PROVIDER_OPENAI_COMPATIBLE
+    }
+
+    fn name(&self) -> &str {
+        "Custom endpoint"
+    }
+
+    fn capabilities(&self) -> LlmCapabilities {
+        // Not every server reports usage, the pipeline estimates it when it's missing
+        LlmCapabilities {
+            streaming: true,
+            reports_usage: false,
+        }
+    }
+
+    fn models(&self) -> &[LlmModel] {
+        &self.models
+    }
+
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion> {
+        let response: serde_json::Value = self.send(request, false).await?.json().await?;
+        let text = response["choices"][0]["message"]["content"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let usage = TokenUsage {
+            input_tokens: response["usage"]["prompt_tokens"].as_u64().unwrap_or(0) as u32,
+            output_tokens: response["usage"]["completion_tokens"].as_u64().unwrap_or(0) as u32,
+        };
+        Ok(Completion { text, usage })
+    }
+
+    async fn stream(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<BoxStream<'static, Result<StreamEvent>>> {
+        let response = self.send(request, true).await?;
+        let mut buffer = Vec::new();
+        let events = response.bytes_stream().flat_map(move |chunk| {
+            let events: Vec<Result<StreamEvent>> = match chunk {
+                Ok(chunk) => take_sse_data(&mut buffer, &chunk)
+                    .iter()
+                    .flat_map(|data| match parse_openai_chunk(data) {
+                        Ok(events) => events.into_iter().map(Ok).collect(),
+                        Err(e) => vec![Err(e)],
+                    })
+                    .collect(),
+                Err(e) => vec![Err(anyhow!("Failed to read chunk: {}", e))],
+            };
+            stream::iter(events)
+        });
+        Ok(events.boxed())
+    }
+}
+
+fn api_key(db: &Connection, setting_key: &str) -> Result<String> {
+    non_empty_setting(db, setting_key).ok_or_else(|| anyhow!("{} is not set", setting_key))
+}
+
+/// Builds the provider `provider_id` with the API key from the settings and its models from
+/// the model registry.
+pub fn create_llm_provider(db: &Connection, provider_id: &str) -> Result<Arc<dyn LlmProvider>> {
+    let models = ModelRegistry::load(db).for_provider(provider_id);
+    match provider_id {
+        PROVIDER_ANTHROPIC => Ok(Arc::new(AnthropicProvider::new(
+            &api_key(db, "api_key_claude")?,
+            models,
+        )?)),
+        PROVIDER_OPENAI => Ok(Arc::new(OpenAiProvider::new(
+            &api_key(db, "api_key_open_ai")?,
+            models,
+        ))),
+        PROVIDER_OPENAI_COMPATIBLE => Ok(Arc::new(OpenAiCompatibleProvider::new(
+            &CustomLlmSettings::load(db),
+            models,
+        )?)),
+        other => bail!("Unknown LLM provider {}", other),
+    }
+}
+
+/// The provider serving `model_id` in the model registry. Without a model, or with one the
+/// registry doesn't know, it's the provider picked in the settings (`api_choice`).
+pub fn get_llm_provider(
+    app_handle: &AppHandle,
+    model_id: Option<&str>,
+) -> Result<Arc<dyn LlmProvider>> {
+    app_handle.db(|db| {
+        let registry = ModelRegistry::load(db);
+        let provider_id = match model_id.and_then(|model_id| registry.get(model_id)) {
+            Some(model) => model.provider.clone(),
+            None => match get_setting(db, "api_choice") {
+                Ok(setting)
+                    if setting.setting_value == PROVIDER_OPENAI
+                        || setting.setting_value == PROVIDER_OPENAI_COMPATIBLE =>
+                {
+                    setting.setting_value
+                }
+                _ => PROVIDER_ANTHROPIC.to_string(),
+            },
+        };
+        create_llm_provider(db, &provider_id)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_anthropic_event, parse_custom_headers, parse_openai_chunk, take_sse_data,
+        StreamEvent, TokenUsage,
+    };
+
+    #[test]
+    fn sse_lines_split_across_chunks_are_joined() {
+        let mut buffer = Vec::new();
+        assert!(take_sse_data(&mut buffer, b"event: ping\ndata: {\"type\": \"pi").is_empty());
+        assert_eq!(
+            take_sse_data(&mut buffer, b"ng\"}\r\n\r\ndata: [DONE]\n"),
+            vec!["{\"type\": \"ping\"}".to_string(), "[DONE]".to_string()]
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn anthropic_events_carry_text_and_usage() {
+        let mut usage = TokenUsage::default();
+        let start = r#"{"type":"message_start","message":{"usage":{"input_tokens":12,"output_tokens":1}}}"#;
+        let delta = r#"{"type":"content_block_delta","delta":{"type":"text_delta","text":"Hi"}}"#;
+        let end = r#"{"type":"message_delta","usage":{"output_tokens":7}}"#;
+
+        parse_anthropic_event(start, &mut usage).unwrap();
+        assert_eq!(
+            parse_anthropic_event(delta, &mut usage).unwrap(),
+            Some(StreamEvent::Delta("Hi".to_string()))
+        );
+        assert_eq!(
+            parse_anthropic_event(end, &mut usage).unwrap(),
+            Some(StreamEvent::Usage(TokenUsage {
+                input_tokens: 12,
+                output_tokens: 7
+            }))
+        );
+        assert!(parse_anthropic_event(r#"{"type":"ping"}"#, &mut usage)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn anthropic_error_events_fail_the_stream() {
+        let mut usage = TokenUsage::default();
+        let overloaded = r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+        assert!(parse_anthropic_event(overloaded, &mut usage).is_err());
+    }
+
+    #[test]
+    fn openai_chunks_carry_text_and_usage() {
+        let delta = r#"{"choices":[{"index":0,"delta":{"content":"Hel"}}]}"#;
+        let usage = r#"{"choices":[],"usage":{"prompt_tokens":30,"completion_tokens":4}}"#;
+        assert_eq!(
+            parse_openai_chunk(delta).unwrap(),
+            vec![StreamEvent::Delta("Hel".to_string())]
+        );
+        assert_eq!(
+            parse_openai_chunk(usage).unwrap(),
+            vec![StreamEvent::Usage(TokenUsage {
+                input_tokens: 30,
+                output_tokens: 4
+            })]
+        );
+        assert!(parse_openai_chunk("[DONE]").unwrap().is_empty());
+        assert!(parse_openai_chunk(r#"{"error":{"message":"model not found"}}"#).is_err());
+    }
+
+    #[test]
+    fn custom_headers_are_a_json_object() {
+        assert_eq!(
+            parse_custom_headers(Some(r#"{"X-Org":"research","Authorization-Extra":"1"}"#))
+                .unwrap(),
+            vec![
+                ("Authorization-Extra".to_string(), "1".to_string()),
+                ("X-Org".to_string(), "research".to_string())
+            ]
+        );
+        assert!(parse_custom_headers(None).unwrap().is_empty());
+        assert!(parse_custom_headers(Some("X-Org: research")).is_err());
+    }
+}
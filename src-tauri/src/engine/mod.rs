@@ -1,8 +1,17 @@
 pub mod chat_engine;
-pub mod chat_engine_openai;
+pub mod chunking_engine;
 pub mod clean_up_engine;
+pub mod context_budget_engine;
+pub mod deduplication_engine;
+pub mod embedding_engine;
+pub mod llm_engine;
+pub mod model_registry_engine;
 pub mod monitoring_engine;
+pub mod reindex_engine;
+pub mod retrieval_engine;
+pub mod search_engine;
 pub mod similarity_search_engine;
+pub mod usage_engine;
 pub mod transcription_engine;
 pub mod text_recognition_engine;
 pub mod os_details_engine;
@@ -0,0 +1,221 @@
+use log::error;
+use rusqlite::Connection;
+use tauri::AppHandle;
+
+use crate::configuration::state::ServiceAccess;
+use crate::engine::embedding_engine::non_empty_setting;
+use crate::engine::llm_engine::{PROVIDER_ANTHROPIC, PROVIDER_OPENAI, PROVIDER_OPENAI_COMPATIBLE};
+use crate::entity::llm_model::{LlmModel, ModelCapabilities};
+
+/// Local servers rarely say how large their context is, this fits most models they run.
+const CUSTOM_CONTEXT_WINDOW: usize = 8192;
+const CUSTOM_MAX_OUTPUT_TOKENS: usize = 2048;
+
+fn model(
+    id: &str,
+    provider: &str,
+    name: &str,
+    description: &str,
+    (context_window, max_output_tokens): (usize, usize),
+    (input_price, output_price): (f64, f64),
+    capabilities: ModelCapabilities,
+) -> LlmModel {
+    LlmModel {
+        id: id.to_string(),
+        provider: provider.to_string(),
+        name: name.to_string(),
+        description: description.to_string(),
+        context_window,
+        max_output_tokens,
+        input_price,
+        output_price,
+        capabilities,
+    }
+}
+
+/// Models known without any configuration. The first model of a provider is its default.
+fn builtin_models() -> Vec<LlmModel> {
+    let chat = ModelCapabilities {
+        streaming: true,
+        reasoning: false,
+        vision: true,
+    };
+    let reasoning = ModelCapabilities {
+        streaming: true,
+        reasoning: true,
+        vision: false,
+    };
+    vec![
+        model(
+            "claude-3-7-sonnet-20250219",
+            PROVIDER_ANTHROPIC,
+            "Claude 3.7 Sonnet",
+            "Main Anthropic model",
+            (200_000, 8192),
+            (3.0, 15.0),
+            chat,
+        ),
+        model(
+            "claude-3-5-haiku-20241022",
+            PROVIDER_ANTHROPIC,
+            "Claude 3.5 Haiku",
+            "Latest Haiku model",
+            (200_000, 8192),
+            (0.8, 4.0),
+            ModelCapabilities {
+                vision: false,
+                ..chat
+            },
+        ),
+        model(
+            "claude-3-haiku-20240307",
+            PROVIDER_ANTHROPIC,
+            "Claude 3 Haiku",
+            "Fastest Anthropic model",
+            (200_000, 4096),
+            (0.25, 1.25),
+            chat,
+        ),
+        model(
+            "gpt-4o",
+            PROVIDER_OPENAI,
+            "GPT-4o",
+            "Latest OpenAI model",
+            (128_000, 16_384),
+            (2.5, 10.0),
+            chat,
+        ),
+        model(
+            "o1",
+            PROVIDER_OPENAI,
+            "O1",
+            "Advanced reasoning",
+            (200_000, 100_000),
+            (15.0, 60.0),
+            ModelCapabilities {
+                vision: true,
+                ..reasoning
+            },
+        ),
+        model(
+            "o3-mini",
+            PROVIDER_OPENAI,
+            "O3-mini",
+            "Efficient reasoning",
+            (200_000, 100_000),
+            (1.1, 4.4),
+            reasoning,
+        ),
+    ]
+}
+
+/// USD per million input tokens of an embedding model. Only OpenAI charges for the models the
+/// app embeds with, local models and custom servers are free.
+pub fn embedding_price(provider_id: &str, model: &str) -> f64 {
+    if provider_id != PROVIDER_OPENAI {
+        return 0.0;
+    }
+    match model {
+        "text-embedding-3-small" => 0.02,
+        "text-embedding-3-large" => 0.13,
+        "text-embedding-ada-002" => 0.1,
+        _ => 0.0,
+    }
+}
+
+/// Entries of `overrides` replace the model with the same id, new ones are appended.
+fn merge_models(mut models: Vec<LlmModel>, overrides: Vec<LlmModel>) -> Vec<LlmModel> {
+    for model in overrides {
+        match models.iter_mut().find(|known| known.id == model.id) {
+            Some(known) => *known = model,
+            None => models.push(model),
+        }
+    }
+    models
+}
+
+/// The models the app can talk to: the built-in catalogue, changed and extended by the JSON
+/// array in the `llm_models` setting, plus the model of the custom endpoint.
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    models: Vec<LlmModel>,
+}
+
+impl ModelRegistry {
+    pub fn load(db: &Connection) -> Self {
+        let mut models = builtin_models();
+        if let Some(custom_model) = non_empty_setting(db, "custom_llm_model") {
+            models.push(LlmModel {
+                id: custom_model.clone(),
+                provider: PROVIDER_OPENAI_COMPATIBLE.to_string(),
+                name: custom_model,
+                description: "Custom endpoint".to_string(),
+                context_window: CUSTOM_CONTEXT_WINDOW,
+                max_output_tokens: CUSTOM_MAX_OUTPUT_TOKENS,
+                input_price: 0.0,
+                output_price: 0.0,
+                capabilities: ModelCapabilities {
+                    streaming: true,
+                    reasoning: false,
+                    vision: false,
+                },
+            });
+        }
+        if let Some(overrides) = non_empty_setting(db, "llm_models") {
+            match serde_json::from_str::<Vec<LlmModel>>(&overrides) {
+                Ok(overrides) => models = merge_models(models, overrides),
+                Err(e) => error!("Ignoring invalid llm_models setting: {}", e),
+            }
+        }
+        ModelRegistry { models }
+    }
+
+    pub fn models(&self) -> &[LlmModel] {
+        &self.models
+    }
+
+    pub fn get(&self, model_id: &str) -> Option<&LlmModel> {
+        self.models.iter().find(|model| model.id == model_id)
+    }
+
+    pub fn for_provider(&self, provider_id: &str) -> Vec<LlmModel> {
+        self.models
+            .iter()
+            .filter(|model| model.provider == provider_id)
+            .cloned()
+            .collect()
+    }
+}
+
+#[tauri::command]
+pub fn list_models(app_handle: AppHandle) -> Result<Vec<LlmModel>, String> {
+    Ok(app_handle.db(|db| ModelRegistry::load(db).models))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{builtin_models, merge_models};
+    use crate::entity::llm_model::LlmModel;
+
+    #[test]
+    fn overrides_replace_known_models_and_add_new_ones() {
+        let overrides: Vec<LlmModel> = serde_json::from_str(
+            r#"[
+                {"id": "gpt-4o", "provider": "openai", "name": "GPT-4o", "context_window": 64000,
+                 "max_output_tokens": 4096, "capabilities": {"streaming": true}},
+                {"id": "mistral-large", "provider": "openai_compatible", "name": "Mistral Large",
+                 "context_window": 128000, "max_output_tokens": 4096, "input_price": 2.0,
+                 "output_price": 6.0, "capabilities": {"streaming": true}}
+            ]"#,
+        )
+        .unwrap();
+        let builtin = builtin_models();
+        let models = merge_models(builtin.clone(), overrides);
+
+        assert_eq!(models.len(), builtin.len() + 1);
+        let gpt_4o = models.iter().find(|model| model.id == "gpt-4o").unwrap();
+        assert_eq!(gpt_4o.context_window, 64000);
+        assert_eq!(gpt_4o.input_price, 0.0);
+        assert_eq!(models.last().unwrap().id, "mistral-large");
+    }
+}
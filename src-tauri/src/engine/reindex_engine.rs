@@ -0,0 +1,293 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::Serialize;
+use tauri::{AppHandle, Manager};
+
+use crate::configuration::database::{
+    open_vector_collection, VECTOR_COLLECTION_MODEL_SETTING, VECTOR_COLLECTION_SETTING,
+};
+use crate::configuration::state::ServiceAccess;
+use crate::engine::chunking_engine::{chunk_text, ChunkingOptions};
+use crate::engine::embedding_engine::{
+    content_hash, embed_texts, get_embedding_provider, EmbeddingProvider,
+};
+use crate::engine::retrieval_engine::get_source_document;
+use crate::entity::setting::Setting;
+use crate::entity::vector_chunk::SourceType;
+use crate::repository::activity_log_repository::get_chunk_embedding_text;
+use crate::repository::settings_repository::insert_or_update_setting;
+use crate::repository::vector_db_repository::{
+    delete_vector_embeddings_except_model, get_indexed_sources,
+    get_vector_chunks_without_embedding, get_vector_chunk_ids_by_source, insert_vector_chunks,
+    save_vector_embeddings,
+};
+use crate::HNSW;
+
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Sources whose chunks are embedded together, activities are often a single short chunk.
+const SOURCES_PER_BATCH: usize = 32;
+
+/// Controls of the running re-embedding job.
+struct ReindexJob {
+    paused: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+lazy_static! {
+    static ref REINDEX_JOB: std::sync::Mutex<Option<Arc<ReindexJob>>> = std::sync::Mutex::new(None);
+}
+
+#[derive(Clone, Serialize)]
+struct ReindexProgress {
+    model: String,
+    processed: usize,
+    total: usize,
+}
+
+#[derive(Clone, Serialize)]
+struct ReindexFinished {
+    model: String,
+    collection: String,
+}
+
+fn emit<S: Serialize + Clone>(app_handle: &AppHandle, event: &str, payload: S) {
+    match app_handle.get_window("main") {
+        Some(window) => {
+            if let Err(e) = window.emit(event, payload) {
+                error!("Failed to emit {}: {}", event, e);
+            }
+        }
+        None => error!("Failed to get main window to emit {}", event),
+    }
+}
+
+fn current_job() -> Result<Arc<ReindexJob>, String> {
+    REINDEX_JOB
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or("No re-embedding job is running".to_string())
+}
+
+/// Returns `false` once the job is cancelled, waits while it's paused.
+async fn wait_while_paused(job: &ReindexJob) -> bool {
+    while job.paused.load(Ordering::SeqCst) && !job.cancelled.load(Ordering::SeqCst) {
+        tokio::time::sleep(PAUSE_POLL_INTERVAL).await;
+    }
+    !job.cancelled.load(Ordering::SeqCst)
+}
+
+/// Returns `(chunk_id, text to embed)` of every chunk of the source that has no vector of
+/// `model` yet.
+///
+/// Documents that were marked as vectorized but lost their chunks are chunked again.
+fn get_pending_chunks(
+    app_handle: &AppHandle,
+    source_type: SourceType,
+    source_id: i64,
+    model: &str,
+) -> Result<Vec<(i64, String)>> {
+    let Some((document_name, document_text)) =
+        app_handle.db(|db| get_source_document(db, source_type, source_id))?
+    else {
+        return Ok(Vec::new());
+    };
+
+    let has_chunks =
+        !app_handle.db(|db| get_vector_chunk_ids_by_source(db, source_type, source_id))?.is_empty();
+    if !has_chunks && source_type == SourceType::ProjectDocument {
+        let options = app_handle.db(|db| ChunkingOptions::load(db));
+        let chunks = chunk_text(&document_text, &options);
+        app_handle.db(|db| insert_vector_chunks(db, source_type, source_id, &chunks))?;
+    }
+
+    let chunks = app_handle
+        .db(|db| get_vector_chunks_without_embedding(db, source_type, source_id, model))?;
+    Ok(chunks
+        .into_iter()
+        .map(|chunk| (chunk.id, get_chunk_embedding_text(&document_name, &chunk.chunk_text)))
+        .collect())
+}
+
+async fn embed_sources(
+    app_handle: &AppHandle,
+    sources: &[(SourceType, i64)],
+    provider: &dyn EmbeddingProvider,
+) -> Result<()> {
+    let model = provider.model_name().to_string();
+    let mut chunk_ids = Vec::new();
+    let mut texts = Vec::new();
+    for (source_type, source_id) in sources {
+        for (chunk_id, text) in get_pending_chunks(app_handle, *source_type, *source_id, &model)? {
+            chunk_ids.push(chunk_id);
+            texts.push(text);
+        }
+    }
+    if texts.is_empty() {
+        return Ok(());
+    }
+
+    let vectors = embed_texts(app_handle, provider, &texts).await?;
+    let embeddings: Vec<(i64, String, Vec<f32>)> = chunk_ids
+        .into_iter()
+        .zip(texts.iter())
+        .zip(vectors)
+        .map(|((chunk_id, text), vector)| (chunk_id, content_hash(text), vector))
+        .collect();
+    app_handle.db(|db| save_vector_embeddings(db, &model, &embeddings))?;
+    Ok(())
+}
+
+/// Builds a collection from the stored vectors of `model` and makes it the active one.
+///
+/// The HNSW write lock is held from before the new graph reads the vectors until the setting
+/// points at it. Documents being saved hold the read lock, so none can be missing from the new
+/// collection.
+async fn swap_collection(app_handle: &AppHandle, model: &str) -> Result<String> {
+    let collection_name = format!("chunk_vectors_{}", Local::now().timestamp());
+    let mut hnsw_guard = HNSW.write().await;
+
+    let new_index = open_vector_collection(app_handle, &collection_name, model.to_string())
+        .map_err(|e| anyhow!("Failed to open collection {}: {}", collection_name, e))?;
+    new_index.flush().await?;
+
+    // The collection setting is written last, a crash before it leaves the old index active.
+    app_handle.db(|db| {
+        insert_or_update_setting(
+            db,
+            Setting {
+                setting_key: VECTOR_COLLECTION_MODEL_SETTING.to_string(),
+                setting_value: model.to_string(),
+            },
+        )?;
+        insert_or_update_setting(
+            db,
+            Setting {
+                setting_key: VECTOR_COLLECTION_SETTING.to_string(),
+                setting_value: collection_name.clone(),
+            },
+        )
+    })?;
+    let old_index = hnsw_guard.replace(new_index);
+    drop(hnsw_guard);
+    // Saves and shuts down the old worker once the lookups still using it are done, its files
+    // are removed on the next start
+    drop(old_index);
+
+    let removed = app_handle.db(|db| delete_vector_embeddings_except_model(db, model))?;
+    info!("Removed {} vectors of previous embedding models", removed);
+    Ok(collection_name)
+}
+
+async fn run_reindex_job(
+    app_handle: &AppHandle,
+    job: &ReindexJob,
+    provider: Arc<dyn EmbeddingProvider>,
+) -> Result<Option<String>> {
+    let model = provider.model_name().to_string();
+    let sources = app_handle.db(|db| get_indexed_sources(db))?;
+    let total = sources.len();
+    info!("Re-embedding {} sources with {}", total, model);
+
+    for (batch_index, batch) in sources.chunks(SOURCES_PER_BATCH).enumerate() {
+        if !wait_while_paused(job).await {
+            return Ok(None);
+        }
+        emit(
+            app_handle,
+            "reindex_progress",
+            ReindexProgress {
+                model: model.clone(),
+                processed: batch_index * SOURCES_PER_BATCH,
+                total,
+            },
+        );
+        embed_sources(app_handle, batch, provider.as_ref()).await?;
+    }
+    emit(
+        app_handle,
+        "reindex_progress",
+        ReindexProgress {
+            model: model.clone(),
+            processed: total,
+            total,
+        },
+    );
+
+    if !wait_while_paused(job).await {
+        return Ok(None);
+    }
+    Ok(Some(swap_collection(app_handle, &model).await?))
+}
+
+/// Re-embeds all indexed activities and documents with the configured embedding model into a
+/// fresh collection and swaps it in once complete.
+///
+/// Emits `reindex_progress` while running, then one of `reindex_completed`, `reindex_cancelled`
+/// or `reindex_failed`. Vectors already computed with the model are reused, so a cancelled job
+/// picks up where it stopped when started again.
+#[tauri::command]
+pub async fn start_reindex(app_handle: AppHandle) -> Result<(), String> {
+    let provider = get_embedding_provider(&app_handle)
+        .map_err(|e| format!("Embedding provider unavailable: {}", e))?;
+
+    let job = {
+        let mut current = REINDEX_JOB.lock().unwrap();
+        if current.is_some() {
+            return Err("A re-embedding job is already running".to_string());
+        }
+        let job = Arc::new(ReindexJob {
+            paused: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        });
+        *current = Some(job.clone());
+        job
+    };
+
+    tokio::spawn(async move {
+        let model = provider.model_name().to_string();
+        match run_reindex_job(&app_handle, &job, provider).await {
+            Ok(Some(collection)) => {
+                info!("Re-embedding finished, switched to collection {}", collection);
+                emit(&app_handle, "reindex_completed", ReindexFinished { model, collection });
+            }
+            Ok(None) => {
+                info!("Re-embedding cancelled");
+                emit(&app_handle, "reindex_cancelled", model);
+            }
+            Err(e) => {
+                error!("Re-embedding failed: {}", e);
+                emit(&app_handle, "reindex_failed", e.to_string());
+            }
+        }
+        *REINDEX_JOB.lock().unwrap() = None;
+    });
+    Ok(())
+}
+
+#[tauri::command]
+pub fn pause_reindex(app_handle: AppHandle) -> Result<(), String> {
+    current_job()?.paused.store(true, Ordering::SeqCst);
+    emit(&app_handle, "reindex_paused", true);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn resume_reindex(app_handle: AppHandle) -> Result<(), String> {
+    current_job()?.paused.store(false, Ordering::SeqCst);
+    emit(&app_handle, "reindex_paused", false);
+    Ok(())
+}
+
+/// Stops the job before the next batch of documents. The active collection stays untouched.
+#[tauri::command]
+pub fn cancel_reindex() -> Result<(), String> {
+    current_job()?.cancelled.store(true, Ordering::SeqCst);
+    Ok(())
+}
@@ -0,0 +1,407 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use log::{debug, error, info};
+use rusqlite::Connection;
+use tauri::AppHandle;
+
+use crate::configuration::state::ServiceAccess;
+use crate::database;
+use crate::engine::chunking_engine::DEFAULT_CHUNK_SIZE;
+use crate::engine::embedding_engine::get_embedding_provider;
+use crate::engine::similarity_search_engine::{IndexParams, MAX_TOP_K};
+use crate::entity::search_filter::SearchFilter;
+use crate::entity::vector_chunk::{SourceType, VectorChunk};
+use crate::repository::activity_log_repository::get_activity_text_by_id;
+use crate::repository::chat_db_repository::get_message_by_id;
+use crate::repository::content_fingerprint_repository::get_duplicate_of;
+use crate::repository::full_text_search_repository::{search_activities, search_project_documents};
+use crate::repository::project_repository::get_activity_text_from_project;
+use crate::repository::search_filter_repository::{get_filtered_chunk_ids, get_filtered_sources};
+use crate::repository::vector_db_repository::{get_vector_chunk, get_vector_chunks_by_source};
+
+/// Damping constant of reciprocal rank fusion. 60 is the value from the original paper and
+/// keeps a single first place from outweighing agreement between the rankings.
+pub const RRF_K: f32 = 60.0;
+const KEYWORD_CANDIDATES: usize = 20;
+/// With a filter the keyword search looks this deep before dropping non-matching sources.
+const FILTERED_KEYWORD_CANDIDATES: usize = 2000;
+const VECTOR_CANDIDATES_PER_RESULT: usize = 4;
+const MAX_QUERY_TERMS: usize = 32;
+
+/// A piece of text returned by the retriever, together with the source it was cut from.
+#[derive(Debug, Clone)]
+pub struct RetrievedChunk {
+    /// `None` when the source is too short to have been split into vector chunks.
+    pub chunk_id: Option<i64>,
+    pub source_type: SourceType,
+    pub source_id: i64,
+    pub document_name: String,
+    pub text: String,
+    /// Reciprocal rank fusion score of the source, higher is better.
+    pub score: f32,
+}
+
+/// Returns the title and text of a source through the repository owning it.
+pub fn get_source_document(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<Option<(String, String)>, rusqlite::Error> {
+    let document = match source_type {
+        SourceType::Activity => get_activity_text_by_id(db, source_id, Some(DEFAULT_CHUNK_SIZE))?
+            .filter(|(window_title, _)| !window_title.is_empty()),
+        SourceType::ProjectDocument => get_activity_text_from_project(db, source_id)?,
+        SourceType::ChatMessage => get_message_by_id(db, source_id)?
+            .map(|(chat_name, message)| (chat_name, message.content)),
+    };
+    Ok(document)
+}
+
+/// Splits a user prompt into lowercase search terms, dropping punctuation around words.
+pub fn query_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = Vec::new();
+    for word in query.split_whitespace() {
+        let term = word
+            .trim_matches(|c: char| !c.is_alphanumeric())
+            .replace('"', "")
+            .to_lowercase();
+        if term.chars().count() < 2 || terms.contains(&term) {
+            continue;
+        }
+        terms.push(term);
+        if terms.len() == MAX_QUERY_TERMS {
+            break;
+        }
+    }
+    terms
+}
+
+/// Builds an FTS5 query matching any of the terms. Every term is quoted, so identifiers such as
+/// `ABC-123` or `get_user` are searched as phrases instead of being parsed as FTS5 syntax.
+pub fn build_fts_query(terms: &[String]) -> Option<String> {
+    if terms.is_empty() {
+        return None;
+    }
+    Some(
+        terms
+            .iter()
+            .map(|term| format!("\"{}\"", term))
+            .collect::<Vec<_>>()
+            .join(" OR "),
+    )
+}
+
+/// Merges several rankings into one. Every item scores `1 / (k + rank)` in each ranking it
+/// appears in, so items found by more than one retriever float to the top.
+pub fn reciprocal_rank_fusion<T: Eq + Hash + Clone>(rankings: &[Vec<T>], k: f32) -> Vec<(T, f32)> {
+    let mut scores: HashMap<T, f32> = HashMap::new();
+    let mut first_seen: Vec<T> = Vec::new();
+    for ranking in rankings {
+        for (rank, item) in ranking.iter().enumerate() {
+            let score = scores.entry(item.clone()).or_insert_with(|| {
+                first_seen.push(item.clone());
+                0.0
+            });
+            *score += 1.0 / (k + rank as f32 + 1.0);
+        }
+    }
+
+    let mut fused: Vec<(T, f32)> = first_seen
+        .into_iter()
+        .map(|item| {
+            let score = scores[&item];
+            (item, score)
+        })
+        .collect();
+    // The sort is stable, so ties keep the order in which the items were first seen.
+    fused.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    fused
+}
+
+fn count_matching_terms(text: &str, terms: &[String]) -> usize {
+    let text = text.to_lowercase();
+    terms.iter().filter(|term| text.contains(term.as_str())).count()
+}
+
+/// Chunk ids closest to `query` in the HNSW index, closest first. Only the chunks of
+/// `allowed_ids` are searched when it's set.
+async fn vector_search(
+    app_handle: &AppHandle,
+    query: &str,
+    k: usize,
+    allowed_ids: Option<HashSet<usize>>,
+) -> Result<Vec<i64>, String> {
+    let embedding_provider = get_embedding_provider(app_handle)
+        .map_err(|e| format!("Embedding provider unavailable: {}", e))?;
+    let params = app_handle.db(|db| IndexParams::load(db));
+    if let Some(index_model) = app_handle.db(|db| database::get_vector_collection_model(db)) {
+        if index_model != embedding_provider.model_name() {
+            return Err(format!(
+                "Vector index holds {} vectors and is waiting to be re-embedded with {}",
+                index_model,
+                embedding_provider.model_name()
+            ));
+        }
+    }
+
+    info!("Getting database instance");
+    let db = database::get_vector_db(app_handle)
+        .await
+        .map_err(|e| format!("Failed to open vector index: {}", e))?;
+    info!("Initiating similarity search...");
+
+    let similar_ids_with_distances = db
+        .top_k(query, k, params.ef_search, allowed_ids, embedding_provider.as_ref())
+        .await
+        .map_err(|e| format!("Similarity search failed: {}", e))?;
+    Ok(similar_ids_with_distances
+        .into_iter()
+        .map(|(chunk_id, _)| chunk_id as i64)
+        .collect())
+}
+
+/// Sources ordered by the fused retrievers, best first.
+pub struct RankedSources {
+    pub terms: Vec<String>,
+    pub ranking: Vec<((SourceType, i64), f32)>,
+    /// Chunks the vector search returned, grouped by source and closest first.
+    vector_hits: HashMap<(SourceType, i64), Vec<VectorChunk>>,
+}
+
+/// Ranks sources by BM25 over the FTS5 tables and by vector similarity of their chunks and
+/// fuses both rankings. Every retriever contributes up to its number of candidates.
+///
+/// Both retrievers only consider sources matching `filter`, so a narrow filter still fills the
+/// ranking. If one of the retrievers fails the other one is used on its own.
+pub async fn rank_sources(
+    app_handle: &AppHandle,
+    query: &str,
+    keyword_candidates: usize,
+    vector_candidates: usize,
+    filter: &SearchFilter,
+) -> Result<RankedSources, String> {
+    let terms = query_terms(query);
+
+    let (allowed_sources, allowed_chunk_ids) = if filter.is_empty() {
+        (None, None)
+    } else {
+        let (sources, chunk_ids) = app_handle
+            .db(|db| -> Result<_, rusqlite::Error> {
+                Ok((get_filtered_sources(db, filter)?, get_filtered_chunk_ids(db, filter)?))
+            })
+            .map_err(|e| format!("Failed to apply search filter: {}", e))?;
+        debug!("Filter {:?} matches {} sources", filter, sources.len());
+        (
+            Some(sources.into_iter().collect::<HashSet<(SourceType, i64)>>()),
+            Some(chunk_ids.into_iter().map(|id| id as usize).collect::<HashSet<usize>>()),
+        )
+    };
+    let keyword_limit = match allowed_sources {
+        Some(_) => keyword_candidates.max(FILTERED_KEYWORD_CANDIDATES),
+        None => keyword_candidates,
+    };
+
+    let (document_ranking, activity_ranking) = match build_fts_query(&terms) {
+        Some(match_query) => app_handle.db(|db| {
+            let documents = search_project_documents(db, &match_query, keyword_limit)
+                .unwrap_or_else(|e| {
+                    error!("Keyword search over documents failed: {}", e);
+                    Vec::new()
+                });
+            let activities = search_activities(db, &match_query, keyword_limit)
+                .unwrap_or_else(|e| {
+                    error!("Keyword search over activities failed: {}", e);
+                    Vec::new()
+                });
+            (documents, activities)
+        }),
+        None => (Vec::new(), Vec::new()),
+    };
+    let is_allowed = |source: &(SourceType, i64)| {
+        allowed_sources
+            .as_ref()
+            .map_or(true, |allowed| allowed.contains(source))
+    };
+    let document_ranking: Vec<(SourceType, i64)> = document_ranking
+        .into_iter()
+        .map(|id| (SourceType::ProjectDocument, id))
+        .filter(is_allowed)
+        .take(keyword_candidates)
+        .collect();
+    let activity_ranking: Vec<(SourceType, i64)> = activity_ranking
+        .into_iter()
+        .map(|id| (SourceType::Activity, id))
+        .filter(is_allowed)
+        .take(keyword_candidates)
+        .collect();
+
+    let vector_candidates = vector_candidates.min(MAX_TOP_K);
+    let chunk_ids = vector_search(app_handle, query, vector_candidates, allowed_chunk_ids)
+        .await
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            Vec::new()
+        });
+
+    // A source ranks as high as its best chunk.
+    let mut vector_ranking: Vec<(SourceType, i64)> = Vec::new();
+    let mut vector_hits: HashMap<(SourceType, i64), Vec<VectorChunk>> = HashMap::new();
+    for chunk_id in chunk_ids {
+        match app_handle.db(|db| get_vector_chunk(db, chunk_id)) {
+            Ok(Some(chunk)) => {
+                let source = (chunk.source_type, chunk.source_id);
+                if !vector_hits.contains_key(&source) {
+                    vector_ranking.push(source);
+                }
+                vector_hits.entry(source).or_default().push(chunk);
+            }
+            Ok(None) => debug!("No chunk found for vector ID {}", chunk_id),
+            Err(e) => error!("Failed to retrieve chunk for ID {}: {}", chunk_id, e),
+        }
+    }
+
+    let mut ranking =
+        reciprocal_rank_fusion(&[vector_ranking, document_ranking, activity_ranking], RRF_K);
+    // Near-duplicates of a source that ranks higher add nothing new.
+    let mut seen_clusters: HashSet<(SourceType, i64)> = HashSet::new();
+    ranking.retain(|((source_type, source_id), _)| {
+        let canonical_id = app_handle
+            .db(|db| get_duplicate_of(db, *source_type, *source_id))
+            .unwrap_or_else(|e| {
+                error!(
+                    "Failed to look up duplicates of {} {}: {}",
+                    source_type.as_str(),
+                    source_id,
+                    e
+                );
+                None
+            })
+            .unwrap_or(*source_id);
+        seen_clusters.insert((*source_type, canonical_id))
+    });
+    debug!("Fused ranking: {:?}", ranking);
+    Ok(RankedSources {
+        terms,
+        ranking,
+        vector_hits,
+    })
+}
+
+impl RankedSources {
+    /// The chunks that made a source rank: its vector hits, or for keyword hits the chunk
+    /// containing most of the query terms. Empty when the source has no chunks.
+    pub fn take_source_chunks(
+        &mut self,
+        app_handle: &AppHandle,
+        source_type: SourceType,
+        source_id: i64,
+    ) -> Vec<VectorChunk> {
+        match self.vector_hits.remove(&(source_type, source_id)) {
+            Some(hits) => hits,
+            None => app_handle
+                .db(|db| get_vector_chunks_by_source(db, source_type, source_id))
+                .unwrap_or_default()
+                .into_iter()
+                .rev()
+                .max_by_key(|chunk| count_matching_terms(&chunk.chunk_text, &self.terms))
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+/// Hybrid retrieval: returns up to `top_k` chunks of the best sources `rank_sources` finds.
+pub async fn retrieve_chunks(
+    app_handle: &AppHandle,
+    query: &str,
+    top_k: usize,
+    filter: &SearchFilter,
+) -> Result<Vec<RetrievedChunk>, String> {
+    let mut ranked = rank_sources(
+        app_handle,
+        query,
+        KEYWORD_CANDIDATES,
+        top_k * VECTOR_CANDIDATES_PER_RESULT,
+        filter,
+    )
+    .await?;
+
+    let mut chunks = Vec::new();
+    for ((source_type, source_id), score) in ranked.ranking.clone() {
+        if chunks.len() >= top_k {
+            break;
+        }
+
+        let document = app_handle
+            .db(|db| get_source_document(db, source_type, source_id))
+            .unwrap_or_else(|e| {
+                error!("Failed to retrieve {} {}: {}", source_type.as_str(), source_id, e);
+                None
+            });
+        let Some((document_name, document_text)) = document else {
+            continue;
+        };
+
+        let source_chunks = ranked.take_source_chunks(app_handle, source_type, source_id);
+        if source_chunks.is_empty() {
+            chunks.push(RetrievedChunk {
+                chunk_id: None,
+                source_type,
+                source_id,
+                document_name,
+                text: document_text.chars().take(DEFAULT_CHUNK_SIZE).collect(),
+                score,
+            });
+            continue;
+        }
+
+        for chunk in source_chunks.into_iter().take(top_k - chunks.len()) {
+            chunks.push(RetrievedChunk {
+                chunk_id: Some(chunk.id),
+                source_type,
+                source_id,
+                document_name: document_name.clone(),
+                text: chunk.chunk_text,
+                score,
+            });
+        }
+    }
+
+    debug!("Retrieved chunks: {:?}", chunks);
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_fts_query, query_terms, reciprocal_rank_fusion, RRF_K};
+
+    #[test]
+    fn query_terms_strip_punctuation_and_duplicates() {
+        let terms = query_terms("What did \"Bob\" say about JIRA-1234? Jira-1234, a fn get_user()!");
+        assert_eq!(
+            terms,
+            vec!["what", "did", "bob", "say", "about", "jira-1234", "fn", "get_user"]
+        );
+    }
+
+    #[test]
+    fn fts_query_quotes_every_term() {
+        let terms = query_terms("ticket OR-42 NEAR done");
+        assert_eq!(
+            build_fts_query(&terms).unwrap(),
+            "\"ticket\" OR \"or-42\" OR \"near\" OR \"done\""
+        );
+        assert_eq!(build_fts_query(&query_terms("? !")), None);
+    }
+
+    #[test]
+    fn fusion_prefers_items_found_by_both_rankings() {
+        let vector = vec!["a", "b", "c"];
+        let keyword = vec!["d", "c"];
+        let fused = reciprocal_rank_fusion(&[vector, keyword], RRF_K);
+        let order: Vec<&str> = fused.iter().map(|(item, _)| *item).collect();
+        assert_eq!(order, vec!["c", "a", "d", "b"]);
+        assert!((fused[1].1 - 1.0 / 61.0).abs() < f32::EPSILON);
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashSet;
+
+use log::{error, info};
+use serde::Serialize;
+use tauri::AppHandle;
+
+use crate::configuration::state::ServiceAccess;
+use crate::database;
+use crate::engine::embedding_engine::{embed_texts, get_embedding_provider};
+use crate::engine::retrieval_engine::{get_source_document, rank_sources};
+use crate::engine::similarity_search_engine::{IndexParams, MAX_TOP_K};
+use crate::entity::search_filter::SearchFilter;
+use crate::entity::vector_chunk::SourceType;
+use crate::repository::activity_log_repository::get_chunk_embedding_text;
+use crate::repository::project_repository::get_activity_text_from_project;
+use crate::repository::search_filter_repository::get_source_details;
+use crate::repository::vector_db_repository::{
+    get_vector_chunk, get_vector_chunk_ids_by_source, get_vector_embeddings_by_source,
+};
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+const SNIPPET_CHARS: usize = 240;
+/// Share of the snippet shown before the first match.
+const SNIPPET_LEAD_DIVISOR: usize = 4;
+const DEFAULT_RELATED_NOTES: usize = 5;
+/// Neighbouring chunks looked up per related note, long notes take several of them.
+const RELATED_CANDIDATES_PER_NOTE: usize = 4;
+
+/// A piece of a snippet, `highlighted` parts matched a query term.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SnippetSegment {
+    pub text: String,
+    pub highlighted: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NoteSearchHit {
+    pub source_type: SourceType,
+    pub source_id: i64,
+    /// `None` when the source is too short to have been split into vector chunks.
+    pub chunk_id: Option<i64>,
+    pub title: String,
+    pub project_id: Option<i64>,
+    pub project_name: Option<String>,
+    pub date: Option<String>,
+    /// Reciprocal rank fusion score, higher is better.
+    pub score: f32,
+    pub snippet: Vec<SnippetSegment>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NoteSearchPage {
+    pub hits: Vec<NoteSearchHit>,
+    pub page: usize,
+    pub page_size: usize,
+    pub has_more: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RelatedNote {
+    pub source_type: SourceType,
+    pub source_id: i64,
+    pub title: String,
+    pub project_id: Option<i64>,
+    pub project_name: Option<String>,
+    pub date: Option<String>,
+    /// Cosine similarity of the closest chunk, 1 means identical.
+    pub similarity: f32,
+}
+
+/// Cuts a window of about `max_chars` characters around the first query term out of `text` and
+/// splits it into plain and highlighted segments. Whitespace is collapsed to single spaces.
+pub fn build_snippet(text: &str, terms: &[String], max_chars: usize) -> Vec<SnippetSegment> {
+    let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let chars: Vec<char> = collapsed.chars().collect();
+    // One lowercase char per char keeps the positions of both in sync.
+    let lower: Vec<char> = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
+    let terms: Vec<Vec<char>> = terms
+        .iter()
+        .map(|term| term.chars().collect::<Vec<char>>())
+        .filter(|term| !term.is_empty())
+        .collect();
+    let match_at = |position: usize| {
+        terms
+            .iter()
+            .filter(|term| lower[position..].starts_with(term))
+            .map(|term| term.len())
+            .max()
+    };
+
+    let first_match = (0..lower.len()).find(|position| match_at(*position).is_some());
+    let mut start = first_match.map_or(0, |position| {
+        position.saturating_sub(max_chars / SNIPPET_LEAD_DIVISOR)
+    });
+    // Start at a word boundary unless that would skip past the match.
+    if start > 0 {
+        if let Some(offset) = chars[start..].iter().position(|c| *c == ' ') {
+            if first_match.map_or(true, |position| start + offset < position) {
+                start += offset + 1;
+            }
+        }
+    }
+    let end = (start + max_chars).min(chars.len());
+
+    let mut segments: Vec<SnippetSegment> = Vec::new();
+    let mut push = |text: String, highlighted: bool| match segments.last_mut() {
+        Some(last) if last.highlighted == highlighted => last.text.push_str(&text),
+        _ => segments.push(SnippetSegment { text, highlighted }),
+    };
+    if start > 0 {
+        push("…".to_string(), false);
+    }
+    let mut position = start;
+    while position < end {
+        match match_at(position) {
+            Some(length) => {
+                let match_end = (position + length).min(end);
+                push(chars[position..match_end].iter().collect(), true);
+                position = match_end;
+            }
+            None => {
+                push(chars[position].to_string(), false);
+                position += 1;
+            }
+        }
+    }
+    if end < chars.len() {
+        push("…".to_string(), false);
+    }
+    segments
+}
+
+/// Keyword and semantic search over activities and project documents, without calling a
+/// language model. `page` starts at 0.
+///
+/// Hits are one per source, ranked like the chat retrieval. Deeper pages rely on keyword matches
+/// only, the vector search contributes at most `MAX_TOP_K` sources.
+#[tauri::command]
+pub async fn search_notes(
+    app_handle: AppHandle,
+    query: String,
+    filter: Option<SearchFilter>,
+    page: Option<usize>,
+    page_size: Option<usize>,
+) -> Result<NoteSearchPage, String> {
+    let page = page.unwrap_or(0);
+    let page_size = page_size.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let filter = filter.unwrap_or_default();
+    let offset = page * page_size;
+    info!("Searching notes, page {} of {} hits: {}", page, page_size, query);
+
+    if query.trim().is_empty() {
+        return Ok(NoteSearchPage {
+            hits: Vec::new(),
+            page,
+            page_size,
+            has_more: false,
+        });
+    }
+
+    // One extra candidate tells whether there is a next page.
+    let candidates = offset + page_size + 1;
+    let mut ranked = rank_sources(
+        &app_handle,
+        &query,
+        candidates,
+        candidates.min(MAX_TOP_K),
+        &filter,
+    )
+    .await?;
+    let has_more = ranked.ranking.len() > offset + page_size;
+
+    let mut hits = Vec::new();
+    let page_sources: Vec<_> = ranked
+        .ranking
+        .iter()
+        .skip(offset)
+        .take(page_size)
+        .cloned()
+        .collect();
+    for ((source_type, source_id), score) in page_sources {
+        let document = app_handle
+            .db(|db| get_source_document(db, source_type, source_id))
+            .unwrap_or_else(|e| {
+                error!("Failed to retrieve {} {}: {}", source_type.as_str(), source_id, e);
+                None
+            });
+        let Some((title, text)) = document else {
+            continue;
+        };
+        let details = app_handle
+            .db(|db| get_source_details(db, source_type, source_id))
+            .unwrap_or_else(|e| {
+                error!(
+                    "Failed to retrieve details of {} {}: {}",
+                    source_type.as_str(),
+                    source_id,
+                    e
+                );
+                None
+            })
+            .unwrap_or_default();
+
+        let chunk = ranked
+            .take_source_chunks(&app_handle, source_type, source_id)
+            .into_iter()
+            .next();
+        let snippet_text = chunk
+            .as_ref()
+            .map_or(text.as_str(), |chunk| chunk.chunk_text.as_str());
+        hits.push(NoteSearchHit {
+            source_type,
+            source_id,
+            chunk_id: chunk.as_ref().map(|chunk| chunk.id),
+            title,
+            project_id: details.project_id,
+            project_name: details.project_name,
+            date: details.date,
+            score,
+            snippet: build_snippet(snippet_text, &ranked.terms, SNIPPET_CHARS),
+        });
+    }
+
+    Ok(NoteSearchPage {
+        hits,
+        page,
+        page_size,
+        has_more,
+    })
+}
+
+/// Mean of the vectors scaled to unit length, so a note with many chunks is compared by its
+/// overall topic. `None` without vectors or when their dimensions differ.
+pub fn mean_vector(vectors: &[Vec<f32>]) -> Option<Vec<f32>> {
+    let dimension = vectors.first()?.len();
+    if vectors.iter().any(|vector| vector.len() != dimension) {
+        return None;
+    }
+    let mut mean = vec![0.0f32; dimension];
+    for vector in vectors {
+        for (sum, value) in mean.iter_mut().zip(vector) {
+            *sum += value;
+        }
+    }
+    let norm = mean.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        mean.iter_mut().for_each(|value| *value /= norm);
+    }
+    Some(mean)
+}
+
+/// Activities and project documents whose text is closest to the project document
+/// `document_id`, most similar first. The document itself is left out.
+///
+/// The stored vectors of the document are used when it's indexed, otherwise its text is embedded.
+#[tauri::command]
+pub async fn get_related_notes(
+    app_handle: AppHandle,
+    document_id: i64,
+    limit: Option<usize>,
+) -> Result<Vec<RelatedNote>, String> {
+    let limit = limit.unwrap_or(DEFAULT_RELATED_NOTES).clamp(1, MAX_TOP_K);
+    let source_type = SourceType::ProjectDocument;
+    let (document_name, document_text) = app_handle
+        .db(|db| get_activity_text_from_project(db, document_id))
+        .map_err(|e| format!("Failed to load document {}: {}", document_id, e))?
+        .ok_or(format!("Document {} not found", document_id))?;
+
+    let provider = get_embedding_provider(&app_handle)
+        .map_err(|e| format!("Embedding provider unavailable: {}", e))?;
+    let model = app_handle
+        .db(|db| database::get_vector_collection_model(db))
+        .unwrap_or(provider.model_name().to_string());
+
+    let (stored_vectors, own_chunk_ids) = app_handle
+        .db(|db| -> Result<_, rusqlite::Error> {
+            Ok((
+                get_vector_embeddings_by_source(db, source_type, document_id, &model)?,
+                get_vector_chunk_ids_by_source(db, source_type, document_id)?,
+            ))
+        })
+        .map_err(|e| format!("Failed to load vectors of document {}: {}", document_id, e))?;
+    let stored_vectors: Vec<Vec<f32>> = stored_vectors
+        .into_iter()
+        .map(|(_, vector)| vector)
+        .collect();
+
+    let query_vector = match mean_vector(&stored_vectors) {
+        Some(vector) => vector,
+        None => {
+            if provider.model_name() != model {
+                return Err(format!(
+                    "Vector index holds {} vectors and is waiting to be re-embedded with {}",
+                    model,
+                    provider.model_name()
+                ));
+            }
+            let text = get_chunk_embedding_text(&document_name, &document_text);
+            embed_texts(&app_handle, provider.as_ref(), &[text])
+                .await
+                .map_err(|e| format!("Failed to embed document {}: {}", document_id, e))?
+                .pop()
+                .ok_or("Embedding provider returned no vector".to_string())?
+        }
+    };
+
+    let ef_search = app_handle.db(|db| IndexParams::load(db)).ef_search;
+    let excluded_ids: HashSet<usize> = own_chunk_ids.into_iter().map(|id| id as usize).collect();
+    let index = database::get_vector_db(&app_handle)
+        .await
+        .map_err(|e| format!("Failed to open vector index: {}", e))?;
+    let candidates = index
+        .nearest(
+            query_vector,
+            (limit * RELATED_CANDIDATES_PER_NOTE).min(MAX_TOP_K),
+            ef_search,
+            excluded_ids,
+        )
+        .await
+        .map_err(|e| format!("Similarity search failed: {}", e))?;
+
+    // A note is as similar as its closest chunk.
+    let mut seen: HashSet<(SourceType, i64)> = HashSet::new();
+    seen.insert((source_type, document_id));
+    let mut related = Vec::new();
+    for (chunk_id, distance) in candidates {
+        if related.len() >= limit {
+            break;
+        }
+        let chunk = match app_handle.db(|db| get_vector_chunk(db, chunk_id as i64)) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Failed to retrieve chunk for ID {}: {}", chunk_id, e);
+                continue;
+            }
+        };
+        if !seen.insert((chunk.source_type, chunk.source_id)) {
+            continue;
+        }
+        let Ok(Some((title, _))) =
+            app_handle.db(|db| get_source_document(db, chunk.source_type, chunk.source_id))
+        else {
+            continue;
+        };
+        let details = app_handle
+            .db(|db| get_source_details(db, chunk.source_type, chunk.source_id))
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        related.push(RelatedNote {
+            source_type: chunk.source_type,
+            source_id: chunk.source_id,
+            title,
+            project_id: details.project_id,
+            project_name: details.project_name,
+            date: details.date,
+            similarity: 1.0 - distance,
+        });
+    }
+    Ok(related)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_snippet, mean_vector, SnippetSegment};
+
+    fn render(segments: &[SnippetSegment]) -> String {
+        segments
+            .iter()
+            .map(|segment| {
+                if segment.highlighted {
+                    format!("[{}]", segment.text)
+                } else {
+                    segment.text.clone()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn snippet_highlights_terms_case_insensitively() {
+        let terms = vec!["budget".to_string(), "q3".to_string()];
+        let snippet = build_snippet("The Q3  budget\nis final.", &terms, 100);
+        assert_eq!(render(&snippet), "The [Q3] [budget] is final.");
+    }
+
+    #[test]
+    fn snippet_is_cut_around_the_first_match() {
+        let text = format!("{} needle {}", "lorem ".repeat(50), "ipsum ".repeat(50));
+        let snippet = render(&build_snippet(&text, &["needle".to_string()], 40));
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(snippet.contains("[needle]"));
+        assert!(snippet.starts_with("…lorem"));
+    }
+
+    #[test]
+    fn snippet_without_match_starts_at_the_beginning() {
+        let snippet = render(&build_snippet("alpha beta gamma", &["delta".to_string()], 10));
+        assert_eq!(snippet, "alpha beta…");
+    }
+
+    #[test]
+    fn mean_vector_has_unit_length() {
+        let mean = mean_vector(&[vec![1.0, 0.0], vec![0.0, 1.0]]).unwrap();
+        let expected = 1.0 / 2.0f32.sqrt();
+        assert!((mean[0] - expected).abs() < 1e-6);
+        assert!((mean[1] - expected).abs() < 1e-6);
+        assert_eq!(mean_vector(&[vec![1.0], vec![1.0, 2.0]]), None);
+        assert_eq!(mean_vector(&[]), None);
+    }
+}
@@ -1,202 +1,948 @@
-use std::fs::create_dir_all;
+use std::collections::HashSet;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Error, Result};
 use hnsw_rs::prelude::*;
 use log::{debug, error, info};
+use ouroboros::self_referencing;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{
+    Mutex, OwnedRwLockWriteGuard, RwLock, RwLockMappedWriteGuard, RwLockReadGuard, RwLockWriteGuard,
+};
 
-use crate::repository::vector_db_repository::compute_vector_embedding;
+use crate::engine::embedding_engine::{truncate_for_embedding, EmbeddingProvider};
+use crate::repository::settings_repository::get_setting;
 
 pub const TOPK: usize = 3;
-pub const MAX_NB_CONNECTION: usize = TOPK;
+pub const MAX_NB_CONNECTION: usize = 16;
 pub const MAX_ELEMENTS: usize = 100_000;
-pub const MAX_LAYERS: usize = 24;
+pub const MAX_LAYERS: usize = 16;
 pub const EF_CONSTRUCTION: usize = 400;
+pub const EF_SEARCH: usize = 64;
+/// Upper bound of the result count, also keeps the search beam within reason.
+pub const MAX_TOP_K: usize = 50;
+/// A rebuild reports its progress every this many points.
+const REBUILD_PROGRESS_STEP: usize = 500;
+
+/// Upper bound of the widened beam of filtered lookups.
+const MAX_FILTERED_EF_SEARCH: usize = 4096;
 
 pub const MAX_INFLIGHT_COMMANDS: usize = 100;
 
-fn get_db<'a>() -> Hnsw<'a, f32, DistCosine> {
+/// The index is dumped once this many changes were journaled since the last dump, which keeps
+/// the replay on the next open short. Closing the index dumps it as well.
+const CHECKPOINT_CHANGES: usize = 1000;
+
+/// Compaction kicks in once at least this many vectors are dead...
+const COMPACTION_MIN_TOMBSTONES: usize = 64;
+/// ...and they make up at least this share of the graph.
+const COMPACTION_TOMBSTONE_RATIO: f32 = 0.2;
+
+/// Graph and search parameters of the index, read from the settings.
+///
+/// `max_nb_connection` (M), `ef_construction` and `max_elements` are fixed when the graph is
+/// created, so changing them requires a rebuild. `ef_search` and `top_k` apply to the next lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct IndexParams {
+    pub max_nb_connection: usize,
+    pub ef_construction: usize,
+    pub ef_search: usize,
+    pub max_elements: usize,
+    pub top_k: usize,
+}
+
+impl Default for IndexParams {
+    fn default() -> Self {
+        IndexParams {
+            max_nb_connection: MAX_NB_CONNECTION,
+            ef_construction: EF_CONSTRUCTION,
+            ef_search: EF_SEARCH,
+            max_elements: MAX_ELEMENTS,
+            top_k: TOPK,
+        }
+    }
+}
+
+impl IndexParams {
+    pub fn load(db: &Connection) -> Self {
+        let parse = |setting_key: &str, default: usize| {
+            get_setting(db, setting_key)
+                .ok()
+                .and_then(|setting| setting.setting_value.parse::<usize>().ok())
+                .filter(|value| *value > 0)
+                .unwrap_or(default)
+        };
+        IndexParams {
+            // hnsw_rs refuses graphs with more than 256 neighbours per point
+            max_nb_connection: parse("hnsw_m", MAX_NB_CONNECTION).min(256),
+            ef_construction: parse("hnsw_ef_construction", EF_CONSTRUCTION),
+            ef_search: parse("hnsw_ef_search", EF_SEARCH),
+            max_elements: parse("hnsw_max_elements", MAX_ELEMENTS),
+            top_k: parse("retrieval_top_k", TOPK).min(MAX_TOP_K),
+        }
+    }
+}
+
+fn get_db<'a>(params: &IndexParams) -> Hnsw<'a, f32, DistCosine> {
     Hnsw::new(
-        MAX_NB_CONNECTION,
-        MAX_ELEMENTS,
+        params.max_nb_connection,
+        params.max_elements,
         MAX_LAYERS,
-        EF_CONSTRUCTION,
+        params.ef_construction,
         DistCosine,
     )
 }
 
+impl IndexParams {
+    fn graph_params(&self) -> GraphParams {
+        GraphParams {
+            max_nb_connection: self.max_nb_connection,
+            ef_construction: self.ef_construction,
+            max_elements: self.max_elements,
+        }
+    }
+}
+
+/// The parameters a dumped graph was built with, stored next to the dump.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct GraphParams {
+    max_nb_connection: usize,
+    ef_construction: usize,
+    max_elements: usize,
+}
+
+/// Reads every stored `(id, vector)` pair, used to rebuild the graph when its dump is unusable.
+pub type VectorLoader = Box<dyn FnOnce() -> Result<Vec<(usize, Vec<f32>)>> + Send>;
+
+/// Progress of a rebuild, in points inserted into the new graph.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RebuildProgress {
+    pub processed: usize,
+    pub total: usize,
+}
+
+/// Ids a lookup may return, deleted vectors are always skipped.
+#[derive(Debug, Default)]
+struct IdFilter {
+    /// Only these ids when set.
+    allowed: Option<HashSet<usize>>,
+    excluded: HashSet<usize>,
+}
+
+impl IdFilter {
+    fn accepts(&self, id: &usize) -> bool {
+        !self.excluded.contains(id)
+            && self
+                .allowed
+                .as_ref()
+                .map_or(true, |allowed| allowed.contains(id))
+    }
+}
+
+/// A graph loaded from a dump. Its points may borrow from the reloader, so both are kept and
+/// dropped together.
+#[self_referencing]
+struct LoadedHnsw {
+    reloader: HnswIo,
+    #[borrows(mut reloader)]
+    #[not_covariant]
+    db: Hnsw<'this, f32, DistCosine>,
+}
+
+/// A graph loaded from a dump or built in memory.
+enum GraphDb {
+    Loaded(LoadedHnsw),
+    Built(Hnsw<'static, f32, DistCosine>),
+}
+
+impl GraphDb {
+    fn load(db_path: &str, collection_name: &str) -> Result<Self> {
+        let loaded = LoadedHnsw::try_new(
+            HnswIo::new(Path::new(db_path), collection_name),
+            |reloader| {
+                reloader
+                    .load_hnsw::<f32, DistCosine>()
+                    .map_err(|e| anyhow!("{}", e))
+            },
+        )?;
+        Ok(GraphDb::Loaded(loaded))
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&Hnsw<f32, DistCosine>) -> R) -> R {
+        match self {
+            GraphDb::Loaded(loaded) => loaded.with_db(f),
+            GraphDb::Built(db) => f(db),
+        }
+    }
+}
+
+/// The graph and the ids deleted from it. Lookups read it directly, changes go through the
+/// worker, which is the only one writing it.
+struct Graph {
+    db: GraphDb,
+    tombstones: HashSet<usize>,
+}
+
+/// `None` once the index is closed.
+type SharedGraph = Arc<RwLock<Option<Graph>>>;
+
+async fn read_graph(graph: &RwLock<Option<Graph>>) -> Result<RwLockReadGuard<'_, Graph>> {
+    RwLockReadGuard::try_map(graph.read().await, Option::as_ref)
+        .map_err(|_| anyhow!("HNSW index is closed"))
+}
+
+async fn write_graph(graph: &RwLock<Option<Graph>>) -> Result<RwLockMappedWriteGuard<'_, Graph>> {
+    RwLockWriteGuard::try_map(graph.write().await, Option::as_mut)
+        .map_err(|_| anyhow!("HNSW index is closed"))
+}
+
 enum HnswCommand {
     Save,
+    Flush(Sender<Result<(), Error>>),
     Add(Vec<f32>, usize),
-    Lookup(Vec<f32>, usize, Sender<Result<Vec<(usize, f32)>, Error>>),
+    Remove(Vec<usize>),
+    Replace(Vec<usize>, Vec<(Vec<f32>, usize)>),
+    Rebuild(IndexParams, Sender<Result<RebuildProgress, Error>>),
     Shutdown,
 }
 
+fn tombstones_path(db_path: &str, collection_name: &str) -> PathBuf {
+    Path::new(db_path).join(format!("{}.hnsw.tombstones", collection_name))
+}
+
+fn params_path(db_path: &str, collection_name: &str) -> PathBuf {
+    Path::new(db_path).join(format!("{}.hnsw.params", collection_name))
+}
+
+fn load_graph_params(db_path: &str, collection_name: &str) -> Option<GraphParams> {
+    std::fs::read_to_string(params_path(db_path, collection_name))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+}
+
+fn save_graph_params(db_path: &str, collection_name: &str, params: &GraphParams) -> Result<()> {
+    write_atomic(
+        &params_path(db_path, collection_name),
+        serde_json::to_string(params)?.as_bytes(),
+    )
+}
+
+fn load_tombstones(db_path: &str, collection_name: &str) -> HashSet<usize> {
+    std::fs::read_to_string(tombstones_path(db_path, collection_name))
+        .ok()
+        .and_then(|content| serde_json::from_str::<Vec<usize>>(&content).ok())
+        .map(|ids| ids.into_iter().collect())
+        .unwrap_or_default()
+}
+
+fn save_tombstones(db_path: &str, collection_name: &str, tombstones: &HashSet<usize>) -> Result<()> {
+    let ids: Vec<&usize> = tombstones.iter().collect();
+    write_atomic(
+        &tombstones_path(db_path, collection_name),
+        serde_json::to_string(&ids)?.as_bytes(),
+    )
+}
+
+/// Writes a file next to its destination, syncs it and renames it into place, so readers see
+/// either the old or the new content.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Makes renames in the directory durable. Directories can't be opened on Windows, where
+/// renames are durable once they return.
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// A change to the graph since the last dump.
+#[derive(Debug, Clone, PartialEq)]
+enum JournalEntry {
+    Add(usize, Vec<f32>),
+    Remove(usize),
+}
+
+const JOURNAL_ADD: u8 = 1;
+const JOURNAL_REMOVE: u8 = 2;
+/// Length and checksum in front of every record.
+const JOURNAL_HEADER_LEN: usize = 12;
+
+fn journal_checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Appends `entries` as records of `[payload length: u32][checksum: u64][payload]`, all little
+/// endian, so a record torn by a crash is recognized when reading.
+fn encode_journal_entries(entries: &[JournalEntry], buffer: &mut Vec<u8>) {
+    for entry in entries {
+        let mut payload = Vec::new();
+        match entry {
+            JournalEntry::Add(id, vector) => {
+                payload.push(JOURNAL_ADD);
+                payload.extend_from_slice(&(*id as u64).to_le_bytes());
+                payload.extend_from_slice(&(vector.len() as u32).to_le_bytes());
+                for value in vector {
+                    payload.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            JournalEntry::Remove(id) => {
+                payload.push(JOURNAL_REMOVE);
+                payload.extend_from_slice(&(*id as u64).to_le_bytes());
+            }
+        }
+        buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&journal_checksum(&payload).to_le_bytes());
+        buffer.extend_from_slice(&payload);
+    }
+}
+
+fn decode_journal_entry(payload: &[u8]) -> Option<JournalEntry> {
+    let (&tag, rest) = payload.split_first()?;
+    let id = u64::from_le_bytes(rest.get(0..8)?.try_into().ok()?) as usize;
+    match tag {
+        JOURNAL_ADD => {
+            let dimension = u32::from_le_bytes(rest.get(8..12)?.try_into().ok()?) as usize;
+            let values = rest.get(12..)?;
+            if values.len() != dimension * 4 {
+                return None;
+            }
+            let vector = values
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .collect();
+            Some(JournalEntry::Add(id, vector))
+        }
+        JOURNAL_REMOVE if rest.len() == 8 => Some(JournalEntry::Remove(id)),
+        _ => None,
+    }
+}
+
+/// Reads records up to the first one that is incomplete or damaged, which can only be the last
+/// one written before a crash.
+fn decode_journal_entries(bytes: &[u8]) -> Vec<JournalEntry> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = bytes.get(offset..offset + JOURNAL_HEADER_LEN) {
+        let length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let checksum = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        let start = offset + JOURNAL_HEADER_LEN;
+        let Some(payload) = bytes.get(start..start + length) else {
+            break;
+        };
+        if journal_checksum(payload) != checksum {
+            break;
+        }
+        let Some(entry) = decode_journal_entry(payload) else {
+            break;
+        };
+        entries.push(entry);
+        offset = start + length;
+    }
+    if offset < bytes.len() {
+        error!("Ignoring {} bytes of a torn HNSW journal record", bytes.len() - offset);
+    }
+    entries
+}
+
+fn journal_path(db_path: &str, collection_name: &str) -> PathBuf {
+    Path::new(db_path).join(format!("{}.hnsw.journal", collection_name))
+}
+
+/// Append-only log of the adds and removes since the last dump, replayed when the index is
+/// opened so a crash doesn't lose them.
+struct Journal {
+    file: File,
+}
+
+impl Journal {
+    fn open(db_path: &str, collection_name: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(journal_path(db_path, collection_name))?;
+        Ok(Journal { file })
+    }
+
+    fn read(db_path: &str, collection_name: &str) -> Vec<JournalEntry> {
+        std::fs::read(journal_path(db_path, collection_name))
+            .map(|bytes| decode_journal_entries(&bytes))
+            .unwrap_or_default()
+    }
+
+    /// Writes the entries and waits until they are on disk.
+    fn append(&mut self, entries: &[JournalEntry]) -> Result<()> {
+        let mut buffer = Vec::new();
+        encode_journal_entries(entries, &mut buffer);
+        self.file.write_all(&buffer)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Empties the journal once its entries made it into a dump.
+    fn truncate(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Applies journal entries on top of a loaded graph. Adds of ids the graph already holds are
+/// skipped, they were dumped before the journal could be emptied. Returns the number of entries
+/// applied.
+fn replay_journal(
+    db: &Hnsw<f32, DistCosine>,
+    tombstones: &mut HashSet<usize>,
+    entries: Vec<JournalEntry>,
+) -> usize {
+    let existing: HashSet<usize> = db
+        .get_point_indexation()
+        .into_iter()
+        .map(|point| point.get_origin_id())
+        .collect();
+    let mut applied = 0;
+    for entry in entries {
+        match entry {
+            JournalEntry::Add(id, vector) => {
+                if !existing.contains(&id) {
+                    db.insert((&vector, id));
+                    applied += 1;
+                }
+            }
+            JournalEntry::Remove(id) => {
+                if tombstones.insert(id) {
+                    applied += 1;
+                }
+            }
+        }
+    }
+    applied
+}
+
+fn append_to_journal(journal: &mut Option<Journal>, entries: &[JournalEntry]) {
+    if let Some(journal) = journal.as_mut() {
+        if let Err(e) = journal.append(entries) {
+            error!("Failed to write HNSW journal, changes are kept in memory only: {}", e);
+        }
+    }
+}
+
+/// Dumps the index and empties the journal once the dump is in place.
+fn checkpoint(
+    db: &Hnsw<f32, DistCosine>,
+    tombstones: &HashSet<usize>,
+    params: &IndexParams,
+    db_path: &str,
+    collection_name: &str,
+    journal: &mut Option<Journal>,
+) -> Result<()> {
+    save_index(db, tombstones, params, db_path, collection_name)?;
+    if let Some(journal) = journal.as_mut() {
+        journal.truncate()?;
+    }
+    Ok(())
+}
+
+fn should_compact(db: &Hnsw<f32, DistCosine>, tombstones: &HashSet<usize>) -> bool {
+    tombstones.len() >= COMPACTION_MIN_TOMBSTONES
+        && tombstones.len() as f32 >= db.get_nb_point() as f32 * COMPACTION_TOMBSTONE_RATIO
+}
+
+/// Rebuilds the graph from the live points only, HNSW can't unlink nodes in place.
+///
+/// `on_progress` is called every few hundred points with the number of points inserted so far
+/// and the number of live points.
+fn compact<'a, 'b>(
+    db: &Hnsw<'a, f32, DistCosine>,
+    tombstones: &HashSet<usize>,
+    params: &IndexParams,
+    mut on_progress: impl FnMut(usize, usize),
+) -> Hnsw<'b, f32, DistCosine> {
+    info!(
+        "Compacting HNSW index: {} points, {} tombstones",
+        db.get_nb_point(),
+        tombstones.len()
+    );
+    let live_points: Vec<_> = db
+        .get_point_indexation()
+        .into_iter()
+        .filter(|point| !tombstones.contains(&point.get_origin_id()))
+        .collect();
+    let total = live_points.len();
+
+    let compacted = get_db(params);
+    for (index, point) in live_points.iter().enumerate() {
+        compacted.insert((point.get_v(), point.get_origin_id()));
+        if (index + 1) % REBUILD_PROGRESS_STEP == 0 {
+            on_progress(index + 1, total);
+        }
+    }
+    compacted
+}
+
+/// Widens the search beam in proportion to how few points pass the filter, otherwise a narrow
+/// filter leaves the beam without any allowed point long before `top_k` are found.
+fn filtered_ef_search(ef_search: usize, nb_points: usize, nb_allowed: usize) -> usize {
+    if nb_allowed == 0 {
+        return ef_search;
+    }
+    let scaled = ef_search.saturating_mul(nb_points) / nb_allowed;
+    scaled.clamp(ef_search, MAX_FILTERED_EF_SEARCH.max(ef_search))
+}
+
+fn complete_marker_path(db_path: &str, collection_name: &str) -> PathBuf {
+    Path::new(db_path).join(format!("{}_new.hnsw.complete", collection_name))
+}
+
+/// Dumps the index as `{collection}_new.hnsw.*` and moves it into place once it's complete.
+///
+/// Every file is synced before the `complete` marker is written. A crash before the marker
+/// leaves the previous dump in place and the `_new` files are discarded, a crash after it is
+/// finished by `SimilaritySearch::open`.
+fn save_index(
+    db: &Hnsw<f32, DistCosine>,
+    tombstones: &HashSet<usize>,
+    params: &IndexParams,
+    db_path: &str,
+    collection_name: &str,
+) -> Result<()> {
+    let resulting_name = format!("{}_new", collection_name);
+    let marker_path = complete_marker_path(db_path, collection_name);
+    // A complete dump that couldn't be moved into place yet is newer than the one in place
+    if marker_path.exists() {
+        promote_new_dump(db_path, collection_name, false)?;
+    }
+    if db.get_nb_point() == 0 {
+        // hnsw_rs can't dump an empty graph. Dropping the old dump makes the next open
+        // rebuild from the stored vectors, which are gone as well.
+        for name in [collection_name, resulting_name.as_str()] {
+            for extension in ["data", "graph"] {
+                let path = Path::new(db_path).join(format!("{}.hnsw.{}", name, extension));
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+    let save_res = db.file_dump(Path::new(db_path), &resulting_name);
+    if let Err(e) = save_res {
+        error!(
+            "Failed to save HNSW index to path={}, collection={}: {}",
+            db_path, collection_name, e
+        );
+        return Err(e.into());
+    }
+    let actual_resulting_name = save_res.unwrap();
+
+    for extension in ["data", "graph"] {
+        let saved_path =
+            Path::new(db_path).join(format!("{}.hnsw.{}", actual_resulting_name, extension));
+        let new_path = Path::new(db_path).join(format!("{}.hnsw.{}", resulting_name, extension));
+        std::fs::rename(&saved_path, &new_path)?;
+        File::open(&new_path)?.sync_all()?;
+    }
+    save_tombstones(db_path, &resulting_name, tombstones)?;
+    save_graph_params(db_path, &resulting_name, &params.graph_params())?;
+    write_atomic(&marker_path, b"")?;
+    sync_dir(Path::new(db_path));
+    promote_new_dump(db_path, collection_name, false)
+}
+
+/// Moves a complete `_new` dump into place and discards an incomplete one. The marker goes last,
+/// so a crash halfway through moves the remaining files on the next call.
+fn promote_new_dump(db_path: &str, collection_name: &str, legacy: bool) -> Result<()> {
+    let dir_path = Path::new(db_path);
+    let marker_path = complete_marker_path(db_path, collection_name);
+    let complete = marker_path.exists() || legacy;
+    for extension in ["data", "graph", "tombstones", "params"] {
+        let new_path = dir_path.join(format!("{}_new.hnsw.{}", collection_name, extension));
+        if !new_path.exists() {
+            continue;
+        }
+        if complete {
+            let path = dir_path.join(format!("{}.hnsw.{}", collection_name, extension));
+            std::fs::rename(&new_path, &path)?;
+        } else {
+            info!("Discarding incomplete HNSW dump file {}", new_path.display());
+            std::fs::remove_file(&new_path)?;
+        }
+    }
+    sync_dir(dir_path);
+    if marker_path.exists() {
+        std::fs::remove_file(&marker_path)?;
+    }
+    Ok(())
+}
+
+/// Builds a fresh graph from the vectors kept in the database.
+fn rebuild_from_stored_vectors<'a>(
+    params: &IndexParams,
+    load_vectors: VectorLoader,
+) -> Result<Hnsw<'a, f32, DistCosine>> {
+    let vectors = load_vectors()?;
+    info!("Rebuilding HNSW index from {} stored vectors", vectors.len());
+    let db = get_db(params);
+    let dimension = vectors.first().map(|(_, vector)| vector.len());
+    for (id, vector) in &vectors {
+        if Some(vector.len()) != dimension {
+            error!("Skipping stored vector {} with dimension {}", id, vector.len());
+            continue;
+        }
+        db.insert((vector, *id));
+    }
+    Ok(db)
+}
+
 async fn hnsw_thread_worker(
     db_path: &str,
     collection_name: &str,
+    mut params: IndexParams,
+    load_vectors: VectorLoader,
+    shared_graph: SharedGraph,
+    mut loading: OwnedRwLockWriteGuard<Option<Graph>>,
     mut command_reader: Receiver<HnswCommand>,
 ) -> Result<()> {
-    let mut reloader = HnswIo::new(std::path::Path::new(db_path), collection_name);
-    let db_res = reloader.load_hnsw::<f32, DistCosine>();
-    let db = match db_res {
-        Ok(db) => db,
-        Err(_) => get_db(),
+    // Dumps written before the parameters were recorded are used as they are.
+    let stored_params = load_graph_params(db_path, collection_name);
+    let params_changed = stored_params.map_or(false, |stored| stored != params.graph_params());
+
+    let db_res = if params_changed {
+        info!("HNSW index was built with {:?}, rebuilding", stored_params);
+        Err(anyhow!("Index parameters changed"))
+    } else {
+        GraphDb::load(db_path, collection_name)
+    };
+    let (db, mut tombstones, rebuilt) = match db_res {
+        Ok(db) => (db, load_tombstones(db_path, collection_name), false),
+        Err(e) => {
+            info!("Can't use the HNSW dump ({}), rebuilding from stored vectors", e);
+            let db = rebuild_from_stored_vectors(&params, load_vectors).unwrap_or_else(|e| {
+                error!("Failed to load stored vectors: {}", e);
+                get_db(&params)
+            });
+            (GraphDb::Built(db), HashSet::new(), true)
+        }
     };
 
+    // Changes made after the last dump, a rebuilt graph already has most of them.
+    let entries = Journal::read(db_path, collection_name);
+    let replayed = db.with(|db| replay_journal(db, &mut tombstones, entries));
+    if replayed > 0 {
+        info!("Replayed {} HNSW journal entries", replayed);
+    }
+    let mut journal = Journal::open(db_path, collection_name)
+        .map_err(|e| error!("Failed to open HNSW journal: {}", e))
+        .ok();
+    if rebuilt || replayed > 0 {
+        let saved = db.with(|db| {
+            checkpoint(
+                db,
+                &tombstones,
+                &params,
+                db_path,
+                collection_name,
+                &mut journal,
+            )
+        });
+        if let Err(e) = saved {
+            error!("Failed to save HNSW index: {}", e);
+        }
+    }
+    // Lookups were waiting for the graph until now
+    *loading = Some(Graph { db, tombstones });
+    drop(loading);
+
+    // Changes journaled since the last dump
+    let mut pending_changes = 0;
     loop {
         let command = command_reader.recv().await.ok_or(anyhow!(
             "Failed to receive command, probably the remote peer is no longer  available"
         ))?;
         match command {
             HnswCommand::Save => {
-                let resulting_name = format!("{}_new", collection_name);
-                let save_res = db.file_dump(std::path::Path::new(db_path), &resulting_name);
-                if let Err(e) = save_res {
-                    error!(
-                        "Failed to save HNSW index to path={}, collection={}: {}",
-                        db_path, collection_name, e
-                    );
-                    return Err(e.into());
+                compact_and_checkpoint(
+                    &shared_graph,
+                    &params,
+                    db_path,
+                    collection_name,
+                    &mut journal,
+                )
+                .await?;
+                pending_changes = 0;
+            }
+            HnswCommand::Flush(sender) => {
+                let saved = {
+                    let graph = read_graph(&shared_graph).await?;
+                    graph.db.with(|db| {
+                        checkpoint(
+                            db,
+                            &graph.tombstones,
+                            &params,
+                            db_path,
+                            collection_name,
+                            &mut journal,
+                        )
+                    })
+                };
+                if saved.is_ok() {
+                    pending_changes = 0;
                 }
-                let actual_resulting_name = save_res.unwrap();
-
-                let saved_data_file_name = format!("{}.hnsw.data", actual_resulting_name);
-                let saved_graph_file_name = format!("{}.hnsw.graph", actual_resulting_name);
-
-                let save_data_path = std::path::Path::new(db_path).join(saved_data_file_name);
-                let save_graph_path = std::path::Path::new(db_path).join(saved_graph_file_name);
-
-                let new_data_file_name = format!("{}_new.hnsw.data", collection_name);
-                let new_graph_file_name = format!("{}_new.hnsw.graph", collection_name);
-
-                let new_data_path = std::path::Path::new(db_path).join(new_data_file_name);
-                let new_graph_path = std::path::Path::new(db_path).join(new_graph_file_name);
-
-                std::fs::rename(&save_data_path, &new_data_path)?;
-                std::fs::rename(&save_graph_path, &new_graph_path)?;
+                sender.send(saved).await?;
             }
             HnswCommand::Add(vector, id) => {
                 // trace!("Adding vector to HNSW index.");
-                db.insert((&vector, id));
+                append_to_journal(&mut journal, &[JournalEntry::Add(id, vector.clone())]);
+                let graph = write_graph(&shared_graph).await?;
+                graph.db.with(|db| db.insert((&vector, id)));
+                pending_changes += 1;
             }
-            HnswCommand::Lookup(vector, top_k, sender) => {
-                let results = db.search(&vector, top_k, MAX_NB_CONNECTION);
-                let candidates = results
+            HnswCommand::Remove(ids) => {
+                let entries: Vec<JournalEntry> =
+                    ids.iter().map(|id| JournalEntry::Remove(*id)).collect();
+                append_to_journal(&mut journal, &entries);
+                write_graph(&shared_graph).await?.tombstones.extend(ids);
+                pending_changes += entries.len();
+            }
+            HnswCommand::Replace(removed_ids, vectors) => {
+                let entries: Vec<JournalEntry> = removed_ids
                     .iter()
-                    .map(|result| (result.d_id, result.distance))
-                    .collect::<Vec<_>>();
-                sender.send(Ok(candidates)).await?;
+                    .map(|id| JournalEntry::Remove(*id))
+                    .chain(
+                        vectors
+                            .iter()
+                            .map(|(vector, id)| JournalEntry::Add(*id, vector.clone())),
+                    )
+                    .collect();
+                append_to_journal(&mut journal, &entries);
+                // Lookups see either the old or the new vectors of the document, never both
+                let mut graph = write_graph(&shared_graph).await?;
+                graph.tombstones.extend(removed_ids);
+                graph.db.with(|db| {
+                    for (vector, id) in &vectors {
+                        db.insert((vector, *id));
+                    }
+                });
+                pending_changes += entries.len();
+            }
+            HnswCommand::Rebuild(new_params, progress) => {
+                // Intermediate reports are dropped rather than stalling the rebuild when the
+                // caller falls behind, the caller may also have stopped listening altogether.
+                let rebuilt = {
+                    let graph = read_graph(&shared_graph).await?;
+                    graph.db.with(|db| {
+                        compact(db, &graph.tombstones, &new_params, |processed, total| {
+                            let _ = progress.try_send(Ok(RebuildProgress { processed, total }));
+                        })
+                    })
+                };
+                {
+                    let mut graph = write_graph(&shared_graph).await?;
+                    graph.db = GraphDb::Built(rebuilt);
+                    graph.tombstones.clear();
+                }
+                params = new_params;
+                pending_changes = 0;
+                let report = {
+                    let graph = read_graph(&shared_graph).await?;
+                    let total = graph.db.with(|db| db.get_nb_point());
+                    let saved = graph.db.with(|db| {
+                        checkpoint(
+                            db,
+                            &graph.tombstones,
+                            &params,
+                            db_path,
+                            collection_name,
+                            &mut journal,
+                        )
+                    });
+                    match saved {
+                        Ok(()) => Ok(RebuildProgress {
+                            processed: total,
+                            total,
+                        }),
+                        Err(e) => Err(anyhow!("Failed to save rebuilt index: {}", e)),
+                    }
+                };
+                let _ = progress.send(report).await;
             }
             HnswCommand::Shutdown => {
                 info!("Shutting down HNSW thread worker");
+                *shared_graph.write().await = None;
                 break;
             }
         }
+        if pending_changes >= CHECKPOINT_CHANGES {
+            let saved = compact_and_checkpoint(
+                &shared_graph,
+                &params,
+                db_path,
+                collection_name,
+                &mut journal,
+            )
+            .await;
+            if let Err(e) = saved {
+                error!("Failed to save HNSW index: {}", e);
+            }
+            pending_changes = 0;
+        }
     }
     Ok(())
 }
 
-pub struct SimilaritySearch(
-    Option<tokio::task::JoinHandle<()>>,
-    Option<Sender<HnswCommand>>,
-);
+/// Compacts the graph when enough of it is dead, then dumps it and empties the journal.
+async fn compact_and_checkpoint(
+    shared_graph: &RwLock<Option<Graph>>,
+    params: &IndexParams,
+    db_path: &str,
+    collection_name: &str,
+    journal: &mut Option<Journal>,
+) -> Result<()> {
+    // Lookups keep using the old graph while the compacted one is built
+    let compacted = {
+        let graph = read_graph(shared_graph).await?;
+        graph.db.with(|db| {
+            should_compact(db, &graph.tombstones)
+                .then(|| compact(db, &graph.tombstones, params, |_, _| {}))
+        })
+    };
+    if let Some(compacted) = compacted {
+        let mut graph = write_graph(shared_graph).await?;
+        graph.db = GraphDb::Built(compacted);
+        graph.tombstones.clear();
+    }
+    let graph = read_graph(shared_graph).await?;
+    graph.db.with(|db| {
+        checkpoint(
+            db,
+            &graph.tombstones,
+            params,
+            db_path,
+            collection_name,
+            journal,
+        )
+    })
+}
 
-unsafe impl Send for SimilaritySearch {}
-unsafe impl Sync for SimilaritySearch {}
+/// Owns the worker, shared by every clone of a `SimilaritySearch`.
+struct Worker {
+    handle: Mutex<Option<tokio::task::JoinHandle<()>>>,
+    commands: Sender<HnswCommand>,
+}
 
-impl Drop for SimilaritySearch {
+impl Drop for Worker {
     fn drop(&mut self) {
         info!("Dropping SimilaritySearch instance");
-        let thread_handle = self.0.take();
-        let sender_channel = self.1.take();
-        if let (Some(t_handle), Some(sc)) = (thread_handle, sender_channel) {
-            match tokio::runtime::Handle::try_current() {
-                Ok(handle) => {
-                    handle.spawn(async move {
-                        if let Err(e) = sc.send(HnswCommand::Save).await {
-                            error!("Failed to send HnswCommand::Save: {}", e);
-                        }
-                        if let Err(e) = sc.send(HnswCommand::Shutdown).await {
-                            error!("Failed to send HnswCommand::Shutdown: {}", e);
-                        }
-                        if let Err(e) = t_handle.await {
-                            error!("Failed to await thread handle: {}", e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    error!("Failed to get Tokio runtime handle: {}", e);
-                }
+        let Some(t_handle) = self.handle.get_mut().take() else {
+            return;
+        };
+        let sc = self.commands.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    if let Err(e) = sc.send(HnswCommand::Save).await {
+                        error!("Failed to send HnswCommand::Save: {}", e);
+                    }
+                    if let Err(e) = sc.send(HnswCommand::Shutdown).await {
+                        error!("Failed to send HnswCommand::Shutdown: {}", e);
+                    }
+                    if let Err(e) = t_handle.await {
+                        error!("Failed to await thread handle: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Failed to get Tokio runtime handle: {}", e);
             }
         }
     }
 }
 
-pub type SyncSimilaritySearch = Arc<Mutex<Option<SimilaritySearch>>>;
+/// Handle to an index. Clones are cheap and share the index, lookups read the graph without
+/// going through the worker, so any number of them run at the same time.
+///
+/// The worker is stopped when the last clone is dropped or when `close` is called.
+#[derive(Clone)]
+pub struct SimilaritySearch {
+    commands: Sender<HnswCommand>,
+    graph: SharedGraph,
+    worker: Arc<Worker>,
+}
 
-const IS_TEST: bool = cfg!(test);
+pub type SyncSimilaritySearch = Arc<RwLock<Option<SimilaritySearch>>>;
 
-const MAX_CHARS: usize = 7900;
+const IS_TEST: bool = cfg!(test);
 
-async fn get_embedding(text: &str, api_key: &str) -> Result<Vec<f32>> {
+/// Embeds a single text the way the index does, long texts are cut off.
+async fn get_embedding(text: &str, provider: &dyn EmbeddingProvider) -> Result<Vec<f32>> {
     if IS_TEST {
         return Ok(vec![0.0; 512]);
     }
 
-    let truncated_text = if text.len() > MAX_CHARS {
-        &text[..MAX_CHARS]
-    } else {
-        text
-    };
-
-    compute_vector_embedding(truncated_text, api_key)
-        .await
-        .map_err(|e| anyhow!("{}", e))
+    provider.embed(truncate_for_embedding(text)).await
 }
 
 impl SimilaritySearch {
-    pub fn open(db_path: &str, collection_name: &str) -> Result<Self> {
+    /// Opens the index dumped in `db_path`. When the dump is missing, can't be read or was built
+    /// with other graph parameters, the graph is rebuilt from the vectors `load_vectors` returns.
+    pub fn open(
+        db_path: &str,
+        collection_name: &str,
+        params: IndexParams,
+        load_vectors: VectorLoader,
+    ) -> Result<Self> {
         info!(
             "Opening HNSW instance: {}, collection: {}",
             db_path, collection_name
         );
-        let dir_path = std::path::Path::new(db_path);
+        let dir_path = Path::new(db_path);
 
         if !dir_path.exists() {
             create_dir_all(dir_path)?;
         }
 
-        // if exist 'collection_name_new.hnsw.{data, graph}' rename them to 'collection_name.hnsw.{data, graph}'
-        let new_data_path = format!("{}_new.hnsw.data", collection_name);
-        let new_graph_path = format!("{}_new.hnsw.graph", collection_name);
-
-        let data_path = format!("{}.hnsw.data", collection_name);
-        let graph_path = format!("{}.hnsw.graph", collection_name);
-
-        let new_data_path = dir_path.join(new_data_path);
-        let new_graph_path = dir_path.join(new_graph_path);
-
-        let data_path = dir_path.join(data_path);
-        let graph_path = dir_path.join(graph_path);
-
-        if new_data_path.exists() {
-            std::fs::rename(&new_data_path, &data_path)?;
-        }
-
-        if new_graph_path.exists() {
-            std::fs::rename(&new_graph_path, &graph_path)?;
-        }
+        // Dumps written before the journal existed have no completion marker.
+        let legacy = !journal_path(db_path, collection_name).exists();
+        promote_new_dump(db_path, collection_name, legacy)?;
 
         let (command_sender, command_receiver) = tokio::sync::mpsc::channel(MAX_INFLIGHT_COMMANDS);
+        let graph: SharedGraph = Arc::new(RwLock::new(None));
+        // The worker holds the graph until it's loaded, lookups wait for it in the meantime
+        let loading = graph.clone().try_write_owned()?;
         async fn worker(
             db_path: String,
             collection_name: String,
+            params: IndexParams,
+            load_vectors: VectorLoader,
+            graph: SharedGraph,
+            loading: OwnedRwLockWriteGuard<Option<Graph>>,
             command_receiver: Receiver<HnswCommand>,
         ) {
-            let res = hnsw_thread_worker(&db_path, &collection_name, command_receiver).await;
+            let res = hnsw_thread_worker(
+                &db_path,
+                &collection_name,
+                params,
+                load_vectors,
+                graph,
+                loading,
+                command_receiver,
+            )
+            .await;
             if let Err(e) = res {
                 panic!("HNSW thread worker failed: {}", e);
             }
@@ -205,21 +951,53 @@ impl SimilaritySearch {
         let db = tokio::spawn(worker(
             db_path.to_string(),
             collection_name.to_string(),
+            params,
+            load_vectors,
+            graph.clone(),
+            loading,
             command_receiver,
         ));
 
-        Ok(SimilaritySearch(Some(db), Some(command_sender)))
+        Ok(SimilaritySearch {
+            commands: command_sender.clone(),
+            graph,
+            worker: Arc::new(Worker {
+                handle: Mutex::new(Some(db)),
+                commands: command_sender,
+            }),
+        })
     }
 
-    pub async fn sync(&self) -> Result<()> {
-        info!("Sending HnswCommand::Save");
-        self.1.as_ref().unwrap().send(HnswCommand::Save).await?;
-        info!("Waiting for HnswCommand::Save");
-        Ok(())
+    /// Dumps the index, waits until it's on disk and stops the worker. Call this before the
+    /// process exits, `Drop` only schedules the dump on the runtime. Other clones of the handle
+    /// fail from then on.
+    pub async fn close(&self) -> Result<()> {
+        let Some(worker) = self.worker.handle.lock().await.take() else {
+            return Ok(());
+        };
+        let (flush_sender, mut flush_receiver) = tokio::sync::mpsc::channel(1);
+        self.send(HnswCommand::Flush(flush_sender)).await?;
+        let flushed = flush_receiver.recv().await.ok_or(anyhow!(
+            "Failed to receive flush result, probably the remote peer is no longer available"
+        ))?;
+        self.send(HnswCommand::Shutdown).await?;
+        worker.await?;
+        info!("Closed HNSW index");
+        flushed
+    }
+
+    /// Dumps the index and waits until it's on disk. This also waits for the graph to be loaded
+    /// or rebuilt, so a freshly opened index is ready afterwards.
+    pub async fn flush(&self) -> Result<()> {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        self.send(HnswCommand::Flush(sender)).await?;
+        receiver.recv().await.ok_or(anyhow!(
+            "Failed to receive flush result, probably the remote peer is no longer available"
+        ))?
     }
 
-    pub async fn add(&self, id: i64, text: &str, api_key: &str) -> Result<()> {
-        let vector_res = get_embedding(text, api_key).await;
+    pub async fn add(&self, id: i64, text: &str, provider: &dyn EmbeddingProvider) -> Result<()> {
+        let vector_res = get_embedding(text, provider).await;
         let vector = match vector_res {
             Ok(v) => v,
             Err(e) => {
@@ -228,32 +1006,77 @@ impl SimilaritySearch {
             }
         };
 
-        match &self.1 {
-            Some(sender) => {
-                if let Err(e) = sender.send(HnswCommand::Add(vector, id as usize)).await {
-                    error!("Failed to send HnswCommand::Add: {}", e);
-                    return Err(anyhow!("Failed to send HnswCommand::Add: {}", e));
-                }
-                Ok(())
-            }
-            None => {
-                error!("Command sender is None");
-                Err(anyhow!("Command sender is None"))
-            }
+        if let Err(e) = self.commands.send(HnswCommand::Add(vector, id as usize)).await {
+            error!("Failed to send HnswCommand::Add: {}", e);
+            return Err(anyhow!("Failed to send HnswCommand::Add: {}", e));
         }
+        Ok(())
     }
 
+    /// Marks the vectors as deleted, they stop showing up in lookups right away.
+    pub async fn remove(&self, ids: &[i64]) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let ids = ids.iter().map(|id| *id as usize).collect();
+        self.send(HnswCommand::Remove(ids)).await
+    }
+
+    /// Swaps the vectors of `removed_ids` for the `(id, vector)` pairs in one step.
+    ///
+    /// Ids are never reused, so replacements of the same document may be applied in any order:
+    /// a vector removed before it was added stays hidden.
+    pub async fn replace(&self, removed_ids: &[i64], items: Vec<(i64, Vec<f32>)>) -> Result<()> {
+        let vectors = items
+            .into_iter()
+            .map(|(id, vector)| (vector, id as usize))
+            .collect();
+        let removed_ids = removed_ids.iter().map(|id| *id as usize).collect();
+        self.send(HnswCommand::Replace(removed_ids, vectors)).await
+    }
+
+    async fn send(&self, command: HnswCommand) -> Result<()> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|e| anyhow!("Failed to send HNSW command: {}", e))
+    }
+
+    /// Re-creates the graph from the stored points with `params`, dropping deleted vectors.
+    ///
+    /// `on_progress` is called as the new graph fills up, the last report has `processed == total`.
+    /// Lookups use the current graph until the new one is complete, changes queue up behind the
+    /// rebuild.
+    pub async fn rebuild(
+        &self,
+        params: IndexParams,
+        mut on_progress: impl FnMut(RebuildProgress),
+    ) -> Result<()> {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+        self.send(HnswCommand::Rebuild(params, sender)).await?;
+        while let Some(report) = receiver.recv().await {
+            on_progress(report?);
+        }
+        Ok(())
+    }
+
+    /// Returns the `top_k` closest vectors to `query_text`. When `allowed_ids` is set, only those
+    /// ids are considered, the filter is applied during the graph search rather than on its result.
+    ///
+    /// The query is embedded before the graph is read, so a slow provider holds up nothing else.
     pub async fn top_k(
         &self,
         query_text: &str,
         top_k: usize,
-        api_key: &str,
+        ef_search: usize,
+        allowed_ids: Option<HashSet<usize>>,
+        provider: &dyn EmbeddingProvider,
     ) -> Result<Vec<(usize, f32)>> {
         info!(
             "Performing similarity search in HNSW Index: Query={}",
             query_text
         );
-        let query_vector_res = get_embedding(query_text, api_key).await;
+        let query_vector_res = get_embedding(query_text, provider).await;
         let query_vector = match query_vector_res {
             Ok(v) => v,
             Err(e) => {
@@ -263,21 +1086,56 @@ impl SimilaritySearch {
         };
        // debug!("Computed query vector embedding: {:?}", query_vector);
 
-        if top_k > MAX_NB_CONNECTION {
-            bail!("top_k exceeds MAX_NB_CONNECTION");
-        }
+        let id_filter = IdFilter {
+            allowed: allowed_ids,
+            ..Default::default()
+        };
+        self.lookup(&query_vector, top_k, ef_search, id_filter).await
+    }
 
-        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
-        self.1
-            .as_ref()
-            .unwrap()
-            .send(HnswCommand::Lookup(query_vector, top_k, sender))
-            .await?;
-        let candidates_res = receiver.recv().await.ok_or(anyhow!(
-            "Failed to receive candidates, probably the remote peer is no longer available"
-        ))?;
+    /// Returns the `top_k` closest vectors to `vector`, leaving out `excluded_ids`.
+    pub async fn nearest(
+        &self,
+        vector: Vec<f32>,
+        top_k: usize,
+        ef_search: usize,
+        excluded_ids: HashSet<usize>,
+    ) -> Result<Vec<(usize, f32)>> {
+        let id_filter = IdFilter {
+            excluded: excluded_ids,
+            ..Default::default()
+        };
+        self.lookup(&vector, top_k, ef_search, id_filter).await
+    }
+
+    /// Searches the graph under a shared lock, lookups only wait for changes being applied.
+    async fn lookup(
+        &self,
+        vector: &[f32],
+        top_k: usize,
+        ef_search: usize,
+        id_filter: IdFilter,
+    ) -> Result<Vec<(usize, f32)>> {
+        if top_k == 0 || top_k > MAX_TOP_K {
+            bail!("top_k must be between 1 and {}", MAX_TOP_K);
+        }
+        if id_filter.allowed.as_ref().map_or(false, |allowed| allowed.is_empty()) {
+            return Ok(Vec::new());
+        }
 
-        let candidates = candidates_res?;
+        let graph = read_graph(&self.graph).await?;
+        let filter = |id: &usize| !graph.tombstones.contains(id) && id_filter.accepts(id);
+        let candidates = graph.db.with(|db| {
+            let ef_search = match &id_filter.allowed {
+                Some(allowed) => filtered_ef_search(ef_search, db.get_nb_point(), allowed.len()),
+                None => ef_search,
+            }
+            .max(top_k);
+            db.search_filter(vector, top_k, ef_search, Some(&filter))
+                .iter()
+                .map(|result| (result.d_id, result.distance))
+                .collect::<Vec<_>>()
+        });
 
         info!(
             "Similarity search completed. Retrieved {} similar documents",
@@ -291,24 +1149,187 @@ impl SimilaritySearch {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
     use anyhow::Result;
 
-    use super::SimilaritySearch;
+    use super::{
+        compact, decode_journal_entries, encode_journal_entries, filtered_ef_search, get_db,
+        should_compact, IndexParams, JournalEntry, SimilaritySearch, EF_SEARCH,
+        MAX_FILTERED_EF_SEARCH,
+    };
+    use crate::engine::embedding_engine::OpenAiEmbeddingProvider;
+
+    /// A distinct direction for every id.
+    fn vector(id: usize) -> Vec<f32> {
+        vec![1.0, id as f32, (id % 7) as f32]
+    }
+
+    fn open_index(db_path: &str) -> Result<SimilaritySearch> {
+        SimilaritySearch::open(
+            db_path,
+            "test_collection",
+            IndexParams::default(),
+            Box::new(|| Ok(Vec::new())),
+        )
+    }
 
     #[tokio::test]
     async fn test_similarity_search() -> Result<()> {
         let temp_dir = tempfile::tempdir()?;
         let db_path = temp_dir.path().join("test.db");
         let collection_name = "test_collection";
-        let mut index = SimilaritySearch::open(db_path.to_str().unwrap(), collection_name)?;
-        index.add(1, "hello world", "").await?;
-        let candidates = index.top_k("hello world", 1, "").await?;
+        let provider = OpenAiEmbeddingProvider::new("", "text-embedding-3-small");
+        let index = SimilaritySearch::open(
+            db_path.to_str().unwrap(),
+            collection_name,
+            IndexParams::default(),
+            Box::new(|| Ok(Vec::new())),
+        )?;
+        index.add(1, "hello world", &provider).await?;
+        let candidates = index.top_k("hello world", 1, EF_SEARCH, None, &provider).await?;
         assert_eq!(candidates, vec![(1, 0.0)]);
         index.close().await?;
         drop(index);
-        let index = SimilaritySearch::open(db_path.to_str().unwrap(), collection_name)?;
-        let candidates = index.top_k("hello world", 1, "").await?;
+        let index = SimilaritySearch::open(
+            db_path.to_str().unwrap(),
+            collection_name,
+            IndexParams::default(),
+            Box::new(|| Ok(Vec::new())),
+        )?;
+        let candidates = index.top_k("hello world", 1, EF_SEARCH, None, &provider).await?;
         assert_eq!(candidates, vec![(1, 0.0)]);
         Ok(())
     }
+
+    #[test]
+    fn filtered_lookups_widen_the_beam() {
+        assert_eq!(filtered_ef_search(64, 1000, 1000), 64);
+        assert_eq!(filtered_ef_search(64, 1000, 100), 640);
+        assert_eq!(filtered_ef_search(64, 1_000_000, 10), MAX_FILTERED_EF_SEARCH);
+        assert_eq!(filtered_ef_search(64, 1000, 0), 64);
+    }
+
+    #[test]
+    fn journal_reading_stops_at_a_torn_record() {
+        let entries = vec![
+            JournalEntry::Add(7, vec![0.5, -1.0, 2.25]),
+            JournalEntry::Remove(3),
+            JournalEntry::Add(8, vec![1.0, 1.0, 1.0]),
+        ];
+        let mut bytes = Vec::new();
+        encode_journal_entries(&entries, &mut bytes);
+        assert_eq!(decode_journal_entries(&bytes), entries);
+
+        bytes.truncate(bytes.len() - 2);
+        assert_eq!(decode_journal_entries(&bytes), entries[..2].to_vec());
+
+        let mut corrupted = Vec::new();
+        encode_journal_entries(&entries, &mut corrupted);
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        assert_eq!(decode_journal_entries(&corrupted), entries[..2].to_vec());
+    }
+
+    async fn nearest_ids(
+        index: &SimilaritySearch,
+        id: usize,
+        excluded: HashSet<usize>,
+    ) -> Result<Vec<usize>> {
+        let candidates = index.nearest(vector(id), 3, EF_SEARCH, excluded).await?;
+        Ok(candidates.into_iter().map(|(id, _)| id).collect())
+    }
+
+    #[tokio::test]
+    async fn removed_vectors_stay_hidden_after_reopening() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let db_path = temp_dir.path().to_str().unwrap().to_string();
+        let index = open_index(&db_path)?;
+        let items = (1..=3).map(|id| (id as i64, vector(id))).collect();
+        index.replace(&[], items).await?;
+        index.remove(&[2]).await?;
+        // Lookups don't go through the worker, wait for the changes to be applied
+        index.flush().await?;
+
+        let found = nearest_ids(&index, 2, HashSet::new()).await?;
+        assert_eq!(found.len(), 2);
+        assert!(!found.contains(&2));
+
+        let found = nearest_ids(&index, 1, HashSet::from([1])).await?;
+        assert_eq!(found, vec![3]);
+
+        index.replace(&[3], vec![(4, vector(4))]).await?;
+        index.close().await?;
+        drop(index);
+
+        let index = open_index(&db_path)?;
+        index.flush().await?;
+        let mut found = nearest_ids(&index, 3, HashSet::new()).await?;
+        found.sort();
+        assert_eq!(found, vec![1, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_drops_the_tombstoned_points() {
+        let params = IndexParams::default();
+        let db = get_db(&params);
+        for id in 0..400 {
+            db.insert((&vector(id), id));
+        }
+
+        // Dead points have to reach both the minimum count and the minimum share
+        let tombstones: HashSet<usize> = (0..63).collect();
+        assert!(!should_compact(&db, &tombstones));
+        let tombstones: HashSet<usize> = (0..79).collect();
+        assert!(!should_compact(&db, &tombstones));
+        let tombstones: HashSet<usize> = (0..80).collect();
+        assert!(should_compact(&db, &tombstones));
+
+        let compacted = compact(&db, &tombstones, &params, |_, _| {});
+        assert_eq!(compacted.get_nb_point(), 320);
+        let mut ids: Vec<usize> = compacted
+            .get_point_indexation()
+            .into_iter()
+            .map(|point| point.get_origin_id())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, (80..400).collect::<Vec<_>>());
+        assert!(!should_compact(&compacted, &HashSet::new()));
+    }
+
+    /// Copies the files of the index as they are on disk, as if the process died right now.
+    fn snapshot(from: &Path, to: &Path) -> Result<()> {
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_crash_during_a_dump_keeps_the_previous_checkpoint() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let crashed_dir = tempfile::tempdir()?;
+        let index = open_index(temp_dir.path().to_str().unwrap())?;
+        let items = vec![(1, vector(1)), (2, vector(2))];
+        index.replace(&[], items).await?;
+        index.flush().await?;
+        index.replace(&[1], vec![(3, vector(3))]).await?;
+        index.flush().await?;
+
+        // The process dies while the next dump is being written, the journal is empty by now
+        snapshot(temp_dir.path(), crashed_dir.path())?;
+        let torn_path = crashed_dir.path().join("test_collection_new.hnsw.graph");
+        std::fs::write(torn_path, b"torn")?;
+        index.close().await?;
+
+        let index = open_index(crashed_dir.path().to_str().unwrap())?;
+        index.flush().await?;
+        let mut found = nearest_ids(&index, 2, HashSet::new()).await?;
+        found.sort();
+        assert_eq!(found, vec![2, 3]);
+        Ok(())
+    }
 }
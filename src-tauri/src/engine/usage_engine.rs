@@ -0,0 +1,226 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::Local;
+use log::error;
+use rusqlite::Connection;
+use tauri::AppHandle;
+
+use crate::configuration::state::ServiceAccess;
+use crate::engine::embedding_engine::{non_empty_setting, EmbeddingProvider};
+use crate::engine::llm_engine::{estimate_tokens, TokenUsage};
+use crate::engine::model_registry_engine::embedding_price;
+use crate::entity::llm_model::LlmModel;
+use crate::entity::usage_event::{
+    BudgetStatus, ChatUsage, DailyUsage, ProjectUsage, UsageEvent, UsagePurpose, UsageScope,
+};
+use crate::repository::usage_repository::{
+    get_chat_usage, get_cost_since, get_daily_usage, get_project_usage, insert_usage_event,
+};
+
+const DEFAULT_REPORT_DAYS: u32 = 30;
+
+/// USD a call costs with prices in USD per million tokens.
+fn usage_cost(input_price: f64, output_price: f64, usage: TokenUsage) -> f64 {
+    (usage.input_tokens as f64 * input_price + usage.output_tokens as f64 * output_price)
+        / 1_000_000.0
+}
+
+fn month_start() -> String {
+    Local::now().format("%Y-%m-01").to_string()
+}
+
+/// The `monthly_budget` setting in USD, `None` when calls aren't limited.
+fn monthly_budget(db: &Connection) -> Option<f64> {
+    let value = non_empty_setting(db, "monthly_budget")?;
+    match value.parse::<f64>() {
+        Ok(budget) if budget > 0.0 => Some(budget),
+        Ok(_) => None,
+        Err(_) => {
+            error!("Ignoring invalid monthly_budget setting: {}", value);
+            None
+        }
+    }
+}
+
+/// Fails once the calls of the current month cost as much as the monthly budget.
+fn check_budget(db: &Connection) -> Result<()> {
+    let Some(budget) = monthly_budget(db) else {
+        return Ok(());
+    };
+    let spent = get_cost_since(db, &month_start())?;
+    if spent >= budget {
+        bail!(
+            "The monthly budget of ${:.2} is used up, ${:.2} was spent this month",
+            budget,
+            spent
+        );
+    }
+    Ok(())
+}
+
+/// Checks the monthly budget before calling `model`. Free models are never blocked.
+pub fn check_llm_budget(app_handle: &AppHandle, model: &LlmModel) -> Result<()> {
+    if model.input_price == 0.0 && model.output_price == 0.0 {
+        return Ok(());
+    }
+    app_handle.db(check_budget)
+}
+
+fn record_usage(app_handle: &AppHandle, event: UsageEvent) {
+    if let Err(e) = app_handle.db(|db| insert_usage_event(db, &event)) {
+        error!("Failed to record usage of {}: {}", event.model, e);
+    }
+}
+
+/// Adds a call to `model` to the usage ledger, priced with the model's registry prices.
+pub fn record_llm_usage(
+    app_handle: &AppHandle,
+    provider_id: &str,
+    model: &LlmModel,
+    purpose: UsagePurpose,
+    usage: TokenUsage,
+    scope: UsageScope,
+) {
+    record_usage(
+        app_handle,
+        UsageEvent {
+            provider: provider_id.to_string(),
+            model: model.id.clone(),
+            purpose,
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+            cost: usage_cost(model.input_price, model.output_price, usage),
+            scope,
+        },
+    );
+}
+
+/// Checks the monthly budget before and records the usage after every request of the provider
+/// it wraps. Embedding APIs don't all report tokens, they're estimated from the texts.
+pub struct MeteredEmbeddingProvider {
+    app_handle: AppHandle,
+    provider_id: String,
+    inner: Arc<dyn EmbeddingProvider>,
+    /// What the usage is recorded for.
+    scope: UsageScope,
+}
+
+impl MeteredEmbeddingProvider {
+    pub fn new(
+        app_handle: AppHandle,
+        provider_id: &str,
+        inner: Arc<dyn EmbeddingProvider>,
+        scope: UsageScope,
+    ) -> Self {
+        MeteredEmbeddingProvider {
+            app_handle,
+            provider_id: provider_id.to_string(),
+            inner,
+            scope,
+        }
+    }
+
+    fn price(&self) -> f64 {
+        embedding_price(&self.provider_id, self.inner.model_name())
+    }
+
+    fn check_budget(&self) -> Result<()> {
+        if self.price() == 0.0 {
+            return Ok(());
+        }
+        self.app_handle.db(check_budget)
+    }
+
+    fn record(&self, input_tokens: u32) {
+        let usage = TokenUsage {
+            input_tokens,
+            output_tokens: 0,
+        };
+        record_usage(
+            &self.app_handle,
+            UsageEvent {
+                provider: self.provider_id.clone(),
+                model: self.inner.model_name().to_string(),
+                purpose: UsagePurpose::Embedding,
+                input_tokens,
+                output_tokens: 0,
+                cost: usage_cost(self.price(), 0.0, usage),
+                scope: self.scope,
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for MeteredEmbeddingProvider {
+    fn model_name(&self) -> &str {
+        self.inner.model_name()
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.check_budget()?;
+        let vector = self.inner.embed(text).await?;
+        self.record(estimate_tokens(text) as u32);
+        Ok(vector)
+    }
+
+    async fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.check_budget()?;
+        let input_tokens: usize = texts.iter().map(|text| estimate_tokens(text)).sum();
+        let vectors = self.inner.embed_batch(texts).await?;
+        self.record(input_tokens as u32);
+        Ok(vectors)
+    }
+}
+
+/// Usage of the last `days` days, 30 by default, by day.
+#[tauri::command]
+pub fn get_usage_by_day(
+    app_handle: AppHandle,
+    days: Option<u32>,
+) -> Result<Vec<DailyUsage>, String> {
+    let days = days.unwrap_or(DEFAULT_REPORT_DAYS).max(1);
+    let since = Local::now().date_naive() - chrono::Duration::days(days as i64 - 1);
+    app_handle
+        .db(|db| get_daily_usage(db, &since.format("%Y-%m-%d").to_string()))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_usage_by_chat(app_handle: AppHandle) -> Result<Vec<ChatUsage>, String> {
+    app_handle.db(get_chat_usage).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_usage_by_project(app_handle: AppHandle) -> Result<Vec<ProjectUsage>, String> {
+    app_handle.db(get_project_usage).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_usage_budget(app_handle: AppHandle) -> Result<BudgetStatus, String> {
+    app_handle.db(|db| {
+        Ok(BudgetStatus {
+            monthly_budget: monthly_budget(db),
+            spent_this_month: get_cost_since(db, &month_start()).map_err(|e| e.to_string())?,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::usage_cost;
+    use crate::engine::llm_engine::TokenUsage;
+
+    #[test]
+    fn cost_uses_prices_per_million_tokens() {
+        let usage = TokenUsage {
+            input_tokens: 2_000,
+            output_tokens: 500,
+        };
+        let cost = usage_cost(3.0, 15.0, usage);
+        assert!((cost - 0.0135).abs() < 1e-12);
+        assert_eq!(usage_cost(0.0, 0.0, usage), 0.0);
+    }
+}
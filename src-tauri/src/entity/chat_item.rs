@@ -1,6 +1,11 @@
+use std::str::FromStr;
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite_from_row::FromRow;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::entity::vector_chunk::SourceType;
+
 #[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
 pub struct Chat {
     pub id: i64,
@@ -9,11 +14,87 @@ pub struct Chat {
     pub updated_at: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+/// A retrieved document an answer cites.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageSource {
+    /// Number of the document in the prompt, the answer cites it as `[n]`.
+    pub citation: usize,
+    pub source_type: SourceType,
+    pub source_id: i64,
+    /// `None` when the source is too short to have been split into vector chunks.
+    pub chunk_id: Option<i64>,
+    pub title: String,
+    /// Start of the passage the model was given.
+    pub snippet: String,
+    pub score: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStatus {
+    /// The answer is still streaming in.
+    Streaming,
+    #[default]
+    Complete,
+    Cancelled,
+    Failed,
+    /// The app closed while the answer was streaming in, the content is what arrived until then.
+    Interrupted,
+}
+
+impl MessageStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageStatus::Streaming => "streaming",
+            MessageStatus::Complete => "complete",
+            MessageStatus::Cancelled => "cancelled",
+            MessageStatus::Failed => "failed",
+            MessageStatus::Interrupted => "interrupted",
+        }
+    }
+}
+
+impl FromStr for MessageStatus {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "streaming" => Ok(MessageStatus::Streaming),
+            "complete" => Ok(MessageStatus::Complete),
+            "cancelled" => Ok(MessageStatus::Cancelled),
+            "failed" => Ok(MessageStatus::Failed),
+            "interrupted" => Ok(MessageStatus::Interrupted),
+            _ => Err(format!("Unknown message status: {}", value)),
+        }
+    }
+}
+
+impl ToSql for MessageStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for MessageStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|_| FromSqlError::InvalidType)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StoredMessage {
     pub id: i64,
     pub chat_id: i64,
     pub role: String,
     pub content: String,
     pub created_at: String,
+    #[serde(default)]
+    pub sources: Vec<MessageSource>,
+    #[serde(default)]
+    pub status: MessageStatus,
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
 }
@@ -0,0 +1,31 @@
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ModelCapabilities {
+    /// Answers can be streamed piece by piece.
+    pub streaming: bool,
+    /// Reasoning models pick their own output length and reject `max_tokens`.
+    #[serde(default)]
+    pub reasoning: bool,
+    #[serde(default)]
+    pub vision: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LlmModel {
+    pub id: String,
+    /// Id of the provider serving the model, e.g. `anthropic`.
+    pub provider: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Tokens of prompt and answer together.
+    pub context_window: usize,
+    pub max_output_tokens: usize,
+    /// Prices in USD per million tokens.
+    #[serde(default)]
+    pub input_price: f64,
+    #[serde(default)]
+    pub output_price: f64,
+    pub capabilities: ModelCapabilities,
+}
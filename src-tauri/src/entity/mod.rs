@@ -2,7 +2,11 @@ pub mod activity_item;
 pub mod chat_item;
 pub mod element_details;
 pub mod keypress_log;
+pub mod llm_model;
 pub mod macos_element_details;
 pub mod permission;
 pub mod setting;
 pub mod project;
+pub mod search_filter;
+pub mod usage_event;
+pub mod vector_chunk;
@@ -0,0 +1,43 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::entity::vector_chunk::SourceType;
+
+/// Restricts retrieval to a subset of the indexed sources. Unset fields don't filter.
+///
+/// Dates are compared by day, both `YYYY-MM-DD` and full timestamps are accepted and the range
+/// includes both ends. Project documents take the app name and date of the activity they were
+/// created from, or the project's creation date. Chat messages have no project or app.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SearchFilter {
+    pub project_id: Option<i64>,
+    pub source_types: Option<Vec<SourceType>>,
+    pub app_name: Option<String>,
+    pub date_from: Option<String>,
+    pub date_to: Option<String>,
+}
+
+impl SearchFilter {
+    pub fn for_project(project_id: Option<i64>) -> Self {
+        SearchFilter {
+            project_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.project_id.is_none()
+            && self.source_types.is_none()
+            && self.app_name.is_none()
+            && self.date_from.is_none()
+            && self.date_to.is_none()
+    }
+}
+
+/// Where a source belongs and when it was captured.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SourceDetails {
+    pub project_id: Option<i64>,
+    pub project_name: Option<String>,
+    pub date: Option<String>,
+}
@@ -0,0 +1,115 @@
+use std::str::FromStr;
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde_derive::{Deserialize, Serialize};
+
+/// What a model was called for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsagePurpose {
+    Answer,
+    /// Picking the retrieved documents that are relevant to the prompt.
+    RelevanceFilter,
+    ConversationName,
+    Embedding,
+}
+
+impl UsagePurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UsagePurpose::Answer => "answer",
+            UsagePurpose::RelevanceFilter => "relevance_filter",
+            UsagePurpose::ConversationName => "conversation_name",
+            UsagePurpose::Embedding => "embedding",
+        }
+    }
+}
+
+impl FromStr for UsagePurpose {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "answer" => Ok(UsagePurpose::Answer),
+            "relevance_filter" => Ok(UsagePurpose::RelevanceFilter),
+            "conversation_name" => Ok(UsagePurpose::ConversationName),
+            "embedding" => Ok(UsagePurpose::Embedding),
+            _ => Err(format!("Unknown usage purpose: {}", value)),
+        }
+    }
+}
+
+impl ToSql for UsagePurpose {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for UsagePurpose {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|_| FromSqlError::InvalidType)
+    }
+}
+
+/// The chat and project a call was made for, if any.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UsageScope {
+    pub chat_id: Option<i64>,
+    pub project_id: Option<i64>,
+}
+
+/// One call to an LLM or embedding model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageEvent {
+    pub provider: String,
+    pub model: String,
+    pub purpose: UsagePurpose,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    /// USD, from the prices of the model at the time of the call.
+    pub cost: f64,
+    pub scope: UsageScope,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct UsageTotals {
+    pub calls: i64,
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    pub cost: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DailyUsage {
+    /// Local date, `YYYY-MM-DD`.
+    pub day: String,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatUsage {
+    pub chat_id: i64,
+    /// `None` once the chat is deleted, its usage is kept.
+    pub chat_name: Option<String>,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProjectUsage {
+    pub project_id: i64,
+    pub project_name: Option<String>,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BudgetStatus {
+    /// USD per calendar month, `None` when calls aren't limited.
+    pub monthly_budget: Option<f64>,
+    pub spent_this_month: f64,
+}
@@ -0,0 +1,67 @@
+use std::str::FromStr;
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite_from_row::FromRow;
+use serde_derive::{Deserialize, Serialize};
+
+/// Table a vector's text was taken from. Ids of different sources overlap, so a source id is
+/// only meaningful together with its type.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceType {
+    /// A row of `activity_full_text`
+    Activity,
+    /// A row of `projects_activities`
+    ProjectDocument,
+    /// A row of `messages`
+    ChatMessage,
+}
+
+impl SourceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceType::Activity => "activity",
+            SourceType::ProjectDocument => "project_document",
+            SourceType::ChatMessage => "chat_message",
+        }
+    }
+}
+
+impl FromStr for SourceType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "activity" => Ok(SourceType::Activity),
+            "project_document" => Ok(SourceType::ProjectDocument),
+            "chat_message" => Ok(SourceType::ChatMessage),
+            _ => Err(format!("Unknown source type: {}", value)),
+        }
+    }
+}
+
+impl ToSql for SourceType {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for SourceType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|_| FromSqlError::InvalidType)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+pub struct VectorChunk {
+    pub id: i64,
+    pub source_type: SourceType,
+    pub source_id: i64,
+    pub chunk_index: i64,
+    pub start_offset: i64,
+    pub end_offset: i64,
+    pub chunk_text: String,
+}
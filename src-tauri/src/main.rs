@@ -9,7 +9,7 @@ use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use lazy_static::lazy_static;
-use log::info;
+use log::{error, info};
 use rusqlite::Connection;
 use rusqlite::params;
 use serde_derive::Serialize;
@@ -18,24 +18,35 @@ use tauri::SystemTray;
 use tauri::{AppHandle, Manager, State, SystemTrayEvent, WindowUrl};
 use tauri::{CustomMenuItem, SystemTrayMenu};
 use tauri_plugin_log::LogTarget;
-use tokio::sync::Mutex;
+use tokio::sync::RwLock;
 
 use configuration::settings::Settings;
 
 use crate::bootstrap::{fix_path_env, prerequisites, setup_directories};
 use crate::configuration::database;
-use crate::configuration::database::drop_database_handle;
+use crate::configuration::database::close_vector_database;
 use crate::configuration::state::{AppState, ServiceAccess};
-use crate::engine::chat_engine::{name_conversation, send_prompt_to_llm};
-use crate::engine::chat_engine_openai::{generate_conversation_name, send_prompt_to_openai};
+use crate::engine::chat_engine::{
+    cancel_generation, generate_conversation_name, recover_interrupted_generations, send_prompt,
+};
 use crate::engine::clean_up_engine::clean_up;
+use crate::engine::deduplication_engine::{get_duplicate_clusters, register_fingerprint};
+use crate::engine::embedding_engine::{get_embedding_provider, get_scoped_embedding_provider};
+use crate::engine::model_registry_engine::list_models;
 use crate::engine::monitoring_engine;
-use crate::engine::similarity_search_engine::SyncSimilaritySearch;
+use crate::engine::reindex_engine::{cancel_reindex, pause_reindex, resume_reindex, start_reindex};
+use crate::engine::search_engine::{get_related_notes, search_notes};
+use crate::engine::similarity_search_engine::{IndexParams, SyncSimilaritySearch};
+use crate::engine::usage_engine::{
+    get_usage_budget, get_usage_by_chat, get_usage_by_day, get_usage_by_project,
+};
 use crate::entity::activity_item::ActivityItem;
-use crate::entity::chat_item::{Chat, StoredMessage};
+use crate::entity::chat_item::{Chat, MessageSource, StoredMessage};
 use crate::entity::permission::Permission;
 use crate::entity::project::Project;
 use crate::entity::setting::Setting;
+use crate::entity::usage_event::UsageScope;
+use crate::entity::vector_chunk::SourceType;
 use crate::permissions::permission_engine::init_permissions;
 use crate::repository::activity_log_repository;
 use crate::repository::chat_db_repository;
@@ -44,6 +55,7 @@ use crate::repository::project_repository::{
     delete_project, fetch_all_projects, add_blank_document, save_project, update_project, 
     get_activity_text_from_project, update_activity_text, update_activity_name, delete_project_document, 
     ensure_unassigned_project, move_document_to_project, mark_document_as_vectorized,
+    mark_document_as_not_vectorized, fetch_activities_by_project_id, VectorIndexUpdate,
 };
 use crate::repository::settings_repository::{get_setting, get_settings, insert_or_update_setting, update_setting_async};
 use tauri_plugin_autostart::MacosLauncher;
@@ -68,8 +80,9 @@ const USE_LOCALHOST_SERVER: bool = false;
 const USE_LOCALHOST_SERVER: bool = true;
 
 lazy_static! {
-    static ref HNSW: SyncSimilaritySearch = Arc::new(Mutex::new(None));
+    static ref HNSW: SyncSimilaritySearch = Arc::new(RwLock::new(None));
     static ref IS_RECORDING: AtomicBool = AtomicBool::new(false);
+    static ref IS_QUITTING: AtomicBool = AtomicBool::new(false);
     static ref RECORDING_PATH: Arc<std::sync::Mutex<Option<String>>> = Arc::new(std::sync::Mutex::new(None));
 }
 
@@ -129,7 +142,7 @@ async fn main() {
                     }
                 }
                 "quit" => {
-                    std::process::exit(0);
+                    quit_gracefully();
                 }
                 _ => {}
             },
@@ -139,11 +152,15 @@ async fn main() {
             refresh_activity_log,
             update_settings,
             get_latest_settings,
-            send_prompt_to_llm,
-            send_prompt_to_openai,
+            send_prompt,
+            cancel_generation,
             generate_conversation_name,
             record_single_activity,
-            name_conversation,
+            list_models,
+            get_usage_by_day,
+            get_usage_by_chat,
+            get_usage_by_project,
+            get_usage_budget,
             create_chat,
             get_all_chats,
             create_message,
@@ -174,6 +191,14 @@ async fn main() {
             read_audio_file,
             get_openai_api_key,
             extract_document_text,
+            rebuild_vector_index,
+            start_reindex,
+            pause_reindex,
+            resume_reindex,
+            cancel_reindex,
+            search_notes,
+            get_related_notes,
+            get_duplicate_clusters,
         ])
         .manage(AppState {
             db: Default::default(),
@@ -216,12 +241,29 @@ async fn main() {
             );
             clean_up(app_handle.path_resolver().app_data_dir().unwrap());
             setup_keypress_listener(&app_handle);
+            recover_interrupted_generations(&app_handle);
             init_app_permissions(app_handle);
             Ok(())
         })
-        .run(context)
-        .expect("error while running tauri application");
-    drop_database_handle().await;
+        .build(context)
+        .expect("error while building tauri application")
+        .run(|_app_handle, event| {
+            if let tauri::RunEvent::ExitRequested { api, .. } = event {
+                api.prevent_exit();
+                quit_gracefully();
+            }
+        });
+}
+
+/// Flushes the vector index, then exits. Every way of quitting goes through here.
+fn quit_gracefully() {
+    if IS_QUITTING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    tauri::async_runtime::spawn(async {
+        close_vector_database().await;
+        std::process::exit(0);
+    });
 }
 
 fn build_system_tray() -> SystemTray {
@@ -319,6 +361,67 @@ async fn update_settings(app_handle: AppHandle, settings: Settings) {
             setting_value: format!("{}", settings.vectorization_enabled),
         },
     ).await.unwrap_or(());
+
+    // Update embedding, vector index, custom LLM endpoint and model catalogue settings, only
+    // when the frontend sent them
+    let optional_settings = [
+        ("embedding_provider", settings.embedding_provider),
+        ("embedding_model", settings.embedding_model),
+        ("embedding_base_url", settings.embedding_base_url),
+        ("embedding_api_key", settings.embedding_api_key),
+        ("embedding_model_path", settings.embedding_model_path),
+        ("hnsw_m", settings.hnsw_m),
+        ("hnsw_ef_construction", settings.hnsw_ef_construction),
+        ("hnsw_ef_search", settings.hnsw_ef_search),
+        ("hnsw_max_elements", settings.hnsw_max_elements),
+        ("retrieval_top_k", settings.retrieval_top_k),
+        ("custom_llm_base_url", settings.custom_llm_base_url),
+        ("custom_llm_model", settings.custom_llm_model),
+        ("custom_llm_api_key", settings.custom_llm_api_key),
+        ("custom_llm_headers", settings.custom_llm_headers),
+        ("llm_models", settings.llm_models),
+        ("monthly_budget", settings.monthly_budget),
+    ];
+    for (setting_key, setting_value) in optional_settings {
+        if let Some(setting_value) = setting_value {
+            update_setting_async(
+                &app_handle,
+                Setting {
+                    setting_key: String::from(setting_key),
+                    setting_value,
+                },
+            ).await.unwrap_or(());
+        }
+    }
+}
+
+/// Re-creates the vector index with the index parameters currently in the settings.
+///
+/// Emits `vector_index_rebuild_progress` events while the graph is rebuilt and returns the
+/// number of vectors in the new index.
+#[tauri::command]
+async fn rebuild_vector_index(app_handle: AppHandle) -> Result<usize, String> {
+    let params = app_handle.db(|db| IndexParams::load(db));
+    info!("Rebuilding vector index with {:?}", params);
+
+    let db = database::get_vector_db(&app_handle)
+        .await
+        .map_err(|e| format!("Failed to open vector index: {}", e))?;
+
+    let window = app_handle
+        .get_window("main")
+        .ok_or("Failed to get main window")?;
+    let mut vector_count = 0;
+    db.rebuild(params, |progress| {
+        vector_count = progress.total;
+        if let Err(e) = window.emit("vector_index_rebuild_progress", progress) {
+            error!("Failed to emit rebuild progress: {}", e);
+        }
+    })
+    .await
+    .map_err(|e| format!("Failed to rebuild vector index: {}", e))?;
+
+    Ok(vector_count)
 }
 
 #[tauri::command]
@@ -356,19 +459,61 @@ fn save_app_project(
 }
 
 #[tauri::command]
-fn update_app_project(
+async fn update_app_project(
     app_handle: AppHandle,
     id: i64,
     name: &str,
     activities: Vec<i64>,
 ) -> Result<Vec<i64>, ()> {
+    // Replacing the activities re-creates the project documents, so their vectors go stale
+    let mut promoted_ids = Vec::new();
+    if !activities.is_empty() {
+        let (document_ids, _, _) = app_handle
+            .db(|database| fetch_activities_by_project_id(database, id))
+            .unwrap_or_default();
+        for document_id in document_ids {
+            let promoted_id = activity_log_repository::remove_document_from_vector_db(
+                &app_handle,
+                SourceType::ProjectDocument,
+                document_id,
+            )
+            .await
+            .unwrap_or(None);
+            promoted_ids.extend(promoted_id);
+        }
+    }
     app_handle.db(|database| update_project(database, id, name, &activities).unwrap());
+    activity_log_repository::embed_promoted_duplicates(
+        &app_handle,
+        SourceType::ProjectDocument,
+        promoted_ids,
+    );
     return Ok(activities);
 }
 
 #[tauri::command]
-fn delete_app_project(app_handle: AppHandle, project_id: i64) -> Result<i64, ()> {
+async fn delete_app_project(app_handle: AppHandle, project_id: i64) -> Result<i64, ()> {
+    let (document_ids, _, _) = app_handle
+        .db(|database| fetch_activities_by_project_id(database, project_id))
+        .unwrap_or_default();
+    let mut promoted_ids = Vec::new();
+    for document_id in document_ids {
+        let promoted_id = activity_log_repository::remove_document_from_vector_db(
+            &app_handle,
+            SourceType::ProjectDocument,
+            document_id,
+        )
+        .await
+        .unwrap_or(None);
+        promoted_ids.extend(promoted_id);
+    }
     app_handle.db(|database| delete_project(database, project_id).unwrap());
+    // Near-duplicates within the project are gone by now and get skipped
+    activity_log_repository::embed_promoted_duplicates(
+        &app_handle,
+        SourceType::ProjectDocument,
+        promoted_ids,
+    );
     return Ok(project_id);
 }
 
@@ -404,23 +549,43 @@ async fn record_single_activity(
         .db(|db| activity_log_repository::save_activity_full_text(&activity_item.clone(), db))
         .expect("Failed to save activity full text");
 
-    let settings =
-        app_handle.db(|db| get_setting(db, "api_key_open_ai").expect("Failed on api_key_open_ai"));
-    match last_insert_rowid {
-        Some(rowid) => {
-            info!("Getting ready to add record to OasysDB, row={}", rowid);
-            let mut oasys_db = database::get_vector_db(&app_handle)
-                .await
-                .expect("Database initialization failed!");
-            activity_log_repository::save_activity_full_text_into_vector_db(
-                &mut oasys_db,
-                &activity_item,
-                rowid,
-                &settings.setting_value,
+    // Near-duplicates of an indexed activity are linked to it instead of being embedded again
+    let last_insert_rowid = last_insert_rowid.filter(|rowid| {
+        match app_handle.db(|db| {
+            register_fingerprint(
+                db,
+                SourceType::Activity,
+                *rowid,
+                &activity_item.full_activity_text,
             )
-            .await
-            .unwrap_or(());
+        }) {
+            Ok(Some(duplicate_of)) => {
+                info!("Activity row={} is a near-duplicate of row={}", rowid, duplicate_of);
+                false
+            }
+            Ok(None) => true,
+            Err(e) => {
+                error!("Failed to fingerprint activity row={}: {}", rowid, e);
+                true
+            }
         }
+    });
+
+    match last_insert_rowid {
+        Some(rowid) => match get_embedding_provider(&app_handle) {
+            Ok(embedding_provider) => {
+                info!("Getting ready to add record to OasysDB, row={}", rowid);
+                activity_log_repository::save_activity_full_text_into_vector_db(
+                    &app_handle,
+                    &activity_item,
+                    rowid,
+                    embedding_provider.as_ref(),
+                )
+                .await
+                .unwrap_or(());
+            }
+            Err(e) => info!("Skipping vectorization of row={}: {}", rowid, e),
+        },
         None => info!("No last insert rowid available"),
     }
 
@@ -453,9 +618,11 @@ fn create_message(
     chat_id: i64,
     role: &str,
     content: &str,
+    sources: Option<Vec<MessageSource>>,
 ) -> Result<i64, String> {
+    let sources = sources.unwrap_or_default();
     app_handle
-        .db(|db| chat_db_repository::create_message(db, chat_id, role, content))
+        .db(|db| chat_db_repository::create_message(db, chat_id, role, content, &sources))
         .map_err(|e| e.to_string())
 }
 
@@ -497,10 +664,23 @@ fn get_activity_history(
 }
 
 #[tauri::command]
-fn delete_activity(app_handle: AppHandle, id: i64) -> Result<bool, String> {
-    app_handle
+async fn delete_activity(app_handle: AppHandle, id: i64) -> Result<bool, String> {
+    let promoted_id = activity_log_repository::remove_document_from_vector_db(
+        &app_handle,
+        SourceType::Activity,
+        id,
+    )
+    .await
+    .unwrap_or(None);
+    let deleted = app_handle
         .db(|db: &Connection| crate::activity_log_repository::delete_activity(db, id))
-        .map_err(|e| e.to_string())
+        .map_err(|e| e.to_string())?;
+    activity_log_repository::embed_promoted_duplicates(
+        &app_handle,
+        SourceType::Activity,
+        promoted_id.into_iter().collect(),
+    );
+    Ok(deleted)
 }
 
 #[tauri::command]
@@ -546,11 +726,28 @@ async fn update_project_activity_text(
     info!("Updating text for project activity ID: {}, length: {}", activity_id, text.len());
     
     // Update the document text and check if vectorization is needed
-    let needs_vectorization = app_handle
+    let index_update = app_handle
         .db(|db| update_activity_text(db, activity_id, text))
         .map_err(|e| e.to_string())?;
     
-    if needs_vectorization {
+    if index_update == VectorIndexUpdate::Remove {
+        info!("Document ID: {} is too short now, removing it from vector DB", activity_id);
+        let promoted_id = activity_log_repository::remove_document_from_vector_db(
+            &app_handle,
+            SourceType::ProjectDocument,
+            activity_id,
+        )
+        .await
+        .unwrap_or(None);
+        app_handle
+            .db(|db| mark_document_as_not_vectorized(db, activity_id))
+            .map_err(|e| e.to_string())?;
+        activity_log_repository::embed_promoted_duplicates(
+            &app_handle,
+            SourceType::ProjectDocument,
+            promoted_id.into_iter().collect(),
+        );
+    } else if index_update == VectorIndexUpdate::Index {
         info!("Document ID: {} meets conditions for vectorization, checking settings", activity_id);
         
         // Check if vectorization is enabled in settings
@@ -562,58 +759,60 @@ async fn update_project_activity_text(
             Err(_) => true // Default to enabled if setting doesn't exist
         };
         
-        // Get API key
-        let api_key_result = app_handle
-            .db(|db| get_setting(db, "api_key_open_ai"))
-            .map_err(|e| e.to_string());
-        
-        let api_key = match api_key_result {
-            Ok(setting) => setting.setting_value,
-            Err(_) => String::new()
-        };
-        
-        // Only proceed with vectorization if it's enabled and API key exists
+        // Only proceed with vectorization if it's enabled and an embedding provider is configured
         if !vectorization_enabled {
             info!("Vectorization disabled in settings, skipping for document ID: {}", activity_id);
             return Ok(());
         }
         
-        // Skip if API key is missing or empty
-        if api_key.is_empty() {
-            info!("API key missing or empty, skipping vectorization for document ID: {}", activity_id);
-            return Ok(());
-        }
-        
-        // Get document name for vector DB
-        let document_name = app_handle
+        // Get document name for vector DB, the embedding usage is recorded for the project
+        let (document_name, project_id) = app_handle
             .db(|db| {
                 db.query_row(
-                    "SELECT document_name FROM projects_activities WHERE id = ?1",
+                    "SELECT document_name, project_id FROM projects_activities WHERE id = ?1",
                     params![activity_id],
-                    |row| row.get::<_, String>(0)
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
                 )
             })
             .map_err(|e| e.to_string())?;
+        let scope = UsageScope {
+            chat_id: None,
+            project_id: Some(project_id),
+        };
+        let embedding_provider = match get_scoped_embedding_provider(&app_handle, scope) {
+            Ok(provider) => provider,
+            Err(e) => {
+                info!("Embedding provider unavailable ({}), skipping vectorization for document ID: {}", e, activity_id);
+                return Ok(());
+            }
+        };
         
-        // Initialize vector DB - exactly as in record_single_activity
-        info!("Initializing vector database for document ID: {}", activity_id);
-        let mut oasys_db = database::get_vector_db(&app_handle)
-            .await
-            .expect("Database initialization failed!");
+        match app_handle.db(|db| register_fingerprint(db, SourceType::ProjectDocument, activity_id, text)) {
+            Ok(Some(duplicate_of)) => info!("Document ID: {} is a near-duplicate of document ID: {}", activity_id, duplicate_of),
+            Ok(None) => {}
+            Err(e) => error!("Failed to fingerprint document ID: {}: {}", activity_id, e),
+        }
         
         // Add to vector DB
         info!("Adding document ID: {} to vector DB", activity_id);
-        activity_log_repository::save_project_document_into_vector_db(
-            &mut oasys_db,
+        let saved = activity_log_repository::save_project_document_into_vector_db(
+            &app_handle,
             activity_id,
             &document_name,
             text,
-            &api_key,
+            embedding_provider.as_ref(),
         )
         .await
-        .unwrap_or(());
+        .map_err(|e| e.to_string());
         
-        // Mark as vectorized
+        // A document that failed to embed is left unvectorized, so the next save retries it
+        if let Err(e) = saved {
+            error!("Failed to vectorize document ID: {}: {}", activity_id, e);
+            app_handle
+                .db(|db| mark_document_as_not_vectorized(db, activity_id))
+                .map_err(|e| e.to_string())?;
+            return Ok(());
+        }
         app_handle
             .db(|db| mark_document_as_vectorized(db, activity_id))
             .map_err(|e| e.to_string())?;
@@ -660,13 +859,26 @@ fn update_project_activity_name(
 }
 
 #[tauri::command]
-fn delete_project_activity(
+async fn delete_project_activity(
     app_handle: AppHandle,
     activity_id: i64,
 ) -> Result<(), String> {
+    let promoted_id = activity_log_repository::remove_document_from_vector_db(
+        &app_handle,
+        SourceType::ProjectDocument,
+        activity_id,
+    )
+    .await
+    .unwrap_or(None);
     app_handle
         .db(|db| delete_project_document(db, activity_id))
-        .map_err(|e| e.to_string())
+        .map_err(|e| e.to_string())?;
+    activity_log_repository::embed_promoted_duplicates(
+        &app_handle,
+        SourceType::ProjectDocument,
+        promoted_id.into_iter().collect(),
+    );
+    Ok(())
 }
 
 #[cfg(target_os = "macos")]
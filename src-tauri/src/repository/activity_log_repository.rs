@@ -1,14 +1,26 @@
 use std::error::Error;
 
 use chrono::Local;
-use fuzzy_matcher::skim::SkimMatcherV2;
-use fuzzy_matcher::FuzzyMatcher;
+use log::{error, info};
 use rusqlite::{named_params, Connection};
 use rusqlite_from_row::FromRow;
-use std::collections::HashSet;
 
-use crate::configuration::database::SyncVectorDatabase;
+use tauri::AppHandle;
+
+use crate::configuration::database::{get_vector_collection_model, lock_vector_db};
+use crate::configuration::state::ServiceAccess;
+use crate::engine::chunking_engine::{chunk_text, ChunkingOptions};
+use crate::engine::deduplication_engine::unregister_fingerprint;
+use crate::engine::embedding_engine::{
+    content_hash, embed_texts, get_embedding_provider, EmbeddingProvider,
+};
+use crate::engine::retrieval_engine::get_source_document;
 use crate::entity::activity_item::ActivityItem;
+use crate::entity::vector_chunk::SourceType;
+use crate::repository::vector_db_repository::{
+    delete_vector_chunks_by_source, get_vector_chunk_ids_by_source, insert_vector_chunks,
+    save_vector_embeddings, source_exists,
+};
 
 pub fn save_activity_item(
     activity_item: &ActivityItem,
@@ -94,58 +106,192 @@ pub fn save_activity_full_text(
     Ok(None)
 }
 pub async fn save_activity_full_text_into_vector_db(
-    oasys_db: &SyncVectorDatabase,
+    app_handle: &AppHandle,
     activity_item: &ActivityItem,
     last_insert_rowid: i64,
-    api_key: &str,
+    provider: &dyn EmbeddingProvider,
 ) -> Result<(), Box<dyn Error>> {
-    let id = last_insert_rowid;
-    let max_length = 5000;
-    let truncated_text = if activity_item.full_activity_text.len() > max_length {
-        &activity_item.full_activity_text[..max_length]
-    } else {
-        &activity_item.full_activity_text
-    };
-
-    // Add the window_title to the beginning and end of the truncated_text
-    let amplified_text = format!(
-        "Document Title: [{}] {} Document Title: [{}]",
-        activity_item.window_title, truncated_text, activity_item.window_title
-    );
-
-    let mut db_guard = oasys_db.lock().await;
-    let db = db_guard.as_mut().expect("Database initialization failed!");
-    db.add(id, &amplified_text, api_key).await?;
-    db.sync().await?;
-    Ok(())
+    save_document_chunks_into_vector_db(
+        app_handle,
+        SourceType::Activity,
+        last_insert_rowid,
+        &activity_item.window_title,
+        &activity_item.full_activity_text,
+        provider,
+    )
+    .await
 }
 
 pub async fn save_project_document_into_vector_db(
-    oasys_db: &SyncVectorDatabase,
+    app_handle: &AppHandle,
     document_id: i64,
     document_name: &str,
     document_text: &str,
-    api_key: &str,
+    provider: &dyn EmbeddingProvider,
 ) -> Result<(), Box<dyn Error>> {
-    let id = document_id;
-    let max_length = 5000;
-    let truncated_text = if document_text.len() > max_length {
-        &document_text[..max_length]
+    save_document_chunks_into_vector_db(
+        app_handle,
+        SourceType::ProjectDocument,
+        document_id,
+        document_name,
+        document_text,
+        provider,
+    )
+    .await
+}
+
+/// Splits the document into chunks and replaces whatever was indexed for it before.
+async fn save_document_chunks_into_vector_db(
+    app_handle: &AppHandle,
+    source_type: SourceType,
+    source_id: i64,
+    document_name: &str,
+    document_text: &str,
+    provider: &dyn EmbeddingProvider,
+) -> Result<(), Box<dyn Error>> {
+    let options = app_handle.db(|db| ChunkingOptions::load(db));
+    let chunks = chunk_text(document_text, &options);
+
+    // Embedding is the slow part, it happens before anything is locked or written
+    let texts: Vec<String> = chunks
+        .iter()
+        .map(|chunk| get_chunk_embedding_text(document_name, &chunk.text))
+        .collect();
+    let vectors = embed_texts(app_handle, provider, &texts).await?;
+
+    let index = lock_vector_db(app_handle).await?;
+    // The rows are swapped in one transaction, so concurrent edits of a document can't
+    // interleave and a failed write keeps the previous chunks. Their index updates may arrive in
+    // either order, chunk ids are never reused.
+    let (previous_chunk_ids, embeddings) = app_handle.db(|db| {
+        let transaction = db.unchecked_transaction()?;
+        let previous_chunk_ids = get_vector_chunk_ids_by_source(db, source_type, source_id)?;
+        delete_vector_chunks_by_source(db, source_type, source_id)?;
+        // A source deleted while it was being embedded stays out of the index
+        let embeddings = if source_exists(db, source_type, source_id)? {
+            let chunk_ids = insert_vector_chunks(db, source_type, source_id, &chunks)?;
+            let embeddings: Vec<(i64, String, Vec<f32>)> = chunk_ids
+                .into_iter()
+                .zip(texts.iter())
+                .zip(vectors)
+                .map(|((chunk_id, text), vector)| (chunk_id, content_hash(text), vector))
+                .collect();
+            // The vectors are kept in SQLite as well, so the graph can be rebuilt without the
+            // provider
+            save_vector_embeddings(db, provider.model_name(), &embeddings)?;
+            embeddings
+        } else {
+            Vec::new()
+        };
+        transaction.commit()?;
+        Ok::<_, rusqlite::Error>((previous_chunk_ids, embeddings))
+    })?;
+
+    // While the index is re-embedded with another model, the new vectors only go to SQLite.
+    // The re-embedding job picks them up when it builds the new collection.
+    let index_model = app_handle.db(|db| get_vector_collection_model(db));
+    let items = if index_model.map_or(true, |model| model == provider.model_name()) {
+        embeddings
+            .into_iter()
+            .map(|(chunk_id, _, vector)| (chunk_id, vector))
+            .collect()
     } else {
-        document_text
+        Vec::new()
     };
+    index.replace(&previous_chunk_ids, items).await?;
+    Ok(())
+}
 
-    // Format the text with document name for better searchability
-    let amplified_text = format!(
+/// The text a chunk is embedded as, with the document name added to its beginning and end.
+pub fn get_chunk_embedding_text(document_name: &str, chunk_text: &str) -> String {
+    format!(
         "Document Title: [{}] {} Document Title: [{}]",
-        document_name, truncated_text, document_name
-    );
+        document_name, chunk_text, document_name
+    )
+}
 
-    let mut db_guard = oasys_db.lock().await;
-    let db = db_guard.as_mut().expect("Database initialization failed!");
-    db.add(id, &amplified_text, api_key).await?;
-    db.sync().await?;
-    Ok(())
+/// Drops every vector of the source from the index, together with its chunk rows.
+///
+/// Returns the near-duplicate that takes the place of the source, it has no vectors yet as
+/// long as it duplicated an embedded source. Callers pass it to [`embed_promoted_duplicates`]
+/// once the source itself is deleted.
+pub async fn remove_document_from_vector_db(
+    app_handle: &AppHandle,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<Option<i64>, Box<dyn Error>> {
+    let index = lock_vector_db(app_handle).await?;
+    let (chunk_ids, promoted_id) = app_handle.db(|db| {
+        let chunk_ids = get_vector_chunk_ids_by_source(db, source_type, source_id)?;
+        delete_vector_chunks_by_source(db, source_type, source_id)?;
+        let promoted_id = unregister_fingerprint(db, source_type, source_id)?;
+        Ok::<_, rusqlite::Error>((chunk_ids, promoted_id))
+    })?;
+    if !chunk_ids.is_empty() {
+        index.remove(&chunk_ids).await?;
+    }
+    Ok(promoted_id)
+}
+
+/// Embeds the near-duplicates [`remove_document_from_vector_db`] promoted, in the background.
+/// Ones deleted in the meantime are skipped.
+pub fn embed_promoted_duplicates(
+    app_handle: &AppHandle,
+    source_type: SourceType,
+    source_ids: Vec<i64>,
+) {
+    if source_ids.is_empty() {
+        return;
+    }
+    let app_handle = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        for source_id in source_ids {
+            if let Err(e) = embed_promoted_duplicate(&app_handle, source_type, source_id).await {
+                error!(
+                    "Failed to embed near-duplicate {} {}: {}",
+                    source_type.as_str(),
+                    source_id,
+                    e
+                );
+            }
+        }
+    });
+}
+
+/// Embeds a source that stopped being a near-duplicate, unless it already has its vectors.
+async fn embed_promoted_duplicate(
+    app_handle: &AppHandle,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<(), Box<dyn Error>> {
+    if !app_handle
+        .db(|db| get_vector_chunk_ids_by_source(db, source_type, source_id))?
+        .is_empty()
+    {
+        return Ok(());
+    }
+    let document = match source_type {
+        SourceType::Activity => app_handle.db(|db| get_activity_text_by_id(db, source_id, None))?,
+        _ => app_handle.db(|db| get_source_document(db, source_type, source_id))?,
+    };
+    let Some((document_name, document_text)) = document else {
+        return Ok(());
+    };
+    let provider = get_embedding_provider(app_handle)?;
+    info!(
+        "Embedding {} {}, its near-duplicate was deleted",
+        source_type.as_str(),
+        source_id
+    );
+    save_document_chunks_into_vector_db(
+        app_handle,
+        source_type,
+        source_id,
+        &document_name,
+        &document_text,
+        provider.as_ref(),
+    )
+    .await
 }
 
 pub fn get_all_activity_logs(db: &Connection) -> Result<Vec<ActivityItem>, rusqlite::Error> {
@@ -268,53 +414,33 @@ pub fn get_activity_full_text_by_id(
     }
 }
 
-pub fn get_additional_ids_from_sql_db(
+/// Window title and captured text of an activity, without the header
+/// `get_activity_full_text_by_id` puts in front of the text.
+pub fn get_activity_text_by_id(
     db: &Connection,
-    num_recent_entries: usize,
-    keywords: &[String],
-) -> Result<Vec<i64>, Box<dyn Error>> {
-    let matcher = SkimMatcherV2::default();
-    let mut stmt = db.prepare(
-        "SELECT id, window_title
-         FROM activity_full_text
-         ORDER BY dateofentry DESC
-         LIMIT 500",
-    )?;
-
-    let rows: Vec<(i64, String)> = stmt
-        .query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?
-        .collect::<Result<_, _>>()?;
-
-    let mut top_ids: HashSet<i64> = HashSet::new();
-
-    for keyword in keywords {
-        let mut best_match = None;
-        let mut best_score = 0;
-
-        for (id, title) in &rows {
-            if let Some(score) = matcher.fuzzy_match(&title, keyword) {
-                if score > best_score {
-                    best_score = score;
-                    best_match = Some(*id);
-                }
-            }
-        }
+    id: i64,
+    max_length: Option<usize>,
+) -> Result<Option<(String, String)>, rusqlite::Error> {
+    let query = "SELECT window_title, edited_full_text FROM activity_full_text WHERE rowid = ?";
+    let result = db.query_row(query, [id], |row| {
+        let window_title: String = row.get(0)?;
+        let edited_full_text: String = row.get(1)?;
+        Ok((window_title, edited_full_text))
+    });
 
-        if let Some(id) = best_match {
-            top_ids.insert(id);
+    match result {
+        Ok((window_title, edited_full_text)) => {
+            let text = match max_length {
+                Some(length) => edited_full_text.chars().take(length).collect::<String>(),
+                None => edited_full_text,
+            };
+            Ok(Some((window_title, text)))
         }
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
     }
-
-    // Get the most recent entries
-    let mut recent_entries_stmt =
-        db.prepare("SELECT id FROM activity_full_text ORDER BY dateofentry DESC LIMIT ?")?;
-    let recent_entries = recent_entries_stmt.query_map([num_recent_entries], |row| row.get(0))?;
-    for entry in recent_entries {
-        top_ids.insert(entry?);
-    }
-
-    Ok(top_ids.into_iter().collect())
 }
+
 pub fn get_activity_history(
     db: &Connection,
     offset: usize,
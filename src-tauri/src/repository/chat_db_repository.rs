@@ -1,5 +1,5 @@
-use crate::entity::chat_item::{Chat, StoredMessage};
-use rusqlite::{params, Connection, Error, Result};
+use crate::entity::chat_item::{Chat, MessageSource, MessageStatus, StoredMessage};
+use rusqlite::{params, Connection, Error, Result, Row};
 use chrono::Local;
 
 pub fn create_chat(db: &Connection, name: &str) -> Result<i64, Error> {
@@ -24,29 +24,133 @@ pub fn get_all_chats(db: &Connection) -> Result<Vec<Chat>, Error> {
     Ok(chats.collect::<Result<_, _>>()?)
 }
 
-pub fn create_message(db: &Connection, chat_id: i64, role: &str, content: &str) -> Result<i64, Error> {
+/// Sources are stored as a JSON array, NULL when the message cites none.
+fn sources_to_column(sources: &[MessageSource]) -> Result<Option<String>, Error> {
+    if sources.is_empty() {
+        return Ok(None);
+    }
+    serde_json::to_string(sources)
+        .map(Some)
+        .map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+/// Columns `message_from_row` reads, in order.
+const MESSAGE_COLUMNS: &str =
+    "id, chat_id, role, content, created_at, sources, status, input_tokens, output_tokens";
+
+/// Reads a message from the `MESSAGE_COLUMNS` starting at column `offset`.
+fn message_from_row(row: &Row, offset: usize) -> Result<StoredMessage, Error> {
+    let sources: Option<String> = row.get(offset + 5)?;
+    Ok(StoredMessage {
+        id: row.get(offset)?,
+        chat_id: row.get(offset + 1)?,
+        role: row.get(offset + 2)?,
+        content: row.get(offset + 3)?,
+        created_at: row.get(offset + 4)?,
+        sources: sources
+            .and_then(|sources| serde_json::from_str(&sources).ok())
+            .unwrap_or_default(),
+        status: row.get(offset + 6)?,
+        input_tokens: row.get(offset + 7)?,
+        output_tokens: row.get(offset + 8)?,
+    })
+}
+
+pub fn create_message(
+    db: &Connection,
+    chat_id: i64,
+    role: &str,
+    content: &str,
+    sources: &[MessageSource],
+) -> Result<i64, Error> {
+    let now = Local::now().to_rfc3339();
+    db.execute(
+        "INSERT INTO messages (chat_id, role, content, created_at, sources) VALUES (?, ?, ?, ?, ?)",
+        params![chat_id, role, content, now, sources_to_column(sources)?],
+    )?;
+    Ok(db.last_insert_rowid())
+}
+
+/// Adds an empty assistant message that's filled in while the answer streams in.
+pub fn start_assistant_message(db: &Connection, chat_id: i64) -> Result<i64, Error> {
     let now = Local::now().to_rfc3339();
     db.execute(
-        "INSERT INTO messages (chat_id, role, content, created_at) VALUES (?, ?, ?, ?)",
-        params![chat_id, role, content, now],
+        "INSERT INTO messages (chat_id, role, content, created_at, status) VALUES (?, 'assistant', '', ?, ?)",
+        params![chat_id, now, MessageStatus::Streaming],
     )?;
     Ok(db.last_insert_rowid())
 }
 
+pub fn update_message_content(db: &Connection, message_id: i64, content: &str) -> Result<(), Error> {
+    db.execute(
+        "UPDATE messages SET content = ? WHERE id = ?",
+        params![content, message_id],
+    )?;
+    Ok(())
+}
+
+/// Stores the final content of a streamed message along with its sources and token usage.
+pub fn finish_message(
+    db: &Connection,
+    message_id: i64,
+    content: &str,
+    status: MessageStatus,
+    sources: &[MessageSource],
+    input_tokens: u32,
+    output_tokens: u32,
+) -> Result<(), Error> {
+    db.execute(
+        "UPDATE messages
+         SET content = ?, status = ?, sources = ?, input_tokens = ?, output_tokens = ?
+         WHERE id = ?",
+        params![
+            content,
+            status,
+            sources_to_column(sources)?,
+            input_tokens,
+            output_tokens,
+            message_id
+        ],
+    )?;
+    Ok(())
+}
+
+/// Marks messages that were still streaming as interrupted, returns how many there were.
+pub fn mark_interrupted_messages(db: &Connection) -> Result<usize, Error> {
+    db.execute(
+        "UPDATE messages SET status = ? WHERE status = ?",
+        params![MessageStatus::Interrupted, MessageStatus::Streaming],
+    )
+}
+
 pub fn get_messages_by_chat_id(db: &Connection, chat_id: i64) -> Result<Vec<StoredMessage>, Error> {
-    let mut stmt = db.prepare("SELECT * FROM messages WHERE chat_id = ? ORDER BY created_at")?;
-    let messages = stmt.query_map(params![chat_id], |row| {
-        Ok(StoredMessage {
-            id: row.get(0)?,
-            chat_id: row.get(1)?,
-            role: row.get(2)?,
-            content: row.get(3)?,
-            created_at: row.get(4)?,
-        })
-    })?;
+    let mut stmt = db.prepare(&format!(
+        "SELECT {} FROM messages WHERE chat_id = ? ORDER BY created_at, id",
+        MESSAGE_COLUMNS
+    ))?;
+    let messages = stmt.query_map(params![chat_id], |row| message_from_row(row, 0))?;
     Ok(messages.collect::<Result<_, _>>()?)
 }
 
+/// Returns the message together with the name of the chat it belongs to.
+pub fn get_message_by_id(db: &Connection, message_id: i64) -> Result<Option<(String, StoredMessage)>, Error> {
+    let result = db.query_row(
+        "SELECT c.name, m.id, m.chat_id, m.role, m.content, m.created_at, m.sources, m.status,
+                m.input_tokens, m.output_tokens
+         FROM messages m
+         JOIN chats c ON c.id = m.chat_id
+         WHERE m.id = ?",
+        params![message_id],
+        |row| Ok((row.get(0)?, message_from_row(row, 1)?)),
+    );
+
+    match result {
+        Ok(message) => Ok(Some(message)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
 pub fn update_chat(conn: &Connection, chat_id: i64, name: &str) -> Result<bool> {
     let now = Local::now().to_rfc3339();
     let rows_affected = conn.execute(
@@ -0,0 +1,133 @@
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::entity::vector_chunk::SourceType;
+
+/// Stores the fingerprint of a source, `duplicate_of` is the source it's a near-duplicate of.
+pub fn save_content_fingerprint(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+    simhash: u64,
+    duplicate_of: Option<i64>,
+) -> Result<(), rusqlite::Error> {
+    db.execute(
+        "INSERT OR REPLACE INTO content_fingerprints (source_type, source_id, simhash, duplicate_of)
+         VALUES (?1, ?2, ?3, ?4)",
+        // SQLite integers are signed, the bits are stored as they are
+        params![source_type, source_id, simhash as i64, duplicate_of],
+    )?;
+    Ok(())
+}
+
+/// Returns `(source_id, simhash)` of the sources of a type that aren't duplicates themselves and
+/// share at least one of the four 16-bit bands of `simhash`. Fingerprints that differ in at most
+/// three bits always do, so only this subset needs comparing. Every band has an index.
+pub fn get_canonical_fingerprint_candidates(
+    db: &Connection,
+    source_type: SourceType,
+    simhash: u64,
+) -> Result<Vec<(i64, u64)>, rusqlite::Error> {
+    let band = |index: u32| ((simhash >> (16 * index)) & 0xffff) as i64;
+    let mut stmt = db.prepare(
+        "SELECT source_id, simhash
+         FROM content_fingerprints
+         WHERE (simhash & 65535 = ?2
+                OR (simhash >> 16) & 65535 = ?3
+                OR (simhash >> 32) & 65535 = ?4
+                OR (simhash >> 48) & 65535 = ?5)
+           -- The unary plus keeps SQLite from scanning every source of the type instead
+           AND +source_type = ?1 AND duplicate_of IS NULL
+         ORDER BY source_id",
+    )?;
+    let rows = stmt.query_map(
+        params![source_type, band(0), band(1), band(2), band(3)],
+        |row| {
+            let source_id: i64 = row.get(0)?;
+            let simhash: i64 = row.get(1)?;
+            Ok((source_id, simhash as u64))
+        },
+    )?;
+    rows.collect()
+}
+
+pub fn get_duplicate_of(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<Option<i64>, rusqlite::Error> {
+    db.query_row(
+        "SELECT duplicate_of FROM content_fingerprints WHERE source_type = ?1 AND source_id = ?2",
+        params![source_type, source_id],
+        |row| row.get::<_, Option<i64>>(0),
+    )
+    .optional()
+    .map(Option::flatten)
+}
+
+/// Points the duplicates of `from_id` at `to_id`, or makes them stand alone when `to_id` is `None`.
+pub fn move_duplicates(
+    db: &Connection,
+    source_type: SourceType,
+    from_id: i64,
+    to_id: Option<i64>,
+) -> Result<usize, rusqlite::Error> {
+    db.execute(
+        "UPDATE content_fingerprints SET duplicate_of = ?3
+         WHERE source_type = ?1 AND duplicate_of = ?2",
+        params![source_type, from_id, to_id],
+    )
+}
+
+/// The oldest source linked to `canonical_id`, if any.
+pub fn get_first_duplicate(
+    db: &Connection,
+    source_type: SourceType,
+    canonical_id: i64,
+) -> Result<Option<i64>, rusqlite::Error> {
+    db.query_row(
+        "SELECT MIN(source_id) FROM content_fingerprints
+         WHERE source_type = ?1 AND duplicate_of = ?2",
+        params![source_type, canonical_id],
+        |row| row.get(0),
+    )
+}
+
+pub fn set_duplicate_of(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+    duplicate_of: Option<i64>,
+) -> Result<(), rusqlite::Error> {
+    db.execute(
+        "UPDATE content_fingerprints SET duplicate_of = ?3
+         WHERE source_type = ?1 AND source_id = ?2",
+        params![source_type, source_id, duplicate_of],
+    )?;
+    Ok(())
+}
+
+pub fn delete_content_fingerprint(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<(), rusqlite::Error> {
+    db.execute(
+        "DELETE FROM content_fingerprints WHERE source_type = ?1 AND source_id = ?2",
+        params![source_type, source_id],
+    )?;
+    Ok(())
+}
+
+/// Returns `(source_type, canonical_id, duplicate_id)` of every duplicate, grouped by cluster.
+pub fn get_duplicate_links(
+    db: &Connection,
+) -> Result<Vec<(SourceType, i64, i64)>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT source_type, duplicate_of, source_id
+         FROM content_fingerprints
+         WHERE duplicate_of IS NOT NULL
+         ORDER BY source_type, duplicate_of, source_id",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    rows.collect()
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use rusqlite::{params, Connection};
+
+use crate::repository::vector_db_repository::{blob_to_vector, vector_to_blob};
+
+/// Returns the cached vectors of `model` for whichever of the hashes are known.
+pub fn get_cached_embeddings(
+    db: &Connection,
+    model: &str,
+    content_hashes: &[String],
+) -> Result<HashMap<String, Vec<f32>>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT embedding FROM embedding_cache WHERE model = ?1 AND content_hash = ?2",
+    )?;
+    let mut cached = HashMap::new();
+    for content_hash in content_hashes {
+        if cached.contains_key(content_hash) {
+            continue;
+        }
+        let result = stmt.query_row(params![model, content_hash], |row| row.get::<_, Vec<u8>>(0));
+        match result {
+            Ok(blob) => {
+                cached.insert(content_hash.clone(), blob_to_vector(&blob));
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(cached)
+}
+
+/// Marks cache entries as used, so pruning keeps them.
+pub fn touch_cached_embeddings(
+    db: &Connection,
+    model: &str,
+    content_hashes: &[String],
+) -> Result<(), rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "UPDATE embedding_cache SET last_used_at = CURRENT_TIMESTAMP
+         WHERE model = ?1 AND content_hash = ?2",
+    )?;
+    for content_hash in content_hashes {
+        stmt.execute(params![model, content_hash])?;
+    }
+    Ok(())
+}
+
+pub fn save_cached_embeddings(
+    db: &Connection,
+    model: &str,
+    items: &[(String, Vec<f32>)],
+) -> Result<(), rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "INSERT OR REPLACE INTO embedding_cache (model, content_hash, dimension, embedding)
+         VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for (content_hash, vector) in items {
+        stmt.execute(params![
+            model,
+            content_hash,
+            vector.len() as i64,
+            vector_to_blob(vector),
+        ])?;
+    }
+    Ok(())
+}
+
+/// Drops the least recently used entries beyond `max_entries`.
+pub fn prune_embedding_cache(db: &Connection, max_entries: usize) -> Result<usize, rusqlite::Error> {
+    db.execute(
+        "DELETE FROM embedding_cache WHERE rowid IN (
+             SELECT rowid FROM embedding_cache
+             ORDER BY last_used_at DESC
+             LIMIT -1 OFFSET ?1
+         )",
+        params![max_entries as i64],
+    )
+}
@@ -0,0 +1,45 @@
+use rusqlite::{params, Connection};
+
+// Title matches count five times as much as matches in the body.
+const TITLE_WEIGHT: f64 = 5.0;
+const BODY_WEIGHT: f64 = 1.0;
+
+/// Returns the ids of `projects_activities` rows matching an FTS5 `match_query`, best BM25 score first.
+pub fn search_project_documents(
+    db: &Connection,
+    match_query: &str,
+    limit: usize,
+) -> Result<Vec<i64>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT rowid
+         FROM projects_activities_fts
+         WHERE projects_activities_fts MATCH ?1
+         ORDER BY bm25(projects_activities_fts, ?2, ?3)
+         LIMIT ?4",
+    )?;
+    let rows = stmt.query_map(
+        params![match_query, TITLE_WEIGHT, BODY_WEIGHT, limit as i64],
+        |row| row.get(0),
+    )?;
+    rows.collect()
+}
+
+/// Returns the ids of `activity_full_text` rows matching an FTS5 `match_query`, best BM25 score first.
+pub fn search_activities(
+    db: &Connection,
+    match_query: &str,
+    limit: usize,
+) -> Result<Vec<i64>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT rowid
+         FROM activity_full_text_fts
+         WHERE activity_full_text_fts MATCH ?1
+         ORDER BY bm25(activity_full_text_fts, ?2, ?3)
+         LIMIT ?4",
+    )?;
+    let rows = stmt.query_map(
+        params![match_query, TITLE_WEIGHT, BODY_WEIGHT, limit as i64],
+        |row| row.get(0),
+    )?;
+    rows.collect()
+}
@@ -1,7 +1,12 @@
 pub mod activity_log_repository;
 pub mod chat_db_repository;
+pub mod content_fingerprint_repository;
+pub mod embedding_cache_repository;
+pub mod full_text_search_repository;
 pub mod keypress_log_repository;
 pub mod permissions_repository;
+pub mod search_filter_repository;
 pub mod settings_repository;
+pub mod usage_repository;
 pub mod vector_db_repository;
 pub mod project_repository;
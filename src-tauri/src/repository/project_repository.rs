@@ -175,11 +175,25 @@ pub fn get_activity_text_from_project(
     }
 }
 
+/// What has to happen to a document's vectors after its text was edited.
+#[derive(Debug, PartialEq)]
+pub enum VectorIndexUpdate {
+    Unchanged,
+    Index,
+    Remove,
+}
+
 pub fn update_activity_text(
     conn: &Connection,
     activity_id: i64,
     text: &str,
-) -> Result<bool, rusqlite::Error> {
+) -> Result<VectorIndexUpdate, rusqlite::Error> {
+    let (previous_text, is_vectorized): (String, bool) = conn.query_row(
+        "SELECT full_document_text, is_vectorized FROM projects_activities WHERE id = ?1",
+        params![activity_id],
+        |row| Ok((row.get(0)?, row.get::<_, i64>(1)? != 0)),
+    )?;
+
     // Update the document text
     conn.execute(
         "UPDATE projects_activities SET full_document_text = ?1 WHERE id = ?2",
@@ -188,22 +202,22 @@ pub fn update_activity_text(
     
     info!("Updated document text for ID: {}, length: {}", activity_id, text.len());
 
-    // Simple check: needs vectorization if text > 200 chars and not already vectorized
+    let text_changed = previous_text != text;
+    info!("Document ID: {} - text changed: {}, already vectorized: {}", activity_id, text_changed, is_vectorized);
+
+    // Needs vectorization if text > 200 chars and either never vectorized or the indexed text is stale
     if text.len() > 200 {
-        let is_vectorized: bool = conn.query_row(
-            "SELECT is_vectorized FROM projects_activities WHERE id = ?1",
-            params![activity_id],
-            |row| Ok(row.get::<_, i64>(0)? != 0)
-        )?;
-        
-        info!("Document ID: {} - Text length > 200, already vectorized: {}", activity_id, is_vectorized);
-        
-        // Return true if document needs vectorization
-        return Ok(!is_vectorized);
+        if !is_vectorized || text_changed {
+            return Ok(VectorIndexUpdate::Index);
+        }
+        return Ok(VectorIndexUpdate::Unchanged);
     }
-    
+
     info!("Document ID: {} text length too short for vectorization", activity_id);
-    Ok(false)
+    if is_vectorized {
+        return Ok(VectorIndexUpdate::Remove);
+    }
+    Ok(VectorIndexUpdate::Unchanged)
 }
 
 /// Simple function to mark a document as vectorized
@@ -219,6 +233,17 @@ pub fn mark_document_as_vectorized(
     Ok(())
 }
 
+pub fn mark_document_as_not_vectorized(
+    conn: &Connection,
+    activity_id: i64,
+) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "UPDATE projects_activities SET is_vectorized = 0 WHERE id = ?1",
+        params![activity_id],
+    )?;
+    Ok(())
+}
+
 pub fn update_activity_name(
     conn: &Connection,
     activity_id: i64,
@@ -273,4 +298,54 @@ pub fn ensure_unassigned_project(conn: &Connection) -> Result<i64, rusqlite::Err
     )?;
     
     Ok(conn.last_insert_rowid())
-  }
\ No newline at end of file
+  }
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{params, Connection};
+
+    use super::{mark_document_as_vectorized, update_activity_text, VectorIndexUpdate};
+
+    fn document(text: &str) -> (Connection, i64) {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(include_str!(
+            "../../migrations/2024-12-20-162928_create_projects_tables/up.sql"
+        ))
+        .unwrap();
+        db.execute(
+            "INSERT INTO projects_activities (project_id, full_document_text) VALUES (1, ?1)",
+            params![text],
+        )
+        .unwrap();
+        let id = db.last_insert_rowid();
+        (db, id)
+    }
+
+    #[test]
+    fn edits_update_the_vectors_of_long_documents_only() {
+        let long_text = "a".repeat(300);
+        let (db, id) = document("Start editing");
+        assert_eq!(
+            update_activity_text(&db, id, "Too short").unwrap(),
+            VectorIndexUpdate::Unchanged
+        );
+        assert_eq!(
+            update_activity_text(&db, id, &long_text).unwrap(),
+            VectorIndexUpdate::Index
+        );
+
+        mark_document_as_vectorized(&db, id).unwrap();
+        assert_eq!(
+            update_activity_text(&db, id, &long_text).unwrap(),
+            VectorIndexUpdate::Unchanged
+        );
+        assert_eq!(
+            update_activity_text(&db, id, &"b".repeat(300)).unwrap(),
+            VectorIndexUpdate::Index
+        );
+        assert_eq!(
+            update_activity_text(&db, id, "Too short").unwrap(),
+            VectorIndexUpdate::Remove
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use rusqlite::{named_params, params, Connection, OptionalExtension};
+
+use crate::entity::search_filter::{SearchFilter, SourceDetails};
+use crate::entity::vector_chunk::SourceType;
+
+/// Every source with the attributes the filter looks at. Project documents take the app and
+/// date of the activity they were created from, or the project's creation date.
+const FILTERED_SOURCES: &str = "
+    WITH sources (source_type, source_id, project_id, app_name, entry_date) AS (
+        SELECT 'activity', a.id, NULL, a.window_app_name, a.dateofentry
+        FROM activity_full_text a
+        UNION ALL
+        SELECT 'project_document', p.id, p.project_id, pa.window_app_name,
+               COALESCE(pa.dateofentry, pr.created_at)
+        FROM projects_activities p
+        LEFT JOIN activity_full_text pa ON pa.id = p.activity_id
+        LEFT JOIN projects pr ON pr.id = p.project_id
+        UNION ALL
+        SELECT 'chat_message', m.id, NULL, NULL, m.created_at
+        FROM messages m
+    ),
+    filtered AS (
+        SELECT s.source_type, s.source_id
+        FROM sources s
+        WHERE (:source_types IS NULL OR instr(:source_types, ',' || s.source_type || ',') > 0)
+          AND (:project_id IS NULL
+               OR s.project_id = :project_id
+               OR (s.source_type = 'activity' AND EXISTS (
+                   SELECT 1 FROM projects_activities pp
+                   WHERE pp.project_id = :project_id AND pp.activity_id = s.source_id)))
+          AND (:app_name IS NULL OR s.app_name = :app_name COLLATE NOCASE)
+          AND (:date_from IS NULL OR date(s.entry_date) >= date(:date_from))
+          AND (:date_to IS NULL OR date(s.entry_date) <= date(:date_to))
+    )";
+
+/// Source types as `,activity,chat_message,` so the query can match them with `instr`.
+fn source_types_param(filter: &SearchFilter) -> Option<String> {
+    filter.source_types.as_ref().map(|types| {
+        let names: Vec<&str> = types.iter().map(|source_type| source_type.as_str()).collect();
+        format!(",{},", names.join(","))
+    })
+}
+
+/// Sources matching the filter, in no particular order.
+pub fn get_filtered_sources(
+    db: &Connection,
+    filter: &SearchFilter,
+) -> Result<Vec<(SourceType, i64)>, rusqlite::Error> {
+    let query = format!("{} SELECT source_type, source_id FROM filtered", FILTERED_SOURCES);
+    let mut stmt = db.prepare(&query)?;
+    let rows = stmt.query_map(
+        named_params! {
+            ":source_types": source_types_param(filter),
+            ":project_id": filter.project_id,
+            ":app_name": filter.app_name,
+            ":date_from": filter.date_from,
+            ":date_to": filter.date_to,
+        },
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    rows.collect()
+}
+
+/// Ids of the vector chunks cut from sources matching the filter.
+pub fn get_filtered_chunk_ids(
+    db: &Connection,
+    filter: &SearchFilter,
+) -> Result<Vec<i64>, rusqlite::Error> {
+    let query = format!(
+        "{} SELECT c.id FROM vector_chunks c
+            JOIN filtered f ON f.source_type = c.source_type AND f.source_id = c.source_id",
+        FILTERED_SOURCES
+    );
+    let mut stmt = db.prepare(&query)?;
+    let rows = stmt.query_map(
+        named_params! {
+            ":source_types": source_types_param(filter),
+            ":project_id": filter.project_id,
+            ":app_name": filter.app_name,
+            ":date_from": filter.date_from,
+            ":date_to": filter.date_to,
+        },
+        |row| row.get(0),
+    )?;
+    rows.collect()
+}
+
+/// Project and date of a source as the filter sees them. Activities belong to the first project
+/// they were added to.
+pub fn get_source_details(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<Option<SourceDetails>, rusqlite::Error> {
+    let query = match source_type {
+        SourceType::Activity => {
+            "SELECT pr.id, pr.name, a.dateofentry
+             FROM activity_full_text a
+             LEFT JOIN projects_activities p
+                 ON p.id = (SELECT MIN(id) FROM projects_activities WHERE activity_id = a.id)
+             LEFT JOIN projects pr ON pr.id = p.project_id
+             WHERE a.id = ?1"
+        }
+        SourceType::ProjectDocument => {
+            "SELECT pr.id, pr.name, COALESCE(pa.dateofentry, pr.created_at)
+             FROM projects_activities p
+             LEFT JOIN projects pr ON pr.id = p.project_id
+             LEFT JOIN activity_full_text pa ON pa.id = p.activity_id
+             WHERE p.id = ?1"
+        }
+        SourceType::ChatMessage => "SELECT NULL, NULL, created_at FROM messages WHERE id = ?1",
+    };
+    db.query_row(query, params![source_id], |row| {
+        Ok(SourceDetails {
+            project_id: row.get(0)?,
+            project_name: row.get(1)?,
+            date: row.get(2)?,
+        })
+    })
+    .optional()
+}
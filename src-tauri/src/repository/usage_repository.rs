@@ -0,0 +1,110 @@
+use chrono::Local;
+use rusqlite::{params, Connection, Row};
+
+use crate::entity::usage_event::{ChatUsage, DailyUsage, ProjectUsage, UsageEvent, UsageTotals};
+
+/// Aggregates `totals_from_row` reads, in order.
+const TOTALS_COLUMNS: &str = "COUNT(*), COALESCE(SUM(input_tokens), 0),
+    COALESCE(SUM(output_tokens), 0), COALESCE(SUM(cost), 0)";
+
+fn totals_from_row(row: &Row, offset: usize) -> Result<UsageTotals, rusqlite::Error> {
+    Ok(UsageTotals {
+        calls: row.get(offset)?,
+        input_tokens: row.get(offset + 1)?,
+        output_tokens: row.get(offset + 2)?,
+        cost: row.get(offset + 3)?,
+    })
+}
+
+pub fn insert_usage_event(db: &Connection, event: &UsageEvent) -> Result<(), rusqlite::Error> {
+    db.execute(
+        "INSERT INTO usage_events
+         (created_at, provider, model, purpose, input_tokens, output_tokens, cost, chat_id, project_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            Local::now().to_rfc3339(),
+            event.provider,
+            event.model,
+            event.purpose,
+            event.input_tokens,
+            event.output_tokens,
+            event.cost,
+            event.scope.chat_id,
+            event.scope.project_id,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Totals by local day, starting at `since_day` (`YYYY-MM-DD`).
+pub fn get_daily_usage(
+    db: &Connection,
+    since_day: &str,
+) -> Result<Vec<DailyUsage>, rusqlite::Error> {
+    let mut stmt = db.prepare(&format!(
+        "SELECT substr(created_at, 1, 10) AS day, {}
+         FROM usage_events
+         WHERE created_at >= ?1
+         GROUP BY day
+         ORDER BY day",
+        TOTALS_COLUMNS
+    ))?;
+    let rows = stmt.query_map(params![since_day], |row| {
+        Ok(DailyUsage {
+            day: row.get(0)?,
+            totals: totals_from_row(row, 1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Totals by chat, most expensive first.
+pub fn get_chat_usage(db: &Connection) -> Result<Vec<ChatUsage>, rusqlite::Error> {
+    let mut stmt = db.prepare(&format!(
+        "SELECT u.chat_id, c.name, {}
+         FROM usage_events u
+         LEFT JOIN chats c ON c.id = u.chat_id
+         WHERE u.chat_id IS NOT NULL
+         GROUP BY u.chat_id
+         ORDER BY SUM(u.cost) DESC",
+        TOTALS_COLUMNS
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        Ok(ChatUsage {
+            chat_id: row.get(0)?,
+            chat_name: row.get(1)?,
+            totals: totals_from_row(row, 2)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Totals by project, most expensive first.
+pub fn get_project_usage(db: &Connection) -> Result<Vec<ProjectUsage>, rusqlite::Error> {
+    let mut stmt = db.prepare(&format!(
+        "SELECT u.project_id, p.name, {}
+         FROM usage_events u
+         LEFT JOIN projects p ON p.id = u.project_id
+         WHERE u.project_id IS NOT NULL
+         GROUP BY u.project_id
+         ORDER BY SUM(u.cost) DESC",
+        TOTALS_COLUMNS
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        Ok(ProjectUsage {
+            project_id: row.get(0)?,
+            project_name: row.get(1)?,
+            totals: totals_from_row(row, 2)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// USD spent since `since`, a local timestamp or date.
+pub fn get_cost_since(db: &Connection, since: &str) -> Result<f64, rusqlite::Error> {
+    db.query_row(
+        "SELECT COALESCE(SUM(cost), 0) FROM usage_events WHERE created_at >= ?1",
+        params![since],
+        |row| row.get(0),
+    )
+}
@@ -1,16 +1,229 @@
-use std::error::Error;
-use async_openai::{types::CreateEmbeddingRequestArgs, Client, config::OpenAIConfig};
-
-// Correct async function for computing vector embeddings
-pub async fn compute_vector_embedding(text: &str, api_key: &str) -> Result<Vec<f32>, Box<dyn Error>> {
-    let config: OpenAIConfig = OpenAIConfig::new()
-    .with_api_key(api_key);
-
-    let client = Client::with_config(config);
-    let request = CreateEmbeddingRequestArgs::default()
-        .model("text-embedding-3-small")
-        .input([text])
-        .build()?;
-    let response = client.embeddings().create(request).await?;
-    Ok(response.data[0].embedding.clone())
+use rusqlite::{params, Connection};
+use rusqlite_from_row::FromRow;
+
+use crate::engine::chunking_engine::TextChunk;
+use crate::entity::vector_chunk::{SourceType, VectorChunk};
+
+pub fn insert_vector_chunks(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+    chunks: &[TextChunk],
+) -> Result<Vec<i64>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "INSERT INTO vector_chunks (source_type, source_id, chunk_index, start_offset, end_offset, chunk_text)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+
+    let mut chunk_ids = Vec::with_capacity(chunks.len());
+    for (chunk_index, chunk) in chunks.iter().enumerate() {
+        stmt.execute(params![
+            source_type,
+            source_id,
+            chunk_index as i64,
+            chunk.start_offset as i64,
+            chunk.end_offset as i64,
+            chunk.text,
+        ])?;
+        chunk_ids.push(db.last_insert_rowid());
+    }
+    Ok(chunk_ids)
+}
+
+pub fn get_vector_chunk(
+    db: &Connection,
+    chunk_id: i64,
+) -> Result<Option<VectorChunk>, rusqlite::Error> {
+    let result = db.query_row(
+        "SELECT id, source_type, source_id, chunk_index, start_offset, end_offset, chunk_text
+         FROM vector_chunks
+         WHERE id = ?1",
+        params![chunk_id],
+        VectorChunk::try_from_row,
+    );
+
+    match result {
+        Ok(chunk) => Ok(Some(chunk)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn get_vector_chunks_by_source(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<Vec<VectorChunk>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT id, source_type, source_id, chunk_index, start_offset, end_offset, chunk_text
+         FROM vector_chunks
+         WHERE source_type = ?1 AND source_id = ?2
+         ORDER BY chunk_index",
+    )?;
+    let rows = stmt.query_map(params![source_type, source_id], VectorChunk::try_from_row)?;
+    rows.collect()
+}
+
+pub fn get_vector_chunk_ids_by_source(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<Vec<i64>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT id FROM vector_chunks WHERE source_type = ?1 AND source_id = ?2 ORDER BY id",
+    )?;
+    let rows = stmt.query_map(params![source_type, source_id], |row| row.get(0))?;
+    rows.collect()
+}
+
+pub fn delete_vector_chunks_by_source(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<(), rusqlite::Error> {
+    // foreign keys aren't enforced on this connection, so the embeddings go first
+    db.execute(
+        "DELETE FROM vector_embeddings
+         WHERE chunk_id IN (SELECT id FROM vector_chunks WHERE source_type = ?1 AND source_id = ?2)",
+        params![source_type, source_id],
+    )?;
+    db.execute(
+        "DELETE FROM vector_chunks WHERE source_type = ?1 AND source_id = ?2",
+        params![source_type, source_id],
+    )?;
+    Ok(())
+}
+
+/// Whether the row the source's text is taken from still exists. Deleted activities are kept
+/// as blanked rows.
+pub fn source_exists(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+) -> Result<bool, rusqlite::Error> {
+    let query = match source_type {
+        SourceType::Activity => {
+            "SELECT EXISTS (SELECT 1 FROM activity_full_text WHERE id = ?1 AND window_title != '')"
+        }
+        SourceType::ProjectDocument => {
+            "SELECT EXISTS (SELECT 1 FROM projects_activities WHERE id = ?1)"
+        }
+        SourceType::ChatMessage => "SELECT EXISTS (SELECT 1 FROM messages WHERE id = ?1)",
+    };
+    db.query_row(query, params![source_id], |row| row.get(0))
+}
+
+pub fn vector_to_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+pub fn blob_to_vector(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+/// Stores the vectors of chunks, `items` are `(chunk_id, content_hash, vector)`.
+pub fn save_vector_embeddings(
+    db: &Connection,
+    model: &str,
+    items: &[(i64, String, Vec<f32>)],
+) -> Result<(), rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "INSERT OR REPLACE INTO vector_embeddings (chunk_id, model, dimension, content_hash, embedding)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+    for (chunk_id, content_hash, vector) in items {
+        stmt.execute(params![
+            chunk_id,
+            model,
+            vector.len() as i64,
+            content_hash,
+            vector_to_blob(vector),
+        ])?;
+    }
+    Ok(())
+}
+
+/// Returns `(chunk_id, vector)` of every stored vector computed by `model`.
+pub fn get_vector_embeddings_by_model(
+    db: &Connection,
+    model: &str,
+) -> Result<Vec<(i64, Vec<f32>)>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT e.chunk_id, e.embedding
+         FROM vector_embeddings e
+         JOIN vector_chunks c ON c.id = e.chunk_id
+         WHERE e.model = ?1
+         ORDER BY e.chunk_id",
+    )?;
+    let rows = stmt.query_map(params![model], |row| {
+        let chunk_id: i64 = row.get(0)?;
+        let blob: Vec<u8> = row.get(1)?;
+        Ok((chunk_id, blob_to_vector(&blob)))
+    })?;
+    rows.collect()
+}
+
+/// Returns `(chunk_id, vector)` of the chunks of a source that have a vector computed by `model`.
+pub fn get_vector_embeddings_by_source(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+    model: &str,
+) -> Result<Vec<(i64, Vec<f32>)>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT e.chunk_id, e.embedding
+         FROM vector_embeddings e
+         JOIN vector_chunks c ON c.id = e.chunk_id
+         WHERE c.source_type = ?1 AND c.source_id = ?2 AND e.model = ?3
+         ORDER BY c.chunk_index",
+    )?;
+    let rows = stmt.query_map(params![source_type, source_id, model], |row| {
+        let chunk_id: i64 = row.get(0)?;
+        let blob: Vec<u8> = row.get(1)?;
+        Ok((chunk_id, blob_to_vector(&blob)))
+    })?;
+    rows.collect()
+}
+
+/// Chunks of the source that have no vector computed by `model` yet.
+pub fn get_vector_chunks_without_embedding(
+    db: &Connection,
+    source_type: SourceType,
+    source_id: i64,
+    model: &str,
+) -> Result<Vec<VectorChunk>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT c.id, c.source_type, c.source_id, c.chunk_index, c.start_offset, c.end_offset, c.chunk_text
+         FROM vector_chunks c
+         WHERE c.source_type = ?1 AND c.source_id = ?2
+           AND NOT EXISTS (SELECT 1 FROM vector_embeddings e WHERE e.chunk_id = c.id AND e.model = ?3)
+         ORDER BY c.chunk_index",
+    )?;
+    let rows = stmt.query_map(params![source_type, source_id, model], VectorChunk::try_from_row)?;
+    rows.collect()
+}
+
+pub fn delete_vector_embeddings_except_model(
+    db: &Connection,
+    model: &str,
+) -> Result<usize, rusqlite::Error> {
+    db.execute("DELETE FROM vector_embeddings WHERE model != ?1", params![model])
+}
+
+/// Activities and project documents that are part of the vector index, oldest first.
+pub fn get_indexed_sources(db: &Connection) -> Result<Vec<(SourceType, i64)>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT 'activity', a.id
+         FROM activity_full_text a
+         WHERE EXISTS (SELECT 1 FROM vector_chunks c WHERE c.source_type = 'activity' AND c.source_id = a.id)
+         UNION ALL
+         SELECT 'project_document', p.id
+         FROM projects_activities p
+         WHERE p.is_vectorized = 1
+         ORDER BY 1, 2",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
 }
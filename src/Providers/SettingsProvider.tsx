@@ -21,6 +21,10 @@ export const DEFAULT_SETTINGS: Settings = {
   api_key_claude: "",
   api_key_open_ai: "",
   vectorization_enabled: false,
+  custom_llm_base_url: "",
+  custom_llm_model: "",
+  custom_llm_api_key: "",
+  custom_llm_headers: "",
 };
 
 type Update = {
@@ -36,6 +40,11 @@ export type Settings = {
   api_key_claude: string;
   api_key_open_ai: string;
   vectorization_enabled: boolean;
+  // OpenAI-compatible endpoint, e.g. a local server
+  custom_llm_base_url: string;
+  custom_llm_model: string;
+  custom_llm_api_key: string;
+  custom_llm_headers: string;
 };
 
 type SettingsContextType = {
@@ -74,6 +83,10 @@ export const SettingsProvider: FC<PropsWithChildren> = ({ children }) => {
       api_key_claude: getSettingOrEmpty(response, "api_key_claude") || "",
       api_key_open_ai: getSettingOrEmpty(response, "api_key_open_ai") || "",
       vectorization_enabled: getSettingOrEmpty(response, "vectorization_enabled") == "true",
+      custom_llm_base_url: getSettingOrEmpty(response, "custom_llm_base_url"),
+      custom_llm_model: getSettingOrEmpty(response, "custom_llm_model"),
+      custom_llm_api_key: getSettingOrEmpty(response, "custom_llm_api_key"),
+      custom_llm_headers: getSettingOrEmpty(response, "custom_llm_headers"),
     };
   };
 
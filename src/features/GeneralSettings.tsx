@@ -16,6 +16,10 @@ type LocalSettings = {
   apiKeyOpenAi: string;
   apiKeyClaude: string;
   vectorizationEnabled: boolean;
+  customLlmBaseUrl: string;
+  customLlmModel: string;
+  customLlmApiKey: string;
+  customLlmHeaders: string;
 };
 export const GeneralSettings = () => {
   const toast = useToast();
@@ -25,6 +29,10 @@ export const GeneralSettings = () => {
     apiKeyOpenAi: settings.api_key_open_ai,
     apiKeyClaude: settings.api_key_claude,
     vectorizationEnabled: settings.vectorization_enabled,
+    customLlmBaseUrl: settings.custom_llm_base_url,
+    customLlmModel: settings.custom_llm_model,
+    customLlmApiKey: settings.custom_llm_api_key,
+    customLlmHeaders: settings.custom_llm_headers,
   });
 
   useEffect(() => {
@@ -33,6 +41,10 @@ export const GeneralSettings = () => {
       apiKeyOpenAi: settings.api_key_open_ai,
       apiKeyClaude: settings.api_key_claude,
       vectorizationEnabled: settings.vectorization_enabled,
+      customLlmBaseUrl: settings.custom_llm_base_url,
+      customLlmModel: settings.custom_llm_model,
+      customLlmApiKey: settings.custom_llm_api_key,
+      customLlmHeaders: settings.custom_llm_headers,
     });
   }, [settings]);
 
@@ -75,6 +87,15 @@ export const GeneralSettings = () => {
     }));
   };
 
+  const onChangeCustomLlm =
+    (key: "customLlmBaseUrl" | "customLlmModel" | "customLlmApiKey" | "customLlmHeaders") =>
+    (event: React.ChangeEvent<HTMLInputElement>) => {
+      setLocalSettings((prevState) => ({
+        ...prevState,
+        [key]: event.target.value,
+      }));
+    };
+
   const onSave = () => {
     update({
       ...settings,
@@ -82,6 +103,10 @@ export const GeneralSettings = () => {
       api_key_open_ai: localSettings.apiKeyOpenAi,
       api_key_claude: localSettings.apiKeyClaude,
       vectorization_enabled: localSettings.vectorizationEnabled,
+      custom_llm_base_url: localSettings.customLlmBaseUrl,
+      custom_llm_model: localSettings.customLlmModel,
+      custom_llm_api_key: localSettings.customLlmApiKey,
+      custom_llm_headers: localSettings.customLlmHeaders,
     });
     savedSuccessfullyToast();
   };
@@ -136,6 +161,68 @@ export const GeneralSettings = () => {
             API keys are required for their respective models. Add the keys you plan to use.
           </Text>
 
+          <Text fontSize="md" mt={4} mb={2}>
+            Custom Endpoint
+          </Text>
+          <Flex alignItems="center" mb={2}>
+            <Flex flex={1}>
+              <Text fontSize="md" mr={4}>
+                Base URL:
+              </Text>
+            </Flex>
+            <Flex flex={2}>
+              <Input
+                value={localSettings.customLlmBaseUrl}
+                onChange={onChangeCustomLlm("customLlmBaseUrl")}
+                placeholder="http://localhost:11434/v1"
+              />
+            </Flex>
+          </Flex>
+          <Flex alignItems="center" mb={2}>
+            <Flex flex={1}>
+              <Text fontSize="md" mr={4}>
+                Model:
+              </Text>
+            </Flex>
+            <Flex flex={2}>
+              <Input
+                value={localSettings.customLlmModel}
+                onChange={onChangeCustomLlm("customLlmModel")}
+              />
+            </Flex>
+          </Flex>
+          <Flex alignItems="center" mb={2}>
+            <Flex flex={1}>
+              <Text fontSize="md" mr={4}>
+                API Key:
+              </Text>
+            </Flex>
+            <Flex flex={2}>
+              <Input
+                value={localSettings.customLlmApiKey}
+                onChange={onChangeCustomLlm("customLlmApiKey")}
+              />
+            </Flex>
+          </Flex>
+          <Flex alignItems="center" mb={2}>
+            <Flex flex={1}>
+              <Text fontSize="md" mr={4}>
+                Extra Headers:
+              </Text>
+            </Flex>
+            <Flex flex={2}>
+              <Input
+                value={localSettings.customLlmHeaders}
+                onChange={onChangeCustomLlm("customLlmHeaders")}
+                placeholder='{"X-Header": "value"}'
+              />
+            </Flex>
+          </Flex>
+          <Text fontSize="sm" color="gray.500">
+            Any server with an OpenAI-compatible chat API. The model is added to the model list once
+            it is set. The API key and headers (a JSON object) are optional.
+          </Text>
+
           <Flex alignItems="center" mt={4} mb={2}>
             <Text fontSize="md" mr={4}>
               Enable Local Document Indexing:
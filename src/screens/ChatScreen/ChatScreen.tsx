@@ -15,7 +15,7 @@ import { Text, NavButton } from "@heelix-app/design";
 import styled from "styled-components";
 import { invoke } from "@tauri-apps/api/tauri";
 import { listen } from "@tauri-apps/api/event";
-import type { StoredMessage, Chat } from "./types";
+import type { StoredMessage, Chat, MessageSource, ChatStreamEvent } from "./types";
 import { debounce } from "lodash";
 import { FileText, X, History, Folder, MessageCircle } from "lucide-react";
 import { ScreenContainer } from "@/components/layout";
@@ -154,8 +154,8 @@ export const ChatScreen: FC = () => {
     projectName?: string
   }>>([]);
   const scrollTimeoutRef = useRef<NodeJS.Timeout | null>(null);
+  const generationIdRef = useRef<number | null>(null);
   const [selectedActivityName, setSelectedActivityName] = useState("");
-  const [currentModelId, setCurrentModelId] = useState<string>("");
   const [isEditing, setIsEditing] = useState(false);
   
   const { 
@@ -200,31 +200,24 @@ export const ChatScreen: FC = () => {
     fetchChats();
     setDialogue([]);
 
-    const unlisten1 = listen("llm_response", (event: any) => {
-      // Handle the llm_response event
-      // ...
-    });
-
-    const unlisten2 = listen("output_tokens", (event: any) => {
+    // Output tokens of every generation count towards the daily limit
+    const unlisten = listen<ChatStreamEvent>("chat_stream", (event) => {
+      const payload = event.payload;
+      if (payload.type !== "finish") {
+        return;
+      }
       setDailyOutputTokens((prevTokens) => {
-        const updatedTokens = prevTokens + event.payload;
+        const updatedTokens = prevTokens + payload.usage.output_tokens;
         saveTokenData(updatedTokens);
         return updatedTokens;
       });
     });
 
-    const unlisten3 = listen("window_titles", (event: any) => {
-      const windowTitles = JSON.parse(event.payload);
-      setWindowTitles(windowTitles);
-    });
-
     retrieveTokenData();
     resetDailyOutputTokens();
 
     return () => {
-      unlisten1.then((f) => f());
-      unlisten2.then((f) => f());
-      unlisten3.then((f) => f());
+      unlisten.then((f) => f());
     };
   }, []);
   
@@ -316,15 +309,6 @@ export const ChatScreen: FC = () => {
     );
   };
 
-  useEffect(() => {
-    // Set default model based on provider preference
-    const defaultModel = settings.api_choice === "claude" 
-      ? "claude-3-7-sonnet-20250219" // Default Claude model is 3.7 Sonnet
-      : "gpt-4o";                    // Default OpenAI model is GPT-4o
-    
-    setCurrentModelId(defaultModel);
-  }, [settings.api_choice]);
-  
   useEffect(() => {
     if (selectedChatId) {
       setDialogue([]);
@@ -336,11 +320,19 @@ export const ChatScreen: FC = () => {
     }
   }, [selectedChatId]);
 
-  const generateName = async (chatId: number, userInput: string) => {
+  const handleStop = async () => {
+    if (generationIdRef.current !== null) {
+      await invoke<boolean>("cancel_generation", { generationId: generationIdRef.current });
+    }
+  };
+
+  const generateName = async (chatId: number, userInput: string, modelId?: string) => {
     try {
-      const name = settings.api_choice === "openai"
-        ? await invoke<string>("generate_conversation_name", { userInput })
-        : await invoke<string>("name_conversation", { userInput });
+      const name = await invoke<string>("generate_conversation_name", {
+        userInput,
+        modelId: modelId ?? null,
+        chatId,
+      });
       await invoke<boolean>("update_chat_name", { chatId, name });
       setChats((prevChats) =>
         prevChats.map((chat) => (chat.id === chatId ? { ...chat, name } : chat))
@@ -366,14 +358,14 @@ export const ChatScreen: FC = () => {
     }
   };
 
-  const getChatId = async (): Promise<number> => {
+  const getChatId = async (modelId?: string): Promise<number> => {
     if (selectedChatId) {
       return selectedChatId;
     }
     try {
       const chatId = await invoke<number>("create_chat", { name: "New Chat" });
       const currentTime = new Date().toISOString();
-      generateName(chatId, userInput);
+      generateName(chatId, userInput, modelId);
       setChats([
         {
           id: chatId,
@@ -436,45 +428,76 @@ export const ChatScreen: FC = () => {
         return;
       }
 
-      const isClaudeModel = modelId ? modelId.includes("claude") : settings.api_choice === "claude";
-    
       // Get combined text from selected documents with document names and project names
       const formattedDocTexts = selectedActivityTexts.map(
         doc => `Document "${doc.name}" from ${doc.projectName ? `project "${doc.projectName}"` : "unassigned"}: ${doc.text}`
       ).join("\n\n");
       
-      // Get the combined activity text and check if it's empty
-      const selectedProjectText = await getSelectedProjectActivityText();
-      const combinedActivityText = selectedProjectText + "\n" + formattedDocTexts;
-      
-      // Override isFirstMessage if there's any activity text or if local indexing is disabled
-      const hasActivityText = combinedActivityText.trim() !== "";
+      // Retrieval is scoped to the selected project. Without the local index there's nothing to
+      // retrieve from, so the project's documents are passed in whole instead.
+      const selectedProject = getSelectedProject();
       const isLocalIndexingDisabled = !settings.vectorization_enabled;
-      const effectiveIsFirstMessage = hasActivityText || isLocalIndexingDisabled ? false : isFirstMessage;
+      const selectedProjectText = isLocalIndexingDisabled
+        ? await getSelectedProjectActivityText()
+        : "";
+      const combinedActivityText = [selectedProjectText, formattedDocTexts]
+        .filter((text) => text.trim() !== "")
+        .join("\n");
+
+      // Hand-picked documents replace retrieval
+      const hasSelectedDocuments = formattedDocTexts.trim() !== "";
+      const effectiveIsFirstMessage = hasSelectedDocuments || isLocalIndexingDisabled ? false : isFirstMessage;
+
+      // The generation can finish before send_prompt returns its id, so it's matched by chat
+      let resolveFinish: (finish: ChatStreamEvent) => void = () => {};
+      const generationFinished = new Promise<ChatStreamEvent>((resolve) => {
+        resolveFinish = resolve;
+      });
+      const unlistenFinish = await listen<ChatStreamEvent>("chat_stream", (event) => {
+        if (event.payload.chat_id === chatId && event.payload.type === "finish") {
+          resolveFinish(event.payload);
+        }
+      });
 
-      // Use the formatted document texts in the API calls
-      if (!isClaudeModel) {
-        await invoke("send_prompt_to_openai", {
+      // The backend picks the provider serving the model
+      try {
+        generationIdRef.current = await invoke<number>("send_prompt", {
           conversationHistory: fullConversation,
           isFirstMessage: effectiveIsFirstMessage,
           combinedActivityText,
-          modelId: modelId // Pass the model ID to the backend
-        });
-      } else {
-        await invoke("send_prompt_to_llm", {
-          conversationHistory: fullConversation,
-          isFirstMessage: effectiveIsFirstMessage,
-          combinedActivityText,
-          modelId: modelId // Pass the model ID to the backend
+          modelId: modelId, // Pass the model ID to the backend
+          chatId,
+          projectId: selectedProject?.id ?? null,
         });
+        const finish = await generationFinished;
+        if (finish.type === "finish" && finish.finish_reason === "error") {
+          throw new Error(finish.error ?? "The answer could not be generated");
+        }
+        if (finish.type === "finish" && finish.finish_reason === "cancelled") {
+          // The backend keeps the cancelled answer, even when nothing arrived yet
+          setDialogue((prevDialogue) => {
+            const lastMessage = prevDialogue[prevDialogue.length - 1];
+            if (lastMessage?.role === "assistant") {
+              return [...prevDialogue.slice(0, -1), { ...lastMessage, status: "cancelled" }];
+            }
+            return [
+              ...prevDialogue,
+              {
+                id: Date.now(),
+                chat_id: chatId,
+                role: "assistant",
+                content: "",
+                created_at: new Date().toISOString(),
+                status: "cancelled",
+              },
+            ];
+          });
+        }
+      } finally {
+        unlistenFinish();
+        generationIdRef.current = null;
       }
 
-      await invoke("create_message", {
-        chatId,
-        role: "user",
-        content: userInput,
-      });
-
       setSelectedActivityTexts([]);
     } catch (error) {
       const errorMessage = error instanceof Error ? error.message : "An unexpected error occurred";
@@ -487,19 +510,15 @@ export const ChatScreen: FC = () => {
           role: "assistant",
           content: errorMessage,
           created_at: new Date().toISOString(),
+          status: "failed",
         },
       ]);
     }
   };
 
-  const handleSubmit = async (modelId?: string) => {
-    const selectedModelId = modelId || currentModelId;
-    
-    // Check if the selected model is Claude or OpenAI based on model ID
-    const isClaudeModel = selectedModelId.includes("claude");
-    
-    // For OpenAI models, we need the OpenAI key
-    if (!isClaudeModel && !settings.api_key_open_ai) {
+  const handleSubmit = async (modelId?: string, provider?: string) => {
+    // Custom endpoints bring their own optional key, the hosted providers need theirs
+    if (provider === "openai" && !settings.api_key_open_ai) {
       toast({
         title: "API key not provided",
         description: "Please provide the OpenAI API key in Settings > General to continue",
@@ -511,8 +530,7 @@ export const ChatScreen: FC = () => {
       return;
     }
     
-    // For Claude models, we only need the Claude key
-    if (isClaudeModel && !settings.api_key_claude) {
+    if (provider === "anthropic" && !settings.api_key_claude) {
       toast({
         title: "API key not provided",
         description: "Please provide the Claude API key in Settings > General to continue",
@@ -538,7 +556,7 @@ export const ChatScreen: FC = () => {
       if (dialogue.length > 0) {
         chatId = dialogue[dialogue.length - 1].chat_id;
       } else {
-        chatId = await getChatId();
+        chatId = await getChatId(modelId);
       }
       setDialogue((prevDialogue) => [
         ...prevDialogue,
@@ -554,11 +572,30 @@ export const ChatScreen: FC = () => {
       setWindowTitles([]);
 
       let assistantMessage = "";
+      let assistantSources: MessageSource[] = [];
 
-      const unlisten = await listen("llm_response", (event: any) => {
-        console.log("Received llm_response event:", event);
+      const unlisten = await listen<ChatStreamEvent>("chat_stream", (event) => {
+        const payload = event.payload;
+        if (payload.chat_id !== chatId) {
+          return;
+        }
+        if (payload.type === "sources") {
+          assistantSources = payload.sources;
+          setWindowTitles(payload.window_titles);
+          setDialogue((prevDialogue) =>
+            prevDialogue.map((message, index) =>
+              index === prevDialogue.length - 1 && message.role === "assistant"
+                ? { ...message, sources: assistantSources }
+                : message
+            )
+          );
+          return;
+        }
+        if (payload.type !== "delta") {
+          return;
+        }
 
-        assistantMessage = event.payload as string;
+        assistantMessage = payload.full_text;
 
         if (!firstTokenReceived) {
           setFirstTokenReceived(true);
@@ -585,18 +622,13 @@ export const ChatScreen: FC = () => {
         });
       });
 
-      await sendPromptToLlm(chatId, isFirstMessage, selectedModelId);
+      await sendPromptToLlm(chatId, isFirstMessage, modelId);
       setIsFirstMessage(false);
 
       unlisten();
       setUserInput("");
       setIsLoading(false);
       setIsGenerating(false);
-      await invoke("create_message", {
-        chatId,
-        role: "assistant",
-        content: assistantMessage,
-      });
     } catch (error) {
       console.error("ChatScreen: handleSubmit has failed");
       return;
@@ -891,6 +923,7 @@ export const ChatScreen: FC = () => {
         onKeyDown={handleKeyPress}
         onSubmit={handleSubmit}
         onActivityHistoryToggle={handleActivityHistoryToggle}
+        onStop={handleStop}
         isGenerating={isGenerating}
         isLoading={isLoading}
       />
@@ -18,14 +18,14 @@ import {
 import { PaperclipIcon } from "lucide-react";
 import { ProjectBadge } from "../../../features/ProjectBadge";
 import { ModelSelector } from "./ModelSelector";
-import { useGlobalSettings } from "../../../Providers/SettingsProvider";
 
 type ChatInputProps = {
   value: string;
-  onSubmit: (modelId?: string) => void; // Updated to accept modelId
+  onSubmit: (modelId?: string, provider?: string) => void;
   onChange: (event: ChangeEvent<HTMLTextAreaElement>) => void;
   onKeyDown: (event: KeyboardEvent<HTMLTextAreaElement>) => void;
   onActivityHistoryToggle: () => void;
+  onStop: () => void;
   isLoading: boolean;
   isGenerating: boolean;
 };
@@ -36,19 +36,15 @@ export const ChatInput: FC<ChatInputProps> = ({
   onChange,
   onKeyDown,
   onActivityHistoryToggle,
+  onStop,
   isLoading,
   isGenerating,
 }) => {
   const textareaRef = useRef<HTMLTextAreaElement>(null);
-  const { settings } = useGlobalSettings();
-  
-  // Initialize with default model based on provider preference
-  const defaultModel = settings.api_choice === "claude" 
-    ? "claude-3-7-sonnet-20250219" // Default Claude model is 3.7 Sonnet
-    : "gpt-4o";                    // Default OpenAI model is GPT-4o
-  
-  const [currentModel, setCurrentModel] = useState(defaultModel);
-  const [currentProvider, setCurrentProvider] = useState<"claude" | "openai">(settings.api_choice);
+
+  // Filled in by the model selector with the registry default
+  const [currentModel, setCurrentModel] = useState("");
+  const [currentProvider, setCurrentProvider] = useState("");
 
   const handleInput = () => {
     if (textareaRef.current) {
@@ -67,10 +63,10 @@ export const ChatInput: FC<ChatInputProps> = ({
     if (textareaRef.current) {
       textareaRef.current.style.height = "40px"; // Reset the height to the initial value
     }
-    onSubmit(currentModel); // Pass the currently selected model to parent
+    onSubmit(currentModel || undefined, currentProvider || undefined); // Pass the currently selected model to parent
   };
 
-  const handleModelChange = (modelId: string, provider: "claude" | "openai") => {
+  const handleModelChange = (modelId: string, provider: string) => {
     setCurrentModel(modelId);
     setCurrentProvider(provider);
   };
@@ -135,14 +131,20 @@ export const ChatInput: FC<ChatInputProps> = ({
               isRound
             />
           </Tooltip>
-          <Button
-            type="submit"
-            isLoading={isLoading || isGenerating}
-            loadingText="Sending"
-            isDisabled={isGenerating || !value}
-          >
-            Send
-          </Button>
+          {isGenerating ? (
+            <Button onClick={onStop} variant="outline">
+              Stop
+            </Button>
+          ) : (
+            <Button
+              type="submit"
+              isLoading={isLoading}
+              loadingText="Sending"
+              isDisabled={!value}
+            >
+              Send
+            </Button>
+          )}
         </Flex>
       </Flex>
     </Box>
@@ -9,17 +9,18 @@ import {
   Text,
 } from "@chakra-ui/react";
 import { ChevronDownIcon } from "@chakra-ui/icons";
+import { invoke } from "@tauri-apps/api/tauri";
 import { useGlobalSettings } from "../../../Providers/SettingsProvider";
 
 type ModelOption = {
   id: string;
+  provider: string; // Registry provider id, e.g. "anthropic"
   name: string;
-  provider: "claude" | "openai";
   description: string;
 };
 
 type ModelSelectorProps = {
-  onModelChange: (modelId: string, provider: "claude" | "openai") => void;
+  onModelChange: (modelId: string, provider: string) => void;
   currentModel?: string;
 };
 
@@ -30,54 +31,30 @@ export const ModelSelector: FC<ModelSelectorProps> = ({
   const { settings } = useGlobalSettings();
   const [currentModel, setCurrentModel] = useState<string>("");
 
-  const modelOptions: ModelOption[] = [
-    // Claude models
-    {
-      id: "claude-3-7-sonnet-20250219",
-      name: "Claude 3.7 Sonnet",
-      provider: "claude",
-      description: "Main Anthropic model"
-    },
-    {
-      id: "claude-3-5-haiku-20241022",
-      name: "Claude 3.5 Haiku",
-      provider: "claude", 
-      description: "Latest Haiku model"
-    },
-    // OpenAI models
-    {
-      id: "gpt-4o",
-      name: "GPT-4o",
-      provider: "openai",
-      description: "Latest OpenAI model"
-    },
-    {
-      id: "o1",
-      name: "O1",
-      provider: "openai",
-      description: "Advanced reasoning"
-    },
-    {
-      id: "o3-mini",
-      name: "O3-mini",
-      provider: "openai",
-      description: "Efficient reasoning"
-    }
-  ];
+  const [modelOptions, setModelOptions] = useState<ModelOption[]>([]);
+
+  // Load the model catalogue from the backend registry
+  useEffect(() => {
+    invoke<ModelOption[]>("list_models")
+      .then(setModelOptions)
+      .catch((error) => console.error("Error loading models:", error));
+  }, [settings.custom_llm_model]);
 
   // Initialize with external current model, or default if not provided
   useEffect(() => {
     if (externalCurrentModel) {
       setCurrentModel(externalCurrentModel);
-    } else {
-      // Set default model based on provider preference in settings
-      const defaultModel = settings.api_choice === "claude" 
-        ? "claude-3-7-sonnet-20250219" // Default Claude model is 3.7 Sonnet
-        : "gpt-4o";                    // Default OpenAI model is GPT-4o
-        
-      setCurrentModel(defaultModel);
+      return;
+    }
+    // The registry lists the default model of a provider first
+    const preferredProvider = settings.api_choice === "claude" ? "anthropic" : settings.api_choice;
+    const defaultModel =
+      modelOptions.find((model) => model.provider === preferredProvider) ?? modelOptions[0];
+    if (defaultModel) {
+      setCurrentModel(defaultModel.id);
+      onModelChange(defaultModel.id, defaultModel.provider);
     }
-  }, [externalCurrentModel, settings.api_choice]);
+  }, [externalCurrentModel, settings.api_choice, modelOptions]);
 
   const handleModelChange = (modelId: string) => {
     setCurrentModel(modelId);
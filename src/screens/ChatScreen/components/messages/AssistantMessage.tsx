@@ -1,5 +1,5 @@
 import { forwardRef } from "react";
-import { IconButton } from "@chakra-ui/react";
+import { IconButton, Text } from "@chakra-ui/react";
 import { IconCopy, IconCheck } from "@tabler/icons-react";
 import styled from "styled-components";
 import type { StoredMessage } from "../../types";
@@ -24,6 +24,13 @@ const MessageContainer = styled.div`
 
 `;
 
+// Shown under answers that didn't complete
+const STATUS_NOTES: Partial<Record<NonNullable<StoredMessage["status"]>, string>> = {
+  cancelled: "Stopped before the answer was complete",
+  interrupted: "The app closed before the answer was complete",
+  failed: "The answer could not be generated",
+};
+
 type AssistantMessageProps = {
   message: StoredMessage;
   isGenerating: boolean;
@@ -39,10 +46,17 @@ export const AssistantMessage = forwardRef<
     copyToClipboard(value);
   };
 
+  const statusNote = message.status && STATUS_NOTES[message.status];
+
   return (
     <MainContainer ref={ref}>
       <MessageContainer>
         <MessageMarkdown content={message.content} />
+        {statusNote && (
+          <Text fontSize="xs" color={message.status === "failed" ? "red.500" : "gray.500"}>
+            {statusNote}
+          </Text>
+        )}
         {!isGenerating && (
           <IconButton
             aria-label="Copy"
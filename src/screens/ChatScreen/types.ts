@@ -1,11 +1,64 @@
+export type MessageSource = {
+  citation: number;
+  source_type: "activity" | "project_document" | "chat_message";
+  source_id: number;
+  chunk_id: number | null;
+  title: string;
+  snippet: string;
+  score: number;
+};
+
 export type StoredMessage = {
   id: number;
   chat_id: number;
   role: "user" | "assistant";
   content: string;
   created_at: string;
+  sources?: MessageSource[];
+  status?: "streaming" | "complete" | "cancelled" | "failed" | "interrupted";
+  input_tokens?: number | null;
+  output_tokens?: number | null;
+};
+
+export type TokenUsage = {
+  input_tokens: number;
+  output_tokens: number;
 };
 
+// Tokens of the context window, by what they were reserved for
+export type ContextBudget = {
+  context_window: number;
+  output: number;
+  system: number;
+  prompt: number;
+  history: number;
+  documents: number;
+};
+
+// Events of one generation, sent on "chat_stream"
+export type ChatStreamEvent = {
+  chat_id: number | null;
+  generation_id: number;
+  message_id: number | null;
+} & (
+  | {
+      type: "context";
+      budget: ContextBudget;
+      dropped_messages: number;
+      dropped_documents: string[];
+      truncated_document: string | null;
+    }
+  | { type: "delta"; delta: string; full_text: string }
+  | { type: "sources"; sources: MessageSource[]; window_titles: string[] }
+  | {
+      type: "finish";
+      finish_reason: "stop" | "cancelled" | "error";
+      full_text: string;
+      usage: TokenUsage;
+      error: string | null;
+    }
+);
+
 export type Chat = {
   id: number;
   name: string;